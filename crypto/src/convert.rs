@@ -1,20 +1,32 @@
 use crate::model;
 use bytes::Bytes;
+use data_proto::encrypted_descriptor::Kind;
+use prost::Message;
 
 impl TryFrom<data_proto::VerifiedDescriptor> for model::VerifiedDescriptor {
     type Error = anyhow::Error;
 
     fn try_from(value: data_proto::VerifiedDescriptor) -> Result<Self, Self::Error> {
+        let file_id: model::FileId = value.file_id.as_slice().try_into()?;
+        let chunks = value
+            .content
+            .iter()
+            .map(|c| {
+                Ok(model::BlockRef {
+                    file_id: match c.file_id.is_empty() {
+                        true => file_id.clone(),
+                        false => c.file_id.as_slice().try_into()?,
+                    },
+                    block_id: c.block_id.as_slice().try_into()?,
+                })
+            })
+            .collect::<anyhow::Result<Vec<model::BlockRef>>>()?;
         let verified = model::VerifiedDescriptor {
-            file_id: value.file_id.as_slice().try_into()?,
+            file_id,
             version: value.version,
             index: value.index.try_into()?,
             total: value.total.try_into()?,
-            chunks: value
-                .content
-                .iter()
-                .map(|c| model::BlockId::try_from(c.block_id.as_slice()))
-                .collect::<anyhow::Result<Vec<model::BlockId>>>()?,
+            chunks,
         };
         Ok(verified)
     }
@@ -24,9 +36,31 @@ impl TryFrom<data_proto::EncryptedDescriptor> for model::ProtectedDescriptor {
     type Error = anyhow::Error;
 
     fn try_from(value: data_proto::EncryptedDescriptor) -> Result<Self, Self::Error> {
+        let kind = match Kind::try_from(value.kind)? {
+            Kind::Regular => model::FileKind::Regular,
+            Kind::Symlink => model::FileKind::Symlink(value.target),
+            Kind::Hardlink => model::FileKind::Hardlink(value.target),
+            Kind::Fifo => model::FileKind::Fifo,
+            Kind::CharDevice => model::FileKind::CharDevice(value.rdev),
+            Kind::BlockDevice => model::FileKind::BlockDevice(value.rdev),
+            Kind::Directory => model::FileKind::Directory(value.entries),
+        };
         let encrypted = model::ProtectedDescriptor {
             filename: value.filename,
             size: value.size,
+            kind,
+            metadata: value.metadata.map(|metadata| model::Metadata {
+                mode: metadata.mode,
+                uid: metadata.uid,
+                gid: metadata.gid,
+                mtime: metadata.mtime,
+                atime: metadata.atime,
+                xattrs: metadata
+                    .xattrs
+                    .into_iter()
+                    .map(|xattr| (xattr.name, xattr.value))
+                    .collect(),
+            }),
         };
         Ok(encrypted)
     }
@@ -67,27 +101,118 @@ impl From<model::VerifiedDescriptor> for data_proto::VerifiedDescriptor {
                 .chunks
                 .iter()
                 .map(|c| data_proto::BlockRef {
-                    block_id: c.as_bytes().to_vec(),
+                    block_id: c.block_id.as_bytes().to_vec(),
+                    file_id: match c.file_id == value.file_id {
+                        true => vec![],
+                        false => c.file_id.as_bytes().to_vec(),
+                    },
                 })
                 .collect(),
+            // Set when encrypting.
+            key_version: 0,
         }
     }
 }
 
 impl From<model::ProtectedDescriptor> for data_proto::EncryptedDescriptor {
     fn from(value: model::ProtectedDescriptor) -> Self {
+        let mut entries = vec![];
+        let (kind, target, rdev) = match value.kind {
+            model::FileKind::Regular => (Kind::Regular, String::new(), 0),
+            model::FileKind::Symlink(target) => (Kind::Symlink, target, 0),
+            model::FileKind::Hardlink(target) => (Kind::Hardlink, target, 0),
+            model::FileKind::Fifo => (Kind::Fifo, String::new(), 0),
+            model::FileKind::CharDevice(rdev) => (Kind::CharDevice, String::new(), rdev),
+            model::FileKind::BlockDevice(rdev) => (Kind::BlockDevice, String::new(), rdev),
+            model::FileKind::Directory(names) => {
+                entries = names;
+                (Kind::Directory, String::new(), 0)
+            }
+        };
         data_proto::EncryptedDescriptor {
             filename: value.filename,
             size: value.size,
+            kind: kind.into(),
+            target,
+            rdev,
+            metadata: value.metadata.map(|metadata| data_proto::Metadata {
+                mode: metadata.mode,
+                uid: metadata.uid,
+                gid: metadata.gid,
+                mtime: metadata.mtime,
+                atime: metadata.atime,
+                xattrs: metadata
+                    .xattrs
+                    .into_iter()
+                    .map(|(name, value)| data_proto::Xattr { name, value })
+                    .collect(),
+            }),
+            entries,
+        }
+    }
+}
+
+impl model::ProtectedDescriptor {
+    /// Encodes the descriptor, to compare it with the ones of other versions.
+    pub fn encode(&self) -> Vec<u8> {
+        data_proto::EncryptedDescriptor::from(self.clone()).encode_to_vec()
+    }
+}
+
+impl TryFrom<data_proto::Manifest> for model::Manifest {
+    type Error = anyhow::Error;
+
+    fn try_from(value: data_proto::Manifest) -> Result<Self, Self::Error> {
+        Ok(model::Manifest {
+            time: value.time,
+            hostname: value.hostname,
+            roots: value.roots,
+            files: value
+                .files
+                .into_iter()
+                .map(|entry| Ok((entry.file_id.as_slice().try_into()?, entry.version)))
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+}
+
+impl From<model::Manifest> for data_proto::Manifest {
+    fn from(value: model::Manifest) -> Self {
+        data_proto::Manifest {
+            time: value.time,
+            hostname: value.hostname,
+            roots: value.roots,
+            files: value
+                .files
+                .into_iter()
+                .map(|(file_id, version)| data_proto::ManifestEntry {
+                    file_id: file_id.as_bytes().to_vec(),
+                    version,
+                })
+                .collect(),
         }
     }
 }
 
+impl model::Manifest {
+    /// Encodes the manifest, to be stored as the content of a snapshot.
+    pub fn encode(&self) -> Vec<u8> {
+        data_proto::Manifest::from(self.clone()).encode_to_vec()
+    }
+
+    /// Decodes a manifest from the content of a snapshot.
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        data_proto::Manifest::decode(data)?.try_into()
+    }
+}
+
 impl From<model::VerifiedBlock> for data_proto::VerifiedBlockPart {
     fn from(value: model::VerifiedBlock) -> Self {
         data_proto::VerifiedBlockPart {
             file_id: value.file_id.as_bytes().to_vec(),
             block_id: value.block_id.as_bytes().to_vec(),
+            // Set when encrypting.
+            key_version: 0,
         }
     }
 }
@@ -115,17 +240,35 @@ mod test {
             index: 1,
             total: 2,
             chunks: vec![
-                BlockId::try_from([0u8; 12].as_slice()).unwrap(),
-                BlockId::try_from([1u8; 12].as_slice()).unwrap(),
+                model::BlockRef {
+                    file_id: [0u8, 1, 2, 3, 4, 5].as_slice().try_into().unwrap(),
+                    block_id: BlockId::try_from([0u8; 12].as_slice()).unwrap(),
+                },
+                model::BlockRef {
+                    file_id: [6u8, 7, 8, 9, 10, 11].as_slice().try_into().unwrap(),
+                    block_id: BlockId::try_from([1u8; 12].as_slice()).unwrap(),
+                },
             ],
         };
         let protected = model::ProtectedDescriptor {
             filename: "test.txt".to_string(),
             size: 4321,
+            kind: model::FileKind::Regular,
+            metadata: Some(model::Metadata {
+                mode: 0o4755,
+                uid: 1000,
+                gid: 100,
+                mtime: -5,
+                atime: 1_700_000_000_000_000_000,
+                xattrs: vec![(b"user.a".to_vec(), vec![]), (b"user.b".to_vec(), vec![0])],
+            }),
         };
 
         let proto_verified: data_proto::VerifiedDescriptor = verified.clone().into();
         let proto_encrypted: data_proto::EncryptedDescriptor = protected.clone().into();
+        // Only references to other files carry their file_id.
+        assert!(proto_verified.content[0].file_id.is_empty());
+        assert_eq!(proto_verified.content[1].file_id, vec![6, 7, 8, 9, 10, 11]);
 
         let verified2: model::VerifiedDescriptor = proto_verified.try_into().unwrap();
         let encrypted2: model::ProtectedDescriptor = proto_encrypted.try_into().unwrap();
@@ -134,6 +277,63 @@ mod test {
         assert!(protected == encrypted2);
     }
 
+    #[test]
+    fn test_manifest_roundtrip() {
+        let manifest = model::Manifest {
+            time: 1_700_000_000_000_000_000,
+            hostname: "host".to_string(),
+            roots: vec!["/home".to_string(), "/etc".to_string()],
+            files: vec![
+                ([0u8, 1, 2, 3, 4, 5].as_slice().try_into().unwrap(), 3),
+                ([6u8, 7, 8, 9, 10, 11].as_slice().try_into().unwrap(), 0),
+            ],
+        };
+        assert_eq!(
+            model::Manifest::decode(&manifest.encode()).unwrap(),
+            manifest
+        );
+
+        let invalid = data_proto::Manifest {
+            files: vec![data_proto::ManifestEntry {
+                file_id: vec![1, 2],
+                version: 0,
+            }],
+            ..Default::default()
+        };
+        assert!(model::Manifest::decode(&invalid.encode_to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_file_kind_roundtrip() {
+        for kind in [
+            model::FileKind::Symlink("../target".to_string()),
+            model::FileKind::Hardlink("/other".to_string()),
+            model::FileKind::Fifo,
+            model::FileKind::CharDevice(0x0103),
+            model::FileKind::BlockDevice(0x0801),
+            model::FileKind::Directory(vec![]),
+            model::FileKind::Directory(vec!["a".to_string(), "b c".to_string()]),
+        ] {
+            let protected = model::ProtectedDescriptor {
+                filename: "file".to_string(),
+                size: 0,
+                kind,
+                metadata: None,
+            };
+            let proto: data_proto::EncryptedDescriptor = protected.clone().into();
+            assert_eq!(
+                model::ProtectedDescriptor::try_from(proto).unwrap(),
+                protected
+            );
+        }
+
+        let unknown = data_proto::EncryptedDescriptor {
+            kind: 100,
+            ..Default::default()
+        };
+        assert!(model::ProtectedDescriptor::try_from(unknown).is_err());
+    }
+
     #[test]
     fn test_block_roundtrip() {
         let verified = model::VerifiedBlock {
@@ -166,6 +366,7 @@ mod test {
             index: (u16::MAX as u32) + 1,
             total: 1,
             content: vec![],
+            key_version: 0,
         };
         assert!(model::VerifiedDescriptor::try_from(proto).is_err());
 
@@ -175,6 +376,7 @@ mod test {
             index: 1,
             total: (u16::MAX as u32) + 1,
             content: vec![],
+            key_version: 0,
         };
         assert!(model::VerifiedDescriptor::try_from(proto).is_err());
     }
@@ -0,0 +1,226 @@
+//! Export of the root keys for escrow or a paper backup, as codes meant to be written down and
+//! typed back: base32 in groups of four characters, ending with a checksum catching typos. The
+//! keys may also be split into shares with Shamir's secret sharing, so that any `threshold` of
+//! them recover the keys while fewer reveal nothing.
+
+use crate::{
+    key::{Durable, Keyring, KEY_LEN},
+    shamir,
+};
+use anyhow::{anyhow, Context};
+use data_encoding::BASE32_NOPAD;
+use ring::{
+    digest,
+    rand::{SecureRandom, SystemRandom},
+};
+
+const FORMAT: u8 = 1;
+const KIND_KEYS: u8 = 0;
+const KIND_SHARE: u8 = 1;
+const CHECKSUM_LEN: usize = 4;
+const SET_ID_LEN: usize = 4;
+const GROUP_LEN: usize = 4;
+
+/// Encodes all the keys of `keyring` into a single code.
+pub fn export(keyring: &Keyring) -> String {
+    encode(&keys_payload(keyring))
+}
+
+/// Splits the keys of `keyring` into `shares` codes, any `threshold` of which recover them.
+pub fn split(keyring: &Keyring, threshold: u8, shares: u8) -> anyhow::Result<Vec<String>> {
+    // Tells the shares of different splits apart.
+    let mut set_id = [0u8; SET_ID_LEN];
+    SystemRandom::new()
+        .fill(&mut set_id)
+        .map_err(|_| anyhow!("Failed to generate a share set id"))?;
+    // The secret keeps its checksum, which then checks the recovered keys.
+    let secret = with_checksum(keys_payload(keyring));
+    Ok(shamir::split(&secret, threshold, shares)?
+        .into_iter()
+        .map(|share| {
+            let mut payload = vec![FORMAT, KIND_SHARE, threshold];
+            payload.extend_from_slice(&set_id);
+            payload.extend(share);
+            encode(&payload)
+        })
+        .collect())
+}
+
+/// Recovers the keys from either a single code from `export`, or enough shares from `split`.
+pub fn import<S: AsRef<str>>(codes: &[S]) -> anyhow::Result<Keyring> {
+    let payloads = codes
+        .iter()
+        .enumerate()
+        .map(|(n, code)| decode(code.as_ref()).with_context(|| format!("Invalid code #{}", n + 1)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    match payloads.first().map(|payload| payload[1]) {
+        None => Err(anyhow!("No code")),
+        Some(KIND_KEYS) if payloads.len() == 1 => parse_keys(&payloads[0][2..]),
+        Some(KIND_KEYS) => Err(anyhow!("Expected a single code, or shares")),
+        Some(_) => combine(&payloads),
+    }
+}
+
+fn combine(payloads: &[Vec<u8>]) -> anyhow::Result<Keyring> {
+    let header = |payload: &[u8]| -> anyhow::Result<(u8, Vec<u8>)> {
+        if payload[1] != KIND_SHARE || payload.len() < 3 + SET_ID_LEN + 2 {
+            return Err(anyhow!("Expected shares only"));
+        }
+        Ok((payload[2], payload[3..3 + SET_ID_LEN].to_vec()))
+    };
+    let (threshold, set_id) = header(&payloads[0])?;
+    let mut shares = Vec::with_capacity(payloads.len());
+    for payload in payloads {
+        if header(payload)? != (threshold, set_id.clone()) {
+            return Err(anyhow!("The shares come from different splits"));
+        }
+        shares.push(&payload[3 + SET_ID_LEN..]);
+    }
+    let secret = shamir::combine(&shares, threshold)?;
+    let payload = strip_checksum(secret).context("Wrong shares")?;
+    if payload.len() < 2 || payload[..2] != [FORMAT, KIND_KEYS] {
+        return Err(anyhow!("Wrong shares"));
+    }
+    parse_keys(&payload[2..])
+}
+
+fn keys_payload(keyring: &Keyring) -> Vec<u8> {
+    let mut payload = vec![FORMAT, KIND_KEYS];
+    for key in keyring.keys() {
+        payload.extend_from_slice(&key.version().to_le_bytes());
+        payload.extend_from_slice(key.key().as_ref());
+    }
+    payload
+}
+
+fn parse_keys(data: &[u8]) -> anyhow::Result<Keyring> {
+    const ENTRY_LEN: usize = 2 + KEY_LEN;
+    if !data.len().is_multiple_of(ENTRY_LEN) {
+        return Err(anyhow!("Invalid key length"));
+    }
+    let keys = data
+        .chunks(ENTRY_LEN)
+        .map(|entry| {
+            let version = u16::from_le_bytes([entry[0], entry[1]]);
+            let key = <[u8; KEY_LEN]>::try_from(&entry[2..]).unwrap();
+            Durable::new(key.into(), version)
+        })
+        .collect();
+    Keyring::new(keys)
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let digest = digest::digest(&digest::SHA256, data);
+    digest.as_ref()[..CHECKSUM_LEN].try_into().unwrap()
+}
+
+fn with_checksum(mut data: Vec<u8>) -> Vec<u8> {
+    let sum = checksum(&data);
+    data.extend_from_slice(&sum);
+    data
+}
+
+fn strip_checksum(mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    if data.len() < CHECKSUM_LEN {
+        return Err(anyhow!("Too short"));
+    }
+    let sum = data.split_off(data.len() - CHECKSUM_LEN);
+    if sum != checksum(&data) {
+        return Err(anyhow!("Checksum mismatch, check for typos"));
+    }
+    Ok(data)
+}
+
+fn encode(payload: &[u8]) -> String {
+    let encoded = BASE32_NOPAD.encode(&with_checksum(payload.to_vec()));
+    encoded
+        .as_bytes()
+        .chunks(GROUP_LEN)
+        // Base32 is ASCII.
+        .map(|group| std::str::from_utf8(group).unwrap())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Decodes a code, ignoring case, spaces and dashes, and checks its checksum and header.
+fn decode(code: &str) -> anyhow::Result<Vec<u8>> {
+    let normalized: String = code
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    let data = BASE32_NOPAD
+        .decode(normalized.as_bytes())
+        .map_err(|err| anyhow!("Invalid character or length: {}", err))?;
+    let payload = strip_checksum(data)?;
+    if payload.len() < 2 {
+        return Err(anyhow!("Too short"));
+    }
+    if payload[0] != FORMAT {
+        return Err(anyhow!("Unsupported format {}", payload[0]));
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn keyring() -> Keyring {
+        Keyring::new(vec![
+            Durable::new([1; KEY_LEN].into(), 0),
+            Durable::new([2; KEY_LEN].into(), 3),
+        ])
+        .unwrap()
+    }
+
+    fn assert_same(keyring: &Keyring, other: &Keyring) {
+        let entries = |keyring: &Keyring| {
+            keyring
+                .keys()
+                .iter()
+                .map(|key| (key.version(), key.key().as_ref().to_vec()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(entries(keyring), entries(other));
+    }
+
+    #[test]
+    fn test_export() -> anyhow::Result<()> {
+        let keyring = keyring();
+        let code = export(&keyring);
+        assert!(code.split('-').all(|group| group.len() <= GROUP_LEN));
+        assert_same(&import(&[&code])?, &keyring);
+        // Typed back loosely.
+        let typed = code.to_lowercase().replace('-', " ");
+        assert_same(&import(&[typed])?, &keyring);
+
+        // A typo.
+        let mut typo = code.into_bytes();
+        typo[5] = if typo[5] == b'A' { b'B' } else { b'A' };
+        let err = import(&[String::from_utf8(typo)?]).map(|_| ()).unwrap_err();
+        assert!(format!("{:#}", err).contains("Checksum"), "{:#}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shares() -> anyhow::Result<()> {
+        let keyring = keyring();
+        let shares = split(&keyring, 2, 3)?;
+        assert_eq!(shares.len(), 3);
+        for pair in [[0, 1], [1, 2], [2, 0]] {
+            assert_same(&import(&pair.map(|n| &shares[n]))?, &keyring);
+        }
+        assert_same(&import(&shares)?, &keyring);
+        assert!(import(&shares[..1]).is_err());
+        assert!(import(&[&shares[0], &shares[0]]).is_err());
+
+        // Shares of another split.
+        let other = split(&keyring, 2, 3)?;
+        assert!(import(&[&shares[0], &other[1]]).is_err());
+        assert!(import(&[&shares[0], &export(&keyring)]).is_err());
+        assert!(split(&keyring, 3, 2).is_err());
+        assert!(split(&keyring, 0, 2).is_err());
+        Ok(())
+    }
+}
@@ -1,10 +1,21 @@
 use anyhow::{anyhow, Context};
 use hex::FromHex;
+use ring::{
+    aead,
+    rand::{SecureRandom, SystemRandom},
+};
 use serde::{Deserialize, Serialize};
 use std::{io::Write, path::Path};
 
 pub const KEY_LEN: usize = 128 / 8;
 
+const SALT_LEN: usize = 16;
+/// Most memory a KDF read from a keyfile may use, so that a tampered file can't exhaust it.
+const MAX_KDF_MEMORY: u64 = 4 << 30;
+/// Most passes or lanes of a KDF read from a keyfile.
+const MAX_KDF_COST: u32 = 64;
+const SEALED_AAD: &[u8] = b"piston keyfile";
+
 /// A key used for encryption and decryption. This is generally
 /// a derived key from a root key.
 #[derive(Debug, PartialEq)]
@@ -33,42 +44,328 @@ impl Durable {
         Durable { key, version }
     }
 
-    /// Load keys from a file.
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+}
+
+/// All the versions of the root key, in increasing order. New data is encrypted under the latest
+/// one, while older ones are kept to decrypt the data encrypted under them.
+pub struct Keyring {
+    keys: Vec<Durable>,
+}
+
+impl Keyring {
+    /// Creates a keyring from at least one key, with distinct versions.
+    pub fn new(mut keys: Vec<Durable>) -> anyhow::Result<Self> {
+        keys.sort_by_key(|key| key.version);
+        if keys.is_empty() {
+            return Err(anyhow!("No key"));
+        }
+        if keys
+            .windows(2)
+            .any(|pair| pair[0].version == pair[1].version)
+        {
+            return Err(anyhow!("Duplicate key version"));
+        }
+        Ok(Keyring { keys })
+    }
+
+    /// Load keys from a file, which must not be sealed.
     pub fn from_file(file_path: &Path) -> anyhow::Result<Self> {
-        let toml = std::fs::read_to_string(file_path).context("reading SourceKey")?;
-        let ondisk: OnDisk = toml::from_str(&toml).context("deserializing SourceKey")?;
-        if ondisk.key.len() != 1 {
-            return Err(anyhow!("Invalid SourceKey file"));
+        let ondisk = OnDisk::read(file_path)?;
+        if ondisk.sealed.is_some() {
+            return Err(anyhow!("SourceKey file is sealed with a passphrase"));
         }
-        Durable::try_from(ondisk.key[0].clone())
+        ondisk.keyring()
+    }
+
+    /// Load keys from a file sealed with `passphrase`.
+    pub fn from_sealed_file(file_path: &Path, passphrase: &str) -> anyhow::Result<Self> {
+        let sealed = OnDisk::read(file_path)?
+            .sealed
+            .context("SourceKey file is not sealed")?;
+        let key = sealed.kdf.derive(
+            passphrase.as_bytes(),
+            &Vec::from_hex(&sealed.salt).context("Invalid salt")?,
+        )?;
+        let nonce = <[u8; aead::NONCE_LEN]>::from_hex(&sealed.nonce).context("Invalid nonce")?;
+        let mut data = Vec::from_hex(&sealed.ciphertext).context("Invalid ciphertext")?;
+        let toml = keyfile_key(&key)?
+            .open_in_place(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(SEALED_AAD),
+                &mut data,
+            )
+            .map_err(|_| anyhow!("Wrong passphrase, or corrupt SourceKey file"))?;
+        let ondisk: OnDisk =
+            toml::from_str(std::str::from_utf8(toml)?).context("deserializing sealed SourceKey")?;
+        ondisk.keyring()
     }
 
     /// Save keys to a file.
     pub fn to_file(&self, file_path: &Path) -> anyhow::Result<()> {
+        self.to_ondisk().write(file_path)
+    }
+
+    /// Save keys to a file, encrypted under a key derived from `passphrase` by `kdf`.
+    pub fn to_sealed_file(
+        &self,
+        file_path: &Path,
+        passphrase: &str,
+        kdf: Kdf,
+    ) -> anyhow::Result<()> {
+        let rnd = SystemRandom::new();
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        rnd.fill(&mut salt)
+            .and_then(|()| rnd.fill(&mut nonce))
+            .map_err(|_| anyhow!("Failed to generate a salt"))?;
+        let key = kdf.derive(passphrase.as_bytes(), &salt)?;
+        let mut data = toml::to_string(&self.to_ondisk())
+            .context("serializing SourceKey")?
+            .into_bytes();
+        keyfile_key(&key)?
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(SEALED_AAD),
+                &mut data,
+            )
+            .map_err(|_| anyhow!("Failed to seal SourceKey"))?;
         let ondisk = OnDisk {
-            key: vec![self.into()],
+            key: vec![],
+            sealed: Some(Sealed {
+                kdf,
+                salt: hex::encode(salt),
+                nonce: hex::encode(nonce),
+                ciphertext: hex::encode(data),
+            }),
         };
+        ondisk.write(file_path)
+    }
 
-        let toml = toml::to_string(&ondisk).context("serializing SourceKey")?;
-        let mut file = std::fs::File::create(file_path).context("opening SourceKey file")?;
-        file.write_all(toml.as_bytes())
-            .context("writing SourceKey file")?;
+    fn to_ondisk(&self) -> OnDisk {
+        OnDisk {
+            key: self.keys.iter().map(String::from).collect(),
+            sealed: None,
+        }
+    }
+
+    /// Returns all the keys, oldest first.
+    pub fn keys(&self) -> &[Durable] {
+        &self.keys
+    }
+
+    /// Returns the key new data is encrypted under.
+    pub fn latest(&self) -> &Durable {
+        // Never empty, see new.
+        self.keys.last().unwrap()
+    }
+
+    /// Returns the key with this version, if known.
+    pub fn get(&self, version: u16) -> Option<&Durable> {
+        self.keys.iter().find(|key| key.version == version)
+    }
+
+    /// Returns the version the next key must have.
+    pub fn next_version(&self) -> anyhow::Result<u16> {
+        self.latest()
+            .version
+            .checked_add(1)
+            .context("No key version left")
+    }
+
+    /// Adds a key newer than all the others, which new data is encrypted under from then on.
+    pub fn rotate(&mut self, key: Durable) -> anyhow::Result<()> {
+        if key.version <= self.latest().version {
+            return Err(anyhow!(
+                "Key version {} is not newer than {}",
+                key.version,
+                self.latest().version
+            ));
+        }
+        self.keys.push(key);
         Ok(())
     }
+}
 
-    pub fn key(&self) -> &Key {
-        &self.key
+impl From<Durable> for Keyring {
+    fn from(key: Durable) -> Keyring {
+        Keyring { keys: vec![key] }
     }
+}
 
-    pub fn version(&self) -> u16 {
-        self.version
+/// Returns whether a keyfile is sealed with a passphrase.
+pub fn is_sealed(file_path: &Path) -> anyhow::Result<bool> {
+    Ok(sealed_kdf(file_path)?.is_some())
+}
+
+/// Returns the KDF a keyfile is sealed with, if it is sealed.
+pub fn sealed_kdf(file_path: &Path) -> anyhow::Result<Option<Kdf>> {
+    Ok(OnDisk::read(file_path)?.sealed.map(|sealed| sealed.kdf))
+}
+
+/// Memory-hard function deriving the key sealing a keyfile from a passphrase, with its cost.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "lowercase")]
+pub enum Kdf {
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+    Scrypt {
+        log_n: u8,
+        r: u32,
+        p: u32,
+    },
+}
+
+impl Kdf {
+    /// Argon2id with the second recommended option of RFC 9106: 64 MiB, 3 passes and 4 lanes.
+    pub fn argon2id() -> Kdf {
+        Kdf::Argon2id {
+            memory_kib: 1 << 16,
+            iterations: 3,
+            parallelism: 4,
+        }
+    }
+
+    /// scrypt with N = 2^17, r = 8 and p = 1, using 128 MiB.
+    pub fn scrypt() -> Kdf {
+        Kdf::Scrypt {
+            log_n: 17,
+            r: 8,
+            p: 1,
+        }
+    }
+
+    /// Checks that the cost of the KDF is bounded, as its parameters may come from a keyfile.
+    fn check(&self) -> anyhow::Result<()> {
+        let (memory, cost) = match *self {
+            Kdf::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => (u64::from(memory_kib) * 1024, iterations.max(parallelism)),
+            Kdf::Scrypt { log_n, r, p } => (
+                1u64.checked_shl(log_n.into())
+                    .and_then(|n| n.checked_mul(128 * u64::from(r)))
+                    .unwrap_or(u64::MAX),
+                p,
+            ),
+        };
+        if memory > MAX_KDF_MEMORY || cost > MAX_KDF_COST {
+            return Err(anyhow!("The KDF parameters exceed the maximum cost"));
+        }
+        Ok(())
+    }
+
+    fn derive(&self, passphrase: &[u8], salt: &[u8]) -> anyhow::Result<[u8; 32]> {
+        self.check()?;
+        let mut key = [0u8; 32];
+        match self {
+            Kdf::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => {
+                let params =
+                    argon2::Params::new(*memory_kib, *iterations, *parallelism, Some(key.len()))
+                        .map_err(|err| anyhow!("Invalid Argon2id parameters: {}", err))?;
+                argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                    .hash_password_into(passphrase, salt, &mut key)
+                    .map_err(|err| anyhow!("Failed to derive the key: {}", err))?;
+            }
+            Kdf::Scrypt { log_n, r, p } => {
+                let params = scrypt::Params::new(*log_n, *r, *p, key.len())
+                    .map_err(|err| anyhow!("Invalid scrypt parameters: {}", err))?;
+                scrypt::scrypt(passphrase, salt, &params, &mut key)
+                    .map_err(|err| anyhow!("Failed to derive the key: {}", err))?;
+            }
+        }
+        Ok(key)
     }
 }
 
-// Helper struct to serialize and deserialize the key to disk.
+fn keyfile_key(key: &[u8; 32]) -> anyhow::Result<aead::LessSafeKey> {
+    let unbound = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, key)
+        .map_err(|_| anyhow!("Invalid sealing key"))?;
+    Ok(aead::LessSafeKey::new(unbound))
+}
+
+// Helper struct to serialize and deserialize the key to disk. Sealed files only hold the plain
+// form, encrypted.
 #[derive(Serialize, Deserialize, Debug)]
 struct OnDisk {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     key: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sealed: Option<Sealed>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Sealed {
+    kdf: Kdf,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+impl OnDisk {
+    fn read(file_path: &Path) -> anyhow::Result<Self> {
+        let toml = std::fs::read_to_string(file_path).context("reading SourceKey")?;
+        toml::from_str(&toml).context("deserializing SourceKey")
+    }
+
+    /// Replaces the file atomically, so that a failure never loses the keys, and only lets its
+    /// owner read it.
+    fn write(&self, file_path: &Path) -> anyhow::Result<()> {
+        let toml = toml::to_string(self).context("serializing SourceKey")?;
+        let dir = match file_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        // Temporary files are unique, and only readable by their owner from the start.
+        let mut tmp = tempfile::Builder::new()
+            .prefix(".")
+            .suffix(".new")
+            .tempfile_in(dir)
+            .context("creating SourceKey file")?;
+        tmp.write_all(toml.as_bytes())
+            .and_then(|()| tmp.as_file().sync_all())
+            .context("writing SourceKey file")?;
+        tmp.persist(file_path).context("replacing SourceKey file")?;
+        sync_dir(dir).context("syncing the SourceKey directory")
+    }
+
+    fn keyring(self) -> anyhow::Result<Keyring> {
+        let keys = self
+            .key
+            .into_iter()
+            .map(Durable::try_from)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Keyring::new(keys).context("Invalid SourceKey file")
+    }
+}
+
+/// Makes the rename of the keyfile durable, so that a newer key is never lost once data was
+/// encrypted under it. The storage layout can't be used here, as it depends on this crate.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> anyhow::Result<()> {
+    std::fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn sync_dir(_dir: &Path) -> anyhow::Result<()> {
+    // Directories can't be opened as files on Windows, and NTFS journals
+    // the metadata updates anyway.
+    Ok(())
 }
 
 impl From<&Durable> for String {
@@ -107,4 +404,107 @@ mod test {
         assert_eq!(durable.key(), durable2.key());
         assert_eq!(durable.version(), durable2.version());
     }
+
+    #[test]
+    fn test_keyring() -> anyhow::Result<()> {
+        let key = |n: u8, version| super::Durable::new([n; super::KEY_LEN].into(), version);
+        assert!(super::Keyring::new(vec![]).is_err());
+        assert!(super::Keyring::new(vec![key(1, 0), key(2, 0)]).is_err());
+
+        let mut keyring = super::Keyring::new(vec![key(2, 3), key(1, 0)])?;
+        assert_eq!(keyring.latest().version(), 3);
+        assert_eq!(keyring.get(0).map(|key| key.key()), Some(&[1; 16].into()));
+        assert!(keyring.get(1).is_none());
+        assert!(keyring.rotate(key(3, 3)).is_err());
+        keyring.rotate(key(3, keyring.next_version()?))?;
+        assert_eq!(keyring.latest().version(), 4);
+
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("keyfile");
+        keyring.to_file(&path)?;
+        let loaded = super::Keyring::from_file(&path)?;
+        let versions = |keyring: &super::Keyring| -> Vec<u16> {
+            keyring.keys.iter().map(|key| key.version()).collect()
+        };
+        assert_eq!(versions(&loaded), vec![0, 3, 4]);
+        assert_eq!(loaded.latest().key(), keyring.latest().key());
+        assert!(!super::is_sealed(&path)?);
+        assert!(super::Keyring::from_sealed_file(&path, "").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sealed_keyfile() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("keyfile");
+        // Cheap parameters, the defaults take a while.
+        let kdfs = [
+            super::Kdf::Argon2id {
+                memory_kib: 64,
+                iterations: 1,
+                parallelism: 1,
+            },
+            super::Kdf::Scrypt {
+                log_n: 4,
+                r: 8,
+                p: 1,
+            },
+        ];
+        for kdf in kdfs {
+            let keyring = super::Keyring::new(vec![
+                super::Durable::new([1; super::KEY_LEN].into(), 0),
+                super::Durable::new([2; super::KEY_LEN].into(), 1),
+            ])?;
+            keyring.to_sealed_file(&path, "correct horse", kdf)?;
+            assert!(super::is_sealed(&path)?);
+            let content = std::fs::read_to_string(&path)?;
+            assert!(!content.contains(&hex::encode([2; super::KEY_LEN])));
+
+            assert!(super::Keyring::from_file(&path).is_err());
+            assert!(super::Keyring::from_sealed_file(&path, "wrong horse").is_err());
+            let loaded = super::Keyring::from_sealed_file(&path, "correct horse")?;
+            assert_eq!(loaded.latest().version(), 1);
+            assert_eq!(loaded.get(0).map(|key| key.key()), Some(&[1; 16].into()));
+        }
+
+        // Costly parameters are refused rather than run.
+        let content = std::fs::read_to_string(&path)?.replace("log_n = 4", "log_n = 40");
+        std::fs::write(&path, content)?;
+        let err = super::Keyring::from_sealed_file(&path, "correct horse")
+            .map(|_| ())
+            .unwrap_err();
+        assert!(err.to_string().contains("maximum cost"), "{:#}", err);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                std::fs::metadata(&path)?.permissions().mode() & 0o777,
+                0o600
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replace_keyfile() -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("keyfile");
+        // Left world-readable by an older version.
+        let stale = tmpdir.path().join("keyfile.new");
+        std::fs::write(&stale, "")?;
+        std::fs::set_permissions(&stale, std::fs::Permissions::from_mode(0o644))?;
+
+        let keyring = super::Keyring::from(super::Durable::new([1; super::KEY_LEN].into(), 0));
+        keyring.to_file(&path)?;
+        keyring.to_file(&path)?;
+        assert_eq!(
+            std::fs::metadata(&path)?.permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(std::fs::read_to_string(&stale)?, "");
+        assert_eq!(std::fs::read_dir(tmpdir.path())?.count(), 2);
+        Ok(())
+    }
 }
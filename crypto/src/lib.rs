@@ -9,17 +9,23 @@ use std::sync::Arc;
 use thiserror::Error;
 
 mod convert;
+pub mod escrow;
 pub mod key;
 pub mod model;
 mod nonce;
+mod shamir;
 
 const DESCRIPTOR_KEY_INFO: [&[u8]; 1] = [b"descriptor key"];
 const BLOCK_KEY_INFO: [&[u8]; 1] = [b"block key"];
+/// Size of the blocks holding a snapshot manifest.
+const MANIFEST_CHUNK: usize = 1 << 20;
 
 #[derive(Error, Debug)]
 pub enum CryptoError {
     #[error("internal crypto error {0}")]
     Internal(String),
+    #[error("unknown key version {0}")]
+    UnknownKey(u32),
 }
 
 pub trait RandomApi {
@@ -50,11 +56,16 @@ impl Random {
     // Generates a new random Key. This is only expected to happen once
     // when the Source is created.
     pub fn generate_root_key(&self) -> anyhow::Result<key::Durable> {
+        self.generate_key(0)
+    }
+
+    // Generates a new random Key with a given version, when rotating keys.
+    pub fn generate_key(&self, version: u16) -> anyhow::Result<key::Durable> {
         let mut id = [0u8; key::KEY_LEN];
         self.rnd
             .fill(&mut id)
             .map_err(|_| CryptoError::Internal("while generating root Key".to_string()))?;
-        Ok(key::Durable::new(id.into(), 0))
+        Ok(key::Durable::new(id.into(), version))
     }
 }
 
@@ -82,12 +93,19 @@ impl RandomApi for Random {
 
 /// The cryptographic keys used to encrypt and decrypt the data.
 pub struct Keys {
-    durable: key::Durable,
+    keyring: key::Keyring,
 }
 
 impl Keys {
-    pub fn new(durable: key::Durable) -> Self {
-        Self { durable }
+    pub fn new(keyring: impl Into<key::Keyring>) -> Self {
+        Self {
+            keyring: keyring.into(),
+        }
+    }
+
+    /// Returns the version of the root key new data is encrypted under.
+    pub fn key_version(&self) -> u16 {
+        self.keyring.latest().version()
     }
 
     /// Encrypts the protected part of a descriptor and sign the verified part.
@@ -96,10 +114,14 @@ impl Keys {
         verified: model::VerifiedDescriptor,
         encrypted: model::ProtectedDescriptor,
     ) -> anyhow::Result<model::Descriptor> {
-        let key = self.derive_descriptor_key(&verified.file_id);
+        let durable = self.keyring.latest();
+        let key = derive_descriptor_key(durable, &verified.file_id);
         let nonce = get_descriptor_nonce(&verified.file_id, verified.version, verified.index);
 
-        let vp: data_proto::VerifiedDescriptor = verified.into();
+        let vp = data_proto::VerifiedDescriptor {
+            key_version: durable.version().into(),
+            ..verified.into()
+        };
         let ep: data_proto::EncryptedDescriptor = encrypted.into();
 
         let aad = vp.encode_to_vec();
@@ -117,16 +139,92 @@ impl Keys {
         })
     }
 
+    /// Encrypts the descriptors of a file version. The blocks are split so that each descriptor
+    /// references at most `max_refs` of them, and only the first descriptor carries the metadata.
+    pub fn encrypt_descriptors(
+        &self,
+        file_id: &model::FileId,
+        version: model::Version,
+        chunks: &[model::BlockRef],
+        protected: model::ProtectedDescriptor,
+        max_refs: usize,
+    ) -> anyhow::Result<Vec<model::Descriptor>> {
+        // Even an empty file needs a descriptor.
+        let parts: Vec<&[model::BlockRef]> = match chunks.is_empty() {
+            true => vec![&[]],
+            false => chunks.chunks(max_refs).collect(),
+        };
+        let total = u16::try_from(parts.len())
+            .map_err(|_| CryptoError::Internal("too many descriptors".to_string()))?;
+        let mut protected = Some(protected);
+        let mut descriptors = vec![];
+        for (index, part) in (0..total).zip(parts) {
+            descriptors.push(self.encrypt_descriptor(
+                model::VerifiedDescriptor {
+                    file_id: file_id.clone(),
+                    version,
+                    index,
+                    total,
+                    chunks: part.to_vec(),
+                },
+                protected.take().unwrap_or_default(),
+            )?);
+        }
+        Ok(descriptors)
+    }
+
+    /// Encrypts a manifest as the blocks and descriptors of snapshot `version`, the content of
+    /// the reserved file `model::SNAPSHOTS`.
+    pub fn encrypt_manifest(
+        &self,
+        rnd: &dyn RandomApi,
+        version: model::Version,
+        manifest: &model::Manifest,
+        max_refs: usize,
+    ) -> anyhow::Result<(Vec<model::Block>, Vec<model::Descriptor>)> {
+        let data = bytes::Bytes::from(manifest.encode());
+        let mut blocks = vec![];
+        let mut chunks = vec![];
+        for start in (0..data.len()).step_by(MANIFEST_CHUNK) {
+            let block_id = rnd.generate_block_id()?;
+            blocks.push(self.encrypt_block(
+                model::VerifiedBlock {
+                    file_id: model::SNAPSHOTS,
+                    block_id: block_id.clone(),
+                },
+                model::ProtectedBlock {
+                    chunk: data.slice(start..data.len().min(start + MANIFEST_CHUNK)),
+                    padding: vec![],
+                },
+            )?);
+            chunks.push(model::BlockRef {
+                file_id: model::SNAPSHOTS,
+                block_id,
+            });
+        }
+        let protected = model::ProtectedDescriptor {
+            size: data.len() as u64,
+            ..Default::default()
+        };
+        let descriptors =
+            self.encrypt_descriptors(&model::SNAPSHOTS, version, &chunks, protected, max_refs)?;
+        Ok((blocks, descriptors))
+    }
+
     /// Encrypts the protected part of a block and sign the verified part.
     pub fn encrypt_block(
         &self,
         verified: model::VerifiedBlock,
         protected: model::ProtectedBlock,
     ) -> anyhow::Result<model::Block> {
-        let key = self.derive_block_key(&verified.file_id);
+        let durable = self.keyring.latest();
+        let key = derive_block_key(durable, &verified.file_id);
         let nonce = *verified.block_id.as_bytes();
 
-        let vp: data_proto::VerifiedBlockPart = verified.into();
+        let vp = data_proto::VerifiedBlockPart {
+            key_version: durable.version().into(),
+            ..verified.into()
+        };
         let ep: data_proto::EncryptedChunk = protected.into();
 
         let aad = vp.encode_to_vec();
@@ -153,9 +251,10 @@ impl Keys {
         // various arguments, but we won't return anything before validating
         // the tag and decrypting.
         let vp = data_proto::VerifiedDescriptor::decode(descriptor.verified.as_slice())?;
+        let durable = self.durable(vp.key_version)?;
         let verified: model::VerifiedDescriptor = vp.try_into()?;
 
-        let key = self.derive_descriptor_key(&verified.file_id);
+        let key = derive_descriptor_key(durable, &verified.file_id);
         let nonce = get_descriptor_nonce(&verified.file_id, verified.version, verified.index);
         let aad = aead::Aad::from(descriptor.verified.as_slice());
 
@@ -172,9 +271,10 @@ impl Keys {
         block: &model::Block,
     ) -> anyhow::Result<(model::VerifiedBlock, model::ProtectedBlock)> {
         let vp = data_proto::VerifiedBlockPart::decode(block.verified.as_slice())?;
+        let durable = self.durable(vp.key_version)?;
         let verified: model::VerifiedBlock = vp.try_into()?;
 
-        let key = self.derive_block_key(&verified.file_id);
+        let key = derive_block_key(durable, &verified.file_id);
         let nonce = verified.block_id.as_bytes();
         let aad = aead::Aad::from(block.verified.as_slice());
 
@@ -185,17 +285,56 @@ impl Keys {
         Ok((verified, ep.try_into()?))
     }
 
-    // Descriptor key = HKDF(key, salt = file_id, info = "descriptor key")
-    fn derive_descriptor_key(&self, file_id: &model::FileId) -> key::Key {
-        derive_key(&self.durable, file_id, &DESCRIPTOR_KEY_INFO)
+    /// Encrypts a descriptor again under the latest root key, unless it already is. Its nonce
+    /// stays the same, which is safe as the key differs.
+    pub fn reencrypt_descriptor(
+        &self,
+        descriptor: &model::Descriptor,
+    ) -> anyhow::Result<Option<model::Descriptor>> {
+        if descriptor_key_version(descriptor)? == self.key_version() {
+            return Ok(None);
+        }
+        let (verified, protected) = self.decrypt_descriptor(descriptor)?;
+        Ok(Some(self.encrypt_descriptor(verified, protected)?))
     }
 
-    // Block key = HKDF(key, salt = file_id, info = "block key")
-    fn derive_block_key(&self, file_id: &model::FileId) -> key::Key {
-        derive_key(&self.durable, file_id, &BLOCK_KEY_INFO)
+    fn durable(&self, version: u32) -> Result<&key::Durable, CryptoError> {
+        u16::try_from(version)
+            .ok()
+            .and_then(|version| self.keyring.get(version))
+            .ok_or(CryptoError::UnknownKey(version))
     }
 }
 
+// Descriptor key = HKDF(key, salt = file_id, info = "descriptor key")
+fn derive_descriptor_key(durable: &key::Durable, file_id: &model::FileId) -> key::Key {
+    derive_key(durable, file_id, &DESCRIPTOR_KEY_INFO)
+}
+
+// Block key = HKDF(key, salt = file_id, info = "block key")
+fn derive_block_key(durable: &key::Durable, file_id: &model::FileId) -> key::Key {
+    derive_key(durable, file_id, &BLOCK_KEY_INFO)
+}
+
+/// Reads the verified part of a descriptor without checking it. This is what the sink sees, as it
+/// can't check anything without the key.
+pub fn peek_descriptor(
+    descriptor: &model::Descriptor,
+) -> anyhow::Result<model::VerifiedDescriptor> {
+    data_proto::VerifiedDescriptor::decode(descriptor.verified.as_slice())?.try_into()
+}
+
+/// Returns the version of the root key a descriptor is encrypted under, without checking it.
+pub fn descriptor_key_version(descriptor: &model::Descriptor) -> anyhow::Result<u16> {
+    let vp = data_proto::VerifiedDescriptor::decode(descriptor.verified.as_slice())?;
+    Ok(vp.key_version.try_into()?)
+}
+
+/// Reads the verified part of a block without checking it.
+pub fn peek_block(block: &model::Block) -> anyhow::Result<model::VerifiedBlock> {
+    data_proto::VerifiedBlockPart::decode(block.verified.as_slice())?.try_into()
+}
+
 // HKDF key derivation helper. All keys derive from the FileId, with different
 // info strings and input key material.
 fn derive_key(key: &key::Durable, file_id: &model::FileId, info: &[&[u8]]) -> key::Key {
@@ -265,19 +404,27 @@ mod test {
         let rnd = Random::new();
         let source = Keys::new(rnd.generate_root_key()?);
 
+        let file_id = rnd.generate_file_id().unwrap();
         let verified = model::VerifiedDescriptor {
-            file_id: rnd.generate_file_id().unwrap(),
+            file_id: file_id.clone(),
             version: 12,
             index: 32,
             total: 43,
             chunks: vec![
-                model::BlockId::try_from([0u8; 12].as_slice()).unwrap(),
-                model::BlockId::try_from([1u8; 12].as_slice()).unwrap(),
+                model::BlockRef {
+                    file_id: file_id.clone(),
+                    block_id: model::BlockId::try_from([0u8; 12].as_slice()).unwrap(),
+                },
+                model::BlockRef {
+                    file_id: rnd.generate_file_id().unwrap(),
+                    block_id: model::BlockId::try_from([1u8; 12].as_slice()).unwrap(),
+                },
             ],
         };
         let encrypted = model::ProtectedDescriptor {
             filename: "test.txt".to_string(),
             size: 123,
+            ..Default::default()
         };
 
         let descriptor = source
@@ -287,6 +434,7 @@ mod test {
 
         assert!(verified == verified2);
         assert!(encrypted == encrypted2);
+        assert_eq!(peek_descriptor(&descriptor)?, verified);
         Ok(())
     }
 
@@ -311,7 +459,57 @@ mod test {
 
         assert!(verified == verified2);
         assert!(protected == protected2);
+        assert_eq!(peek_block(&block)?, verified);
+
+        Ok(())
+    }
 
+    #[test]
+    fn test_rotation() -> Result<()> {
+        let rnd = Random::new();
+        let file_id = rnd.generate_file_id()?;
+        let verified = model::VerifiedDescriptor {
+            file_id: file_id.clone(),
+            version: 1,
+            index: 0,
+            total: 1,
+            chunks: vec![],
+        };
+        let old_key = [7u8; key::KEY_LEN];
+        let old = Keys::new(key::Durable::new(old_key.into(), 4));
+        let descriptor = old.encrypt_descriptor(verified.clone(), Default::default())?;
+        let block = old.encrypt_block(
+            model::VerifiedBlock {
+                file_id: file_id.clone(),
+                block_id: rnd.generate_block_id()?,
+            },
+            model::ProtectedBlock {
+                chunk: Bytes::from_static(b"abc"),
+                padding: vec![],
+            },
+        )?;
+        assert_eq!(descriptor_key_version(&descriptor)?, 4);
+        // Not the key the data was encrypted under.
+        let other = Keys::new(rnd.generate_key(4)?);
+        assert!(other.decrypt_descriptor(&descriptor).is_err());
+        let missing = Keys::new(rnd.generate_key(5)?);
+        assert!(missing.decrypt_block(&block).is_err());
+
+        let new = Keys::new(key::Keyring::new(vec![
+            key::Durable::new(old_key.into(), 4),
+            rnd.generate_key(7)?,
+        ])?);
+        assert_eq!(new.key_version(), 7);
+        // Older data is still readable.
+        assert_eq!(new.decrypt_descriptor(&descriptor)?.0, verified);
+        assert!(new.decrypt_block(&block).is_ok());
+
+        let rotated = new.reencrypt_descriptor(&descriptor)?.unwrap();
+        assert_eq!(descriptor_key_version(&rotated)?, 7);
+        assert_eq!(new.decrypt_descriptor(&rotated)?.0, verified);
+        assert!(new.reencrypt_descriptor(&rotated)?.is_none());
+        // The old key alone can't read it anymore.
+        assert!(old.decrypt_descriptor(&rotated).is_err());
         Ok(())
     }
 }
@@ -8,25 +8,38 @@ use std::sync::Arc;
 /// FileId is a unique and random identifier for a file in this Source.
 /// Both properties must be strongly enforced for the encryption to be
 /// secure.
-#[derive(Clone, Debug, PartialEq, Default)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Default)]
 pub struct FileId([u8; FILE_ID_LEN]);
 pub const FILE_ID_LEN: usize = 48 / 8;
 
+/// Snapshots are stored as the versions of a file with this reserved id,
+/// their manifest being its content. It is never given to another file.
+pub const SNAPSHOTS: FileId = FileId([0xff; FILE_ID_LEN]);
+
 /// BlockId is a unique and random identifier for a data block.
 /// Both properties must be strongly enforced for the encryption to be
 /// secure. Uniquess is in the context of a given FileId.
-#[derive(Clone, Debug, PartialEq, Default)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Default)]
 pub struct BlockId([u8; BLOCK_ID_LEN]);
 pub const BLOCK_ID_LEN: usize = 96 / 8;
 
 pub type Version = u32;
 
+/// BlockRef locates a block on the sink. Blocks are stored under the
+/// file they were uploaded for, which may differ from the file that
+/// references them when identical content is shared.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockRef {
+    pub file_id: FileId,
+    pub block_id: BlockId,
+}
+
 /// Descriptor is all the information about a file, split between a
 /// verified part and a protected part. The verified part is signed
 /// and the protected part is encrypted. It is essential that the sink
 /// stores the verified part as bytes (even if it can deserialize them)
 /// as serialization is _not_ guaranteed to be deterministic.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Descriptor {
     pub verified: Vec<u8>,
     pub protected: Vec<u8>,
@@ -77,13 +90,60 @@ pub struct VerifiedDescriptor {
     pub index: u16,
     pub total: u16,
 
-    pub chunks: Vec<BlockId>,
+    pub chunks: Vec<BlockRef>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct ProtectedDescriptor {
     pub filename: String,
     pub size: u64,
+    pub kind: FileKind,
+    pub metadata: Option<Metadata>,
+}
+
+/// Metadata is the POSIX metadata of a file. Times are in nanoseconds
+/// since the epoch.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// Permission bits, including setuid, setgid and sticky.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub atime: i64,
+    /// Extended attributes as (name, value), sorted by name. ACLs are
+    /// kept as the system.posix_acl_* attributes.
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// FileKind is what is needed to recreate a file, besides its content.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum FileKind {
+    #[default]
+    Regular,
+    /// Symbolic link to a target, which may not exist.
+    Symlink(String),
+    /// Regular file which is also reachable through another path. Its
+    /// content is still referenced, in case the other file is missing.
+    Hardlink(String),
+    Fifo,
+    /// Character device, with its device number.
+    CharDevice(u64),
+    /// Block device, with its device number.
+    BlockDevice(u64),
+    /// Directory, with the sorted names of its entries which are backed up too.
+    Directory(Vec<String>),
+}
+
+/// Manifest of a snapshot: the file versions backed up by a pass of the
+/// Source, which together make the state of its roots at that time.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Manifest {
+    /// Start of the pass, in nanoseconds since the epoch.
+    pub time: i64,
+    pub hostname: String,
+    pub roots: Vec<String>,
+    pub files: Vec<(FileId, Version)>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -98,7 +158,7 @@ pub struct ProtectedBlock {
     pub padding: Vec<u8>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Block {
     pub verified: Vec<u8>,
     pub protected: Arc<Vec<u8>>,
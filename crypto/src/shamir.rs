@@ -0,0 +1,144 @@
+//! Shamir's secret sharing over GF(256), byte by byte. Each share is its x coordinate followed by
+//! the y coordinate of every byte of the secret.
+//!
+//! The field uses the polynomial x^8 + x^4 + x^3 + x^2 + 1 (0x11d), and shares are numbered from
+//! 1, as the shares previously split with the `sharks` crate, which still combine. Unlike that
+//! crate, the random coefficients are drawn uniformly from the whole field: excluding 0 made the
+//! shares leak information about the secret (RUSTSEC-2024-0398).
+
+use anyhow::anyhow;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashSet;
+
+/// Splits `secret` into `shares` shares, any `threshold` of which recover it.
+pub(crate) fn split(secret: &[u8], threshold: u8, shares: u8) -> anyhow::Result<Vec<Vec<u8>>> {
+    if threshold == 0 || threshold > shares {
+        return Err(anyhow!(
+            "The threshold must be between 1 and the number of shares"
+        ));
+    }
+    // One polynomial per byte of the secret, whose constant term is that byte.
+    let degree = threshold as usize - 1;
+    let mut coefficients = vec![0u8; secret.len() * degree];
+    SystemRandom::new()
+        .fill(&mut coefficients)
+        .map_err(|_| anyhow!("Failed to generate coefficients"))?;
+    Ok((1..=shares)
+        .map(|x| {
+            let mut share = vec![x];
+            share.extend(secret.iter().enumerate().map(|(n, byte)| {
+                // Horner's method, from the highest degree down to the secret byte.
+                coefficients[n * degree..(n + 1) * degree]
+                    .iter()
+                    .rev()
+                    .chain(std::iter::once(byte))
+                    .fold(0, |acc, c| mul(acc, x) ^ c)
+            }));
+            share
+        })
+        .collect())
+}
+
+/// Recovers the secret from at least `threshold` distinct shares.
+pub(crate) fn combine<S: AsRef<[u8]>>(shares: &[S], threshold: u8) -> anyhow::Result<Vec<u8>> {
+    let mut seen = HashSet::new();
+    let shares: Vec<&[u8]> = shares
+        .iter()
+        .map(AsRef::as_ref)
+        .filter(|share| seen.insert(share.first().copied()))
+        .take(threshold as usize)
+        .collect();
+    if threshold == 0 || shares.len() < threshold as usize {
+        return Err(anyhow!("{} distinct shares are needed", threshold));
+    }
+    let len = shares[0].len();
+    if len < 2
+        || shares
+            .iter()
+            .any(|share| share.len() != len || share[0] == 0)
+    {
+        return Err(anyhow!("Invalid shares"));
+    }
+    // Lagrange interpolation at x = 0.
+    let weights: Vec<u8> = shares
+        .iter()
+        .map(|share_i| {
+            shares
+                .iter()
+                .filter(|share_j| share_j[0] != share_i[0])
+                .fold(1, |acc, share_j| {
+                    mul(acc, div(share_j[0], share_j[0] ^ share_i[0]))
+                })
+        })
+        .collect();
+    Ok((1..len)
+        .map(|n| {
+            shares
+                .iter()
+                .zip(&weights)
+                .fold(0, |acc, (share, weight)| acc ^ mul(share[n], *weight))
+        })
+        .collect())
+}
+
+/// Multiplies in GF(256), without tables indexed by secret values.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & (b & 1).wrapping_neg();
+        a = (a << 1) ^ (0x1d & (a >> 7).wrapping_neg());
+        b >>= 1;
+    }
+    product
+}
+
+/// Divides in GF(256), where a^254 is the inverse of a non-zero a.
+fn div(a: u8, b: u8) -> u8 {
+    let mut inverse = 1;
+    let mut power = b;
+    for _ in 0..7 {
+        power = mul(power, power);
+        inverse = mul(inverse, power);
+    }
+    mul(a, inverse)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn field() {
+        assert_eq!(mul(0x80, 2), 0x1d);
+        for a in 1..=255 {
+            assert_eq!(mul(div(1, a), a), 1);
+            assert_eq!(div(mul(a, 0x53), 0x53), a);
+        }
+    }
+
+    #[test]
+    fn split_and_combine() -> anyhow::Result<()> {
+        let secret = b"secret";
+        let shares = split(secret, 3, 5)?;
+        assert_eq!(shares.len(), 5);
+        assert!(shares.iter().all(|share| share.len() == secret.len() + 1));
+        for picked in [[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            assert_eq!(combine(&picked.map(|n| &shares[n]), 3)?, secret);
+        }
+        assert!(combine(&shares[..2], 3).is_err());
+        assert!(combine(&[&shares[0], &shares[1], &shares[0]], 3).is_err());
+        assert_eq!(combine(&split(secret, 1, 2)?[1..], 1)?, secret);
+        Ok(())
+    }
+
+    #[test]
+    fn combine_previous_shares() -> anyhow::Result<()> {
+        // Shares of b"piston" split 2 out of 3 by the sharks crate.
+        let shares: [&[u8]; 2] = [
+            &[1, 184, 161, 144, 170, 179, 200],
+            &[3, 53, 44, 75, 11, 22, 153],
+        ];
+        assert_eq!(combine(&shares, 2)?, b"piston");
+        Ok(())
+    }
+}
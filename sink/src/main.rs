@@ -1,20 +1,87 @@
 use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
 use server::Server;
 use settings::process;
 
 mod server;
 
+#[derive(Parser, Debug)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Store the data of Sources. This is the default.
+    Serve,
+    /// Remove the blocks no descriptor references anymore, once they are older than the grace
+    /// period. Safe to run while serving.
+    Gc {
+        /// Only report what would be removed.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Check the stored data still decodes and matches its checksums, without the keys of the
+    /// Sources. Fails if anything is corrupt.
+    Scrub,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = Args::parse();
     let settings = sink_settings::load().context("Failed to load the Sink settings")?;
     process::init(settings.process());
 
-    let server = Server::builder()
-        .settings(&settings)
-        .context("Failed to build the Sink configuration")?
-        .build()
-        .context("Failed to configure the Sink")?;
-    server.serve().await.context("Failed to run the Sink")?;
+    match args.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            let server = Server::builder()
+                .settings(&settings)
+                .context("Failed to build the Sink configuration")?
+                .build()
+                .context("Failed to configure the Sink")?;
+            server.serve().await.context("Failed to run the Sink")?;
+        }
+        Command::Gc { dry_run } => {
+            let storage = settings.storage();
+            let reports = server::collect(storage.root(), storage.gc_grace(), dry_run)
+                .await
+                .context("Failed to collect unreferenced blocks")?;
+            for (source, report) in reports {
+                println!(
+                    "{}: {} {} blocks, {} bytes ({} recent blocks kept)",
+                    source,
+                    if dry_run { "reclaimable" } else { "removed" },
+                    report.blocks,
+                    report.bytes,
+                    report.recent
+                );
+            }
+        }
+        Command::Scrub => {
+            let reports = server::scrub(settings.storage().root())
+                .await
+                .context("Failed to scrub the stored data")?;
+            let mut corrupt = 0;
+            for (source, report) in reports {
+                println!(
+                    "{}: {} blocks, {} descriptors, {} without checksum, {} corrupt",
+                    source,
+                    report.blocks,
+                    report.descriptors,
+                    report.unchecked,
+                    report.corrupt.len()
+                );
+                for entry in &report.corrupt {
+                    println!("  {}", entry);
+                }
+                corrupt += report.corrupt.len();
+            }
+            if corrupt > 0 {
+                anyhow::bail!("Found {} corrupt entries", corrupt);
+            }
+        }
+    }
 
     Ok(())
 }
@@ -1,23 +1,39 @@
 use anyhow::{Context, Result};
 use broker_client::Broker;
+use crypto::model;
 use rpcutil::auth;
 use settings::connection;
 use sink_proto::{
+    fetch_blocks_reply,
     sink_server::{Sink, SinkServer},
-    StoreReply, StoreRequest,
+    store_request, FetchBlocksReply, FetchBlocksRequest, FetchDescriptorReply,
+    FetchDescriptorRequest, ListReply, ListRequest, PruneReply, PruneRequest, StoreReply,
+    StoreRequest, StoredFile, UploadReply, UploadRequest,
 };
 use sink_settings::Settings;
 use std::net::{AddrParseError, SocketAddr};
+use std::path::{Path, PathBuf};
+use storage::layout;
+use store::Store;
 use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{
     transport::{self, ServerTlsConfig},
-    Request, Response, Status,
+    Request, Response, Status, Streaming,
 };
+use upload::{Assembler, Item};
+
+pub mod gc;
+pub mod scrub;
+mod store;
+mod upload;
 
 pub struct Server {
     address: SocketAddr,
     connection: connection::Info,
     broker: broker_client::Settings,
+    storage: PathBuf,
 }
 
 #[derive(Default)]
@@ -25,6 +41,7 @@ pub struct Builder {
     address: Option<SocketAddr>,
     broker_connection: Option<connection::Info>,
     broker: Option<broker_client::Settings>,
+    storage: Option<PathBuf>,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -37,6 +54,8 @@ pub enum BuilderError {
     MissingAddress,
     #[error("Invalid listening address")]
     InvalidAddress(#[from] AddrParseError),
+    #[error("Missing storage location")]
+    MissingStorage,
 }
 
 impl Builder {
@@ -44,7 +63,8 @@ impl Builder {
         Ok(self
             .connection(settings.connection())
             .address(settings.server().address().clone())
-            .broker(settings.broker()))
+            .broker(settings.broker())
+            .storage(settings.storage().root()))
     }
 
     pub fn address(mut self, addr: SocketAddr) -> Builder {
@@ -62,6 +82,11 @@ impl Builder {
         self
     }
 
+    pub fn storage(mut self, root: &Path) -> Builder {
+        self.storage = Some(root.to_path_buf());
+        self
+    }
+
     pub fn build(self) -> Result<Server, BuilderError> {
         Ok(Server {
             address: self.address.ok_or(BuilderError::MissingAddress)?,
@@ -69,6 +94,7 @@ impl Builder {
                 .broker_connection
                 .ok_or(BuilderError::MissingConnection)?,
             broker: self.broker.ok_or(BuilderError::MissingBrokerInfo)?,
+            storage: self.storage.ok_or(BuilderError::MissingStorage)?,
         })
     }
 }
@@ -107,7 +133,7 @@ impl Server {
 
         broker.set_addresses(&accepting_on).await?;
 
-        let sink = SinkImpl::default();
+        let sink = SinkImpl::new(Store::new(&self.storage)?);
         transport::Server::builder()
             .tls_config(
                 ServerTlsConfig::new()
@@ -123,18 +149,581 @@ impl Server {
     }
 }
 
-#[derive(Default)]
-struct SinkImpl {}
+/// Removes the blocks no descriptor references anymore from `storage`, for every Source, unless
+/// they are more recent than `grace`. Returns what was, or would be if `dry_run`, removed for each
+/// Source.
+pub async fn collect(
+    storage: &Path,
+    grace: std::time::Duration,
+    dry_run: bool,
+) -> Result<Vec<(String, gc::Report)>> {
+    Store::new(storage)?.collect(grace, dry_run).await
+}
+
+/// Checks the data of every Source in `storage` still decodes and matches its checksums, without
+/// any key. Returns what was found for each Source.
+pub async fn scrub(storage: &Path) -> Result<Vec<(String, scrub::Report)>> {
+    Store::new(storage)?.scrub().await
+}
+
+struct SinkImpl {
+    store: Store,
+}
+
+impl SinkImpl {
+    fn new(store: Store) -> Self {
+        SinkImpl { store }
+    }
+}
 
 #[tonic::async_trait]
 impl Sink for SinkImpl {
     async fn store(&self, request: Request<StoreRequest>) -> Result<Response<StoreReply>, Status> {
-        let peer = auth::peer(&request)?;
-        tracing::info!("[{:?}] source()", &peer);
-
-        let request = request.get_ref();
-        tracing::info!("received {} bytes of data", request.data.len());
+        let source = source(&request)?;
+        let item = match request.into_inner().data {
+            Some(store_request::Data::Block(pb)) => Item::Block(layout::block_from_proto(pb)),
+            Some(store_request::Data::Descriptor(pb)) => {
+                Item::Descriptor(layout::descriptor_from_proto(pb))
+            }
+            None => return Err(Status::invalid_argument("Nothing to store")),
+        };
+        self.put(&source, item).await?;
 
         Ok(Response::new(StoreReply {}))
     }
+
+    async fn upload(
+        &self,
+        request: Request<Streaming<UploadRequest>>,
+    ) -> Result<Response<UploadReply>, Status> {
+        let source = source(&request)?;
+        tracing::info!("[{}] upload()", &source);
+
+        let mut frames = request.into_inner();
+        let mut assembler = Assembler::default();
+        let mut reply = UploadReply::default();
+        while let Some(request) = frames.message().await? {
+            let frame = request
+                .frame
+                .ok_or_else(|| Status::invalid_argument("Empty frame"))?;
+            let item = assembler
+                .push(frame)
+                .map_err(|err| Status::invalid_argument(err.to_string()))?;
+            if let Some(item) = item {
+                match &item {
+                    Item::Block(_) => reply.blocks += 1,
+                    Item::Descriptor(_) => reply.descriptors += 1,
+                }
+                self.put(&source, item).await?;
+            }
+        }
+        assembler
+            .finish()
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        tracing::info!(
+            "[{}] uploaded {} blocks and {} descriptors",
+            &source,
+            reply.blocks,
+            reply.descriptors
+        );
+
+        Ok(Response::new(reply))
+    }
+
+    async fn list(&self, request: Request<ListRequest>) -> Result<Response<ListReply>, Status> {
+        let source = source(&request)?;
+        tracing::info!("[{}] list()", &source);
+
+        let files = self.store.list(&source).await.map_err(read_error)?;
+        Ok(Response::new(ListReply {
+            file: files
+                .into_iter()
+                .map(|(file_id, versions)| StoredFile {
+                    file_id: file_id.as_bytes().to_vec(),
+                    version: versions,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn fetch_descriptor(
+        &self,
+        request: Request<FetchDescriptorRequest>,
+    ) -> Result<Response<FetchDescriptorReply>, Status> {
+        let source = source(&request)?;
+        let request = request.into_inner();
+        let file_id = file_id(&request.file_id)?;
+        tracing::info!(
+            "[{}] fetch_descriptor({:?}, {}, {})",
+            &source,
+            &file_id,
+            request.version,
+            request.index
+        );
+        let index =
+            u16::try_from(request.index).map_err(|_| Status::not_found("No such descriptor"))?;
+
+        let descriptor = self
+            .store
+            .descriptor(&source, file_id, request.version, index)
+            .await
+            .map_err(read_error)?;
+        Ok(Response::new(FetchDescriptorReply {
+            descriptor: Some(layout::descriptor_to_proto(&descriptor)),
+        }))
+    }
+
+    type FetchBlocksStream = ReceiverStream<Result<FetchBlocksReply, Status>>;
+
+    async fn fetch_blocks(
+        &self,
+        request: Request<FetchBlocksRequest>,
+    ) -> Result<Response<Self::FetchBlocksStream>, Status> {
+        let source = source(&request)?;
+        let request = request.into_inner();
+        let file_id = file_id(&request.file_id)?;
+        let block_ids = request
+            .block_id
+            .iter()
+            .map(|id| model::BlockId::try_from(id.as_slice()))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(|_| Status::invalid_argument("Invalid block_id"))?;
+        tracing::info!(
+            "[{}] fetch_blocks({:?}, {} blocks)",
+            &source,
+            &file_id,
+            block_ids.len()
+        );
+
+        let (tx, rx) = mpsc::channel(1);
+        let store = self.store.clone();
+        tokio::spawn(async move {
+            for block_id in block_ids {
+                let block = match store
+                    .block(&source, file_id.clone(), block_id.clone())
+                    .await
+                {
+                    Ok(block) => block,
+                    Err(err) => {
+                        // Stop on the first failure.
+                        let _ = tx.send(Err(read_error(err))).await;
+                        break;
+                    }
+                };
+                let (start, continuations) =
+                    sink_proto::split_block(layout::block_to_proto(&block));
+                let frames = std::iter::once(FetchBlocksReply {
+                    block_id: block_id.as_bytes().to_vec(),
+                    frame: Some(fetch_blocks_reply::Frame::Block(start)),
+                })
+                .chain(continuations.into_iter().map(|part| FetchBlocksReply {
+                    block_id: vec![],
+                    frame: Some(fetch_blocks_reply::Frame::Continuation(part)),
+                }));
+                for frame in frames {
+                    // Stop early if the client went away.
+                    if tx.send(Ok(frame)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn prune(&self, request: Request<PruneRequest>) -> Result<Response<PruneReply>, Status> {
+        let source = source(&request)?;
+        let files = request
+            .into_inner()
+            .file
+            .into_iter()
+            .map(|file| Ok((file_id(&file.file_id)?, file.version)))
+            .collect::<Result<Vec<_>, Status>>()?;
+        tracing::info!("[{}] prune({} files)", &source, files.len());
+
+        let descriptors = self.store.prune(&source, files).await.map_err(|err| {
+            tracing::error!("failed to prune data: {:?}", err);
+            Status::internal("Failed to prune data")
+        })?;
+        tracing::info!("[{}] pruned {} descriptors", &source, descriptors);
+        Ok(Response::new(PruneReply { descriptors }))
+    }
+}
+
+impl SinkImpl {
+    /// Durably stores an item for `source`, indexed by its verified part.
+    async fn put(&self, source: &auth::Source, item: Item) -> Result<(), Status> {
+        let result = match item {
+            Item::Block(block) => {
+                let verified = crypto::peek_block(&block)
+                    .map_err(|_| Status::invalid_argument("Invalid block"))?;
+                tracing::debug!(
+                    "[{}] store block {:?}/{:?}",
+                    source,
+                    &verified.file_id,
+                    &verified.block_id
+                );
+                self.store
+                    .put_block(source, verified.file_id, verified.block_id, block)
+                    .await
+            }
+            Item::Descriptor(descriptor) => {
+                let verified = crypto::peek_descriptor(&descriptor)
+                    .map_err(|_| Status::invalid_argument("Invalid descriptor"))?;
+                tracing::debug!(
+                    "[{}] store descriptor {:?} v{}",
+                    source,
+                    &verified.file_id,
+                    verified.version
+                );
+                if verified.index >= verified.total {
+                    return Err(Status::invalid_argument("Invalid descriptor index"));
+                }
+                self.store
+                    .put_descriptor(
+                        source,
+                        verified.file_id,
+                        verified.version,
+                        verified.index,
+                        descriptor,
+                    )
+                    .await
+            }
+        };
+        result.map_err(|err| {
+            tracing::error!("failed to store data: {:?}", err);
+            Status::internal("Failed to store data")
+        })
+    }
+}
+
+/// Returns the Source performing the request. Other peers don't have access to the Sink's data.
+fn source<T>(request: &Request<T>) -> Result<auth::Source, Status> {
+    match auth::peer(request)? {
+        auth::Peer::Source(source) => Ok(source.clone()),
+        _ => Err(Status::permission_denied(
+            "Only Sources can access the Sink",
+        )),
+    }
+}
+
+fn file_id(file_id: &[u8]) -> Result<model::FileId, Status> {
+    model::FileId::try_from(file_id).map_err(|_| Status::invalid_argument("Invalid file_id"))
+}
+
+/// Maps a failure to read the store into a Status. Data that can't be found, or that doesn't
+/// belong to the caller, is reported as missing.
+fn read_error(err: anyhow::Error) -> Status {
+    match err.root_cause().downcast_ref::<std::io::Error>() {
+        Some(io) if io.kind() != std::io::ErrorKind::NotFound => {
+            tracing::error!("failed to read data: {:?}", err);
+            Status::internal("Failed to read data")
+        }
+        _ => Status::not_found("No such data"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::RandomApi;
+    use rpcutil::testing;
+
+    /// Encrypted data for a single file, as a Source would send it.
+    struct TestFile {
+        file_id: model::FileId,
+        block_id: model::BlockId,
+        block: model::Block,
+        descriptor: model::Descriptor,
+    }
+
+    impl TestFile {
+        fn new() -> anyhow::Result<Self> {
+            Self::with_chunk(bytes::Bytes::from_static(b"abc"))
+        }
+
+        fn with_chunk(chunk: bytes::Bytes) -> anyhow::Result<Self> {
+            let size = chunk.len() as u64;
+            let rnd = crypto::Random::new();
+            let keys = crypto::Keys::new(rnd.generate_root_key()?);
+            let file_id = rnd.generate_file_id()?;
+            let block_id = rnd.generate_block_id()?;
+            let block = keys.encrypt_block(
+                model::VerifiedBlock {
+                    file_id: file_id.clone(),
+                    block_id: block_id.clone(),
+                },
+                model::ProtectedBlock {
+                    chunk,
+                    padding: vec![],
+                },
+            )?;
+            let descriptor = keys.encrypt_descriptor(
+                model::VerifiedDescriptor {
+                    file_id: file_id.clone(),
+                    version: 0,
+                    index: 0,
+                    total: 1,
+                    chunks: vec![model::BlockRef {
+                        file_id: file_id.clone(),
+                        block_id: block_id.clone(),
+                    }],
+                },
+                model::ProtectedDescriptor {
+                    filename: "file".to_string(),
+                    size,
+                    ..Default::default()
+                },
+            )?;
+            Ok(TestFile {
+                file_id,
+                block_id,
+                block,
+                descriptor,
+            })
+        }
+
+        /// Stores the file through the Sink on behalf of `peer`.
+        async fn store(&self, sink: &SinkImpl, peer: &auth::Peer) -> Result<(), Status> {
+            for data in [
+                store_request::Data::Block(layout::block_to_proto(&self.block)),
+                store_request::Data::Descriptor(layout::descriptor_to_proto(&self.descriptor)),
+            ] {
+                sink.store(testing::request(
+                    StoreRequest { data: Some(data) },
+                    peer.clone(),
+                ))
+                .await?;
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn store_from_source() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let sink = SinkImpl::new(Store::new(tmpdir.path())?);
+        let file = TestFile::new()?;
+
+        let source = auth::Peer::Source(auth::Source::new("111.src.piston.com"));
+        file.store(&sink, &source).await?;
+
+        let stored = layout::Root::new(tmpdir.path().join("111.src.piston.com"))
+            .existing_file(&file.file_id)?;
+        assert_eq!(stored.read_block(&file.block_id)?, file.block);
+        assert_eq!(stored.read_descriptor(0, 0)?, file.descriptor);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_data() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let sink = SinkImpl::new(Store::new(tmpdir.path())?);
+        let source = auth::Peer::Source(auth::Source::new("111.src.piston.com"));
+
+        for data in [
+            None,
+            Some(store_request::Data::Block(layout_proto::Block {
+                protected: vec![1],
+                verified: vec![2, 3],
+            })),
+            Some(store_request::Data::Descriptor(layout_proto::Descriptor {
+                protected: vec![1],
+                verified: vec![],
+            })),
+        ] {
+            let err = sink
+                .store(testing::request(StoreRequest { data }, source.clone()))
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_stored_data() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let sink = SinkImpl::new(Store::new(tmpdir.path())?);
+        let source = auth::Peer::Source(auth::Source::new("111.src.piston.com"));
+        let file = TestFile::new()?;
+        file.store(&sink, &source).await?;
+
+        let list = sink
+            .list(testing::request(ListRequest {}, source.clone()))
+            .await?
+            .into_inner();
+        assert_eq!(
+            list.file,
+            vec![StoredFile {
+                file_id: file.file_id.as_bytes().to_vec(),
+                version: vec![0],
+            }]
+        );
+
+        let descriptor = sink
+            .fetch_descriptor(testing::request(
+                FetchDescriptorRequest {
+                    file_id: file.file_id.as_bytes().to_vec(),
+                    version: 0,
+                    index: 0,
+                },
+                source.clone(),
+            ))
+            .await?
+            .into_inner();
+        assert_eq!(
+            descriptor.descriptor,
+            Some(layout::descriptor_to_proto(&file.descriptor))
+        );
+
+        let mut blocks = sink
+            .fetch_blocks(testing::request(
+                FetchBlocksRequest {
+                    file_id: file.file_id.as_bytes().to_vec(),
+                    block_id: vec![file.block_id.as_bytes().to_vec(), vec![0; 12]],
+                },
+                source.clone(),
+            ))
+            .await?
+            .into_inner()
+            .into_inner();
+        let block = blocks.recv().await.unwrap()?;
+        assert_eq!(block.block_id, file.block_id.as_bytes().to_vec());
+        let (start, _) = sink_proto::split_block(layout::block_to_proto(&file.block));
+        assert_eq!(block.frame, Some(fetch_blocks_reply::Frame::Block(start)));
+        // The second block does not exist.
+        assert_eq!(
+            blocks.recv().await.unwrap().unwrap_err().code(),
+            tonic::Code::NotFound
+        );
+        assert!(blocks.recv().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_large_blocks() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let sink = SinkImpl::new(Store::new(tmpdir.path())?);
+        let source = auth::Peer::Source(auth::Source::new("111.src.piston.com"));
+        // Larger than the messages tonic decodes by default.
+        let file = TestFile::with_chunk(vec![7; 5 << 20].into())?;
+        file.store(&sink, &source).await?;
+
+        let service = SinkServer::with_interceptor(sink, move |mut request: Request<()>| {
+            request.extensions_mut().insert(source.clone());
+            Ok(request)
+        });
+        let client = sink_client::SinkImpl::new(testing::fake_server(service).await?);
+        let blocks = sink_client::Sink::fetch_blocks(
+            &client,
+            file.file_id.as_bytes(),
+            &[file.block_id.as_bytes().to_vec()],
+        )
+        .await?;
+        assert_eq!(blocks, vec![layout::block_to_proto(&file.block)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prune_stored_versions() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let sink = SinkImpl::new(Store::new(tmpdir.path())?);
+        let source = auth::Peer::Source(auth::Source::new("111.src.piston.com"));
+        let file = TestFile::new()?;
+        file.store(&sink, &source).await?;
+
+        let reply = sink
+            .prune(testing::request(
+                PruneRequest {
+                    file: vec![StoredFile {
+                        file_id: file.file_id.as_bytes().to_vec(),
+                        version: vec![0],
+                    }],
+                },
+                source.clone(),
+            ))
+            .await?
+            .into_inner();
+        assert_eq!(reply.descriptors, 1);
+        let list = sink
+            .list(testing::request(ListRequest {}, source.clone()))
+            .await?
+            .into_inner();
+        assert_eq!(
+            list.file,
+            vec![StoredFile {
+                file_id: file.file_id.as_bytes().to_vec(),
+                version: vec![],
+            }]
+        );
+
+        let err = sink
+            .prune(testing::request(
+                PruneRequest {
+                    file: vec![StoredFile {
+                        file_id: vec![1],
+                        version: vec![0],
+                    }],
+                },
+                source,
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_is_per_source() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let sink = SinkImpl::new(Store::new(tmpdir.path())?);
+        let file = TestFile::new()?;
+        file.store(
+            &sink,
+            &auth::Peer::Source(auth::Source::new("111.src.piston.com")),
+        )
+        .await?;
+
+        let other = auth::Peer::Source(auth::Source::new("222.src.piston.com"));
+        let list = sink
+            .list(testing::request(ListRequest {}, other.clone()))
+            .await?
+            .into_inner();
+        assert!(list.file.is_empty());
+        let err = sink
+            .fetch_descriptor(testing::request(
+                FetchDescriptorRequest {
+                    file_id: file.file_id.as_bytes().to_vec(),
+                    version: 0,
+                    index: 0,
+                },
+                other,
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+
+        let user = auth::Peer::User(auth::User::new("bob@example.com"));
+        let err = sink
+            .list(testing::request(ListRequest {}, user))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_other_peers() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let sink = SinkImpl::new(Store::new(tmpdir.path())?);
+        let file = TestFile::new()?;
+
+        let sink_peer = auth::Peer::Sink(auth::Sink::new("222.snk.piston.com"));
+        assert_eq!(
+            file.store(&sink, &sink_peer).await.unwrap_err().code(),
+            tonic::Code::PermissionDenied
+        );
+        Ok(())
+    }
 }
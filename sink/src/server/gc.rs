@@ -0,0 +1,203 @@
+//! Garbage collection of the blocks no descriptor references anymore, typically once a Source
+//! pruned the versions using them. Only the verified parts are read, so no key is needed.
+//!
+//! Blocks are uploaded before the descriptors referencing them, so recent blocks are kept for a
+//! grace period. Sources only reuse blocks that versions they keep reference, so older blocks
+//! never become referenced again.
+
+use anyhow::{Context, Result};
+use crypto::model;
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+use storage::layout;
+
+/// Outcome of a collection.
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    /// Blocks no descriptor references, and their size on disk.
+    pub blocks: u64,
+    pub bytes: u64,
+    /// Unreferenced blocks kept as they are more recent than the grace period.
+    pub recent: u64,
+}
+
+/// Removes the blocks of `root` no descriptor references, unless they are more recent than
+/// `grace`. Only reports what would be removed if `dry_run`.
+pub fn collect(root: &layout::Root, grace: Duration, dry_run: bool) -> Result<Report> {
+    let files = root.files()?;
+    let mut referenced = HashSet::new();
+    for file_id in &files {
+        let file = root.existing_file(file_id)?;
+        for version in file.versions()? {
+            mark(&file, file_id, version, &mut referenced)?;
+        }
+    }
+
+    let now = SystemTime::now();
+    let mut report = Report::default();
+    for file_id in &files {
+        let file = root.existing_file(file_id)?;
+        for block_id in file.blocks()? {
+            if referenced.contains(&(file_id.clone(), block_id.clone())) {
+                continue;
+            }
+            let metadata = file.block_metadata(&block_id)?;
+            let age = now
+                .duration_since(metadata.modified()?)
+                .unwrap_or(Duration::ZERO);
+            if age < grace {
+                report.recent += 1;
+                continue;
+            }
+            report.blocks += 1;
+            report.bytes += metadata.len();
+            if !dry_run {
+                file.remove_block(&block_id)?;
+            }
+        }
+        if !dry_run {
+            // Left by interrupted writes, they would keep the directory.
+            file.remove_temporaries(grace)?;
+            file.remove_orphaned_checksums(grace)?;
+            file.remove_if_empty()?;
+        }
+    }
+    Ok(report)
+}
+
+/// Adds the blocks referenced by a version to `referenced`.
+fn mark(
+    file: &layout::File,
+    file_id: &model::FileId,
+    version: model::Version,
+    referenced: &mut HashSet<(model::FileId, model::BlockId)>,
+) -> Result<()> {
+    let context = || format!("Invalid descriptor for {:?} v{}", file_id, version);
+    let first =
+        crypto::peek_descriptor(&file.read_descriptor(version, 0)?).with_context(context)?;
+    let mut descriptors = vec![first.clone()];
+    for index in 1..first.total {
+        match file.read_descriptor(version, index) {
+            Ok(descriptor) => {
+                descriptors.push(crypto::peek_descriptor(&descriptor).with_context(context)?)
+            }
+            // The upload of the version was interrupted: the Source never references it.
+            Err(err) if layout::is_not_found(&err) => {
+                tracing::warn!("{:?} v{} is incomplete", file_id, version);
+                break;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    for descriptor in descriptors {
+        if descriptor.file_id != *file_id || descriptor.version != version {
+            return Err(anyhow::anyhow!(context()));
+        }
+        for chunk in descriptor.chunks {
+            referenced.insert((chunk.file_id, chunk.block_id));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::RandomApi;
+    use storage::testing::Fixture;
+
+    const CHUNK: bytes::Bytes = bytes::Bytes::from_static(b"abc");
+
+    #[test]
+    fn collect_unreferenced_blocks() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let fixture = Fixture::new(tmpdir.path())?;
+        let kept = fixture.rnd.generate_file_id()?;
+        let pruned = fixture.rnd.generate_file_id()?;
+        // The block of the pruned file is still used by the kept one.
+        let shared = fixture.block(&pruned, CHUNK)?;
+        let own = fixture.block(&kept, CHUNK)?;
+        // Unreferenced blocks.
+        fixture.block(&kept, CHUNK)?;
+        fixture.block(&pruned, CHUNK)?;
+        fixture.describe(&kept, 0, &[own.clone(), shared.clone()], Default::default())?;
+
+        // Everything is recent.
+        let report = collect(&fixture.root, Duration::from_secs(3600), false)?;
+        assert_eq!(
+            report,
+            Report {
+                recent: 2,
+                ..Default::default()
+            }
+        );
+
+        let report = collect(&fixture.root, Duration::ZERO, true)?;
+        assert_eq!(report.blocks, 2);
+        assert!(report.bytes > 0);
+        assert_eq!(fixture.root.existing_file(&pruned)?.blocks()?.len(), 2);
+
+        assert_eq!(collect(&fixture.root, Duration::ZERO, false)?, report);
+        assert_eq!(
+            fixture.root.existing_file(&kept)?.blocks()?,
+            vec![own.block_id]
+        );
+        assert_eq!(
+            fixture.root.existing_file(&pruned)?.blocks()?,
+            vec![shared.block_id]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn remove_pruned_files() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let fixture = Fixture::new(tmpdir.path())?;
+        let file_id = fixture.rnd.generate_file_id()?;
+        let block = fixture.block(&file_id, CHUNK)?;
+        fixture.describe(&file_id, 0, &[block], Default::default())?;
+        fixture.root.existing_file(&file_id)?.remove_version(0)?;
+        // Left by an interrupted write.
+        std::fs::write(
+            tmpdir
+                .path()
+                .join(hex::encode(file_id.as_bytes()))
+                .join(".v1.dsc.tmp"),
+            b"",
+        )?;
+        fixture
+            .root
+            .existing_file(&file_id)?
+            .write_block_checksum(&fixture.rnd.generate_block_id()?, &[1])?;
+
+        let report = collect(&fixture.root, Duration::ZERO, false)?;
+        assert_eq!(report.blocks, 1);
+        assert!(fixture.root.files()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn skip_incomplete_versions() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let mut fixture = Fixture::new(tmpdir.path())?;
+        // One descriptor per block.
+        fixture.max_refs = 1;
+        let file_id = fixture.rnd.generate_file_id()?;
+        let first = fixture.block(&file_id, CHUNK)?;
+        let second = fixture.block(&file_id, CHUNK)?;
+        fixture.describe(&file_id, 0, &[first.clone(), second], Default::default())?;
+        std::fs::remove_file(
+            tmpdir
+                .path()
+                .join(format!("{}/v0.1.dsc", hex::encode(file_id.as_bytes()))),
+        )?;
+
+        let report = collect(&fixture.root, Duration::ZERO, false)?;
+        assert_eq!(report.blocks, 1);
+        assert_eq!(
+            fixture.root.existing_file(&file_id)?.blocks()?,
+            vec![first.block_id]
+        );
+        Ok(())
+    }
+}
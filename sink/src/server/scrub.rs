@@ -0,0 +1,199 @@
+//! Scrubbing re-reads the data stored for a Source to find corruption, such as bit-rot, before a
+//! restore needs it. Only the verified parts and the checksums kept alongside each entry are
+//! checked, so no key is needed: the Source still authenticates everything when restoring.
+
+use anyhow::Result;
+use crypto::model;
+use storage::layout;
+
+/// Outcome of a scrub.
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    /// Entries read.
+    pub blocks: u64,
+    pub descriptors: u64,
+    /// Entries stored without a checksum, which are only checked to decode.
+    pub unchecked: u64,
+    /// Entries which failed a check, with what failed.
+    pub corrupt: Vec<String>,
+}
+
+/// Checks every block and descriptor of `root`: each must decode, its verified part must match
+/// where it is stored, and its checksum must match if there is one.
+pub fn scrub(root: &layout::Root) -> Result<Report> {
+    let mut report = Report::default();
+    for file_id in root.files()? {
+        let file = root.existing_file(&file_id)?;
+        for block_id in file.blocks()? {
+            report.blocks += 1;
+            let name = format!("{:?} block {:?}", file_id, block_id);
+            match check_block(&file, &file_id, &block_id) {
+                Ok(checked) => report.unchecked += !checked as u64,
+                Err(err) => report.corrupt.push(format!("{}: {:#}", name, err)),
+            }
+        }
+        for version in file.versions()? {
+            let mut total = 1;
+            let mut index = 0;
+            while index < total {
+                let name = format!("{:?} v{}.{}", file_id, version, index);
+                match check_descriptor(&file, &file_id, version, index) {
+                    Ok((descriptor_total, checked)) => {
+                        report.descriptors += 1;
+                        report.unchecked += !checked as u64;
+                        if index == 0 {
+                            total = descriptor_total;
+                        }
+                    }
+                    // The upload of the version was interrupted: nothing references it.
+                    Err(err) if index > 0 && layout::is_not_found(&err) => {
+                        tracing::warn!("{:?} v{} is incomplete", file_id, version);
+                        break;
+                    }
+                    Err(err) => {
+                        report.descriptors += 1;
+                        report.corrupt.push(format!("{}: {:#}", name, err));
+                        // The total is unknown without the first descriptor.
+                        if index == 0 {
+                            break;
+                        }
+                    }
+                }
+                index += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Checks a block, returning whether it had a checksum.
+fn check_block(
+    file: &layout::File,
+    file_id: &model::FileId,
+    block_id: &model::BlockId,
+) -> Result<bool> {
+    let block = file.read_block(block_id)?;
+    let verified = crypto::peek_block(&block)?;
+    if verified.file_id != *file_id || verified.block_id != *block_id {
+        anyhow::bail!(
+            "Stored as {:?} {:?} but names {:?} {:?}",
+            file_id,
+            block_id,
+            verified.file_id,
+            verified.block_id
+        );
+    }
+    verify_checksum(
+        file.read_block_checksum(block_id)?,
+        &block.verified,
+        &block.protected,
+    )
+}
+
+/// Checks a descriptor, returning its total and whether it had a checksum.
+fn check_descriptor(
+    file: &layout::File,
+    file_id: &model::FileId,
+    version: model::Version,
+    index: u16,
+) -> Result<(u16, bool)> {
+    let descriptor = file.read_descriptor(version, index)?;
+    let verified = crypto::peek_descriptor(&descriptor)?;
+    if verified.file_id != *file_id || verified.version != version || verified.index != index {
+        anyhow::bail!(
+            "Stored as {:?} v{}.{} but names {:?} v{}.{}",
+            file_id,
+            version,
+            index,
+            verified.file_id,
+            verified.version,
+            verified.index
+        );
+    }
+    let checked = verify_checksum(
+        file.read_descriptor_checksum(version, index)?,
+        &descriptor.verified,
+        &descriptor.protected,
+    )?;
+    Ok((verified.total, checked))
+}
+
+fn verify_checksum(checksum: Option<Vec<u8>>, verified: &[u8], protected: &[u8]) -> Result<bool> {
+    match checksum {
+        None => Ok(false),
+        Some(checksum) if checksum == layout::checksum(verified, protected) => Ok(true),
+        Some(_) => Err(anyhow::anyhow!("Checksum mismatch")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::RandomApi;
+    use storage::testing::Fixture;
+
+    const CHUNK: bytes::Bytes = bytes::Bytes::from_static(b"abc");
+
+    #[test]
+    fn clean() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let mut fixture = Fixture::new(tmpdir.path())?;
+        fixture.max_refs = 1;
+        let file_id = fixture.rnd.generate_file_id()?;
+        let block = fixture.block(&file_id, CHUNK)?;
+        let protected = model::ProtectedDescriptor::default();
+        fixture.describe(
+            &file_id,
+            0,
+            &[block.clone(), block.clone()],
+            protected.clone(),
+        )?;
+        // Stored before checksums were kept.
+        fixture.describe(&file_id, 1, &[block], protected)?;
+        let dir = tmpdir.path().join(hex::encode(file_id.as_bytes()));
+        std::fs::remove_file(dir.join("v1.dsc.sum"))?;
+
+        assert_eq!(
+            scrub(&fixture.root)?,
+            Report {
+                blocks: 1,
+                descriptors: 3,
+                unchecked: 1,
+                corrupt: vec![],
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn detect_corruption() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let fixture = Fixture::new(tmpdir.path())?;
+        let file_id = fixture.rnd.generate_file_id()?;
+        let dir = tmpdir.path().join(hex::encode(file_id.as_bytes()));
+        // Bit-rot in the protected part.
+        let rotten = fixture.block(&file_id, CHUNK)?;
+        let path = dir.join(format!("{}.blk", hex::encode(rotten.block_id.as_bytes())));
+        let mut data = std::fs::read(&path)?;
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        std::fs::write(&path, data)?;
+        // Stored under the wrong name.
+        let misplaced = fixture.rnd.generate_block_id()?;
+        fixture.block_as(
+            &file_id,
+            &fixture.rnd.generate_block_id()?,
+            &misplaced,
+            CHUNK,
+        )?;
+        // No longer decodes.
+        fixture.describe(&file_id, 0, &[], model::ProtectedDescriptor::default())?;
+        std::fs::write(dir.join("v0.dsc"), b"\xff\xff")?;
+
+        let report = scrub(&fixture.root)?;
+        assert_eq!(report.blocks, 2);
+        assert_eq!(report.descriptors, 1);
+        assert_eq!(report.corrupt.len(), 3, "{:?}", report.corrupt);
+        Ok(())
+    }
+}
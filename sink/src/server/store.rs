@@ -0,0 +1,440 @@
+//! Durable storage of the data sent by Sources.
+//!
+//! Each Source gets its own directory under the root, which follows the
+//! canonical `storage::layout`: data is indexed by file_id, block_id and
+//! version, as found in the verified part of each message. Data is only
+//! considered stored once it has been fsync'ed.
+
+use anyhow::{anyhow, Context, Result};
+use crypto::model;
+use rpcutil::auth;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use storage::layout;
+
+use super::{gc, scrub};
+
+/// Storage for all the Sources using this Sink.
+#[derive(Debug, Clone)]
+pub struct Store {
+    root: PathBuf,
+}
+
+impl Store {
+    /// Creates a new Store rooted at `root`. The directory is created if it does not exist.
+    pub fn new(root: &Path) -> Result<Self> {
+        fs::create_dir_all(root).context(format!("Failed to create storage dir {:?}", root))?;
+        Ok(Store {
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// Durably stores a block on behalf of `source`, once it has reached the disk.
+    pub async fn put_block(
+        &self,
+        source: &auth::Source,
+        file_id: model::FileId,
+        block_id: model::BlockId,
+        block: model::Block,
+    ) -> Result<()> {
+        let root = self.create_layout(source)?;
+        tokio::task::spawn_blocking(move || {
+            let file = root.file(&file_id)?;
+            // Block ids are never reused by a Source, so there is no need to write
+            // the same block again. Its checksum goes first, so that an interrupted write
+            // never leaves a block without one.
+            if !file.has_block(&block_id) {
+                file.write_block_checksum(
+                    &block_id,
+                    &layout::checksum(&block.verified, &block.protected),
+                )?;
+                file.write_block(&block, &block_id)?;
+            }
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Durably stores a descriptor on behalf of `source`, once it has reached the disk.
+    pub async fn put_descriptor(
+        &self,
+        source: &auth::Source,
+        file_id: model::FileId,
+        version: model::Version,
+        index: u16,
+        descriptor: model::Descriptor,
+    ) -> Result<()> {
+        let root = self.create_layout(source)?;
+        tokio::task::spawn_blocking(move || {
+            let file = root.file(&file_id)?;
+            let checksum = layout::checksum(&descriptor.verified, &descriptor.protected);
+            // A new descriptor gets its checksum first, so that an interrupted write never
+            // leaves it without one. A replaced descriptor, once encrypted again, loses its old
+            // checksum first instead, so that it is never paired with the wrong one.
+            if file.has_descriptor(version, index) {
+                file.remove_descriptor_checksum(version, index)?;
+                file.write_descriptor(&descriptor, version, index)?;
+                file.write_descriptor_checksum(version, index, &checksum)
+            } else {
+                file.write_descriptor_checksum(version, index, &checksum)?;
+                file.write_descriptor(&descriptor, version, index)
+            }
+        })
+        .await?
+    }
+
+    /// Lists the files stored by `source`, with all their versions in increasing order.
+    pub async fn list(
+        &self,
+        source: &auth::Source,
+    ) -> Result<Vec<(model::FileId, Vec<model::Version>)>> {
+        let root = self.layout(source)?;
+        tokio::task::spawn_blocking(move || {
+            let Some(root) = root else {
+                return Ok(vec![]);
+            };
+            let mut files = vec![];
+            for file_id in root.files()? {
+                let versions = root.existing_file(&file_id)?.versions()?;
+                files.push((file_id, versions));
+            }
+            Ok(files)
+        })
+        .await?
+    }
+
+    /// Reads one of the descriptors of a file version stored by `source`.
+    pub async fn descriptor(
+        &self,
+        source: &auth::Source,
+        file_id: model::FileId,
+        version: model::Version,
+        index: u16,
+    ) -> Result<model::Descriptor> {
+        let root = self.layout(source)?.context("Unknown source")?;
+        tokio::task::spawn_blocking(move || {
+            root.existing_file(&file_id)?
+                .read_descriptor(version, index)
+        })
+        .await?
+    }
+
+    /// Reads a block stored by `source`.
+    pub async fn block(
+        &self,
+        source: &auth::Source,
+        file_id: model::FileId,
+        block_id: model::BlockId,
+    ) -> Result<model::Block> {
+        let root = self.layout(source)?.context("Unknown source")?;
+        tokio::task::spawn_blocking(move || root.existing_file(&file_id)?.read_block(&block_id))
+            .await?
+    }
+
+    /// Removes versions of files stored by `source`. Unknown files and versions are skipped.
+    /// Returns how many descriptors were removed.
+    pub async fn prune(
+        &self,
+        source: &auth::Source,
+        files: Vec<(model::FileId, Vec<model::Version>)>,
+    ) -> Result<u64> {
+        let root = self.layout(source)?;
+        tokio::task::spawn_blocking(move || {
+            let Some(root) = root else {
+                return Ok(0);
+            };
+            let mut removed = 0;
+            for (file_id, versions) in files {
+                let Ok(file) = root.existing_file(&file_id) else {
+                    continue;
+                };
+                for version in versions {
+                    removed += file.remove_version(version)? as u64;
+                }
+            }
+            Ok(removed)
+        })
+        .await?
+    }
+
+    /// Removes the blocks no descriptor references anymore, for every Source, unless they are
+    /// more recent than `grace`. Only reports what would be removed if `dry_run`.
+    pub async fn collect(
+        &self,
+        grace: std::time::Duration,
+        dry_run: bool,
+    ) -> Result<Vec<(String, gc::Report)>> {
+        let root = self.root.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut reports = vec![];
+            for entry in fs::read_dir(&root)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_dir() {
+                    continue;
+                }
+                let source = entry.file_name().to_string_lossy().to_string();
+                let report = gc::collect(&layout::Root::new(entry.path()), grace, dry_run)
+                    .with_context(|| format!("Failed to collect the blocks of {}", source))?;
+                reports.push((source, report));
+            }
+            reports.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Ok(reports)
+        })
+        .await?
+    }
+
+    /// Checks the data stored for every Source still decodes and matches its checksums.
+    pub async fn scrub(&self) -> Result<Vec<(String, scrub::Report)>> {
+        let root = self.root.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut reports = vec![];
+            for entry in fs::read_dir(&root)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_dir() {
+                    continue;
+                }
+                let source = entry.file_name().to_string_lossy().to_string();
+                let report = scrub::scrub(&layout::Root::new(entry.path()))
+                    .with_context(|| format!("Failed to scrub the data of {}", source))?;
+                reports.push((source, report));
+            }
+            reports.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Ok(reports)
+        })
+        .await?
+    }
+
+    /// Returns the layout of the data stored by `source`, creating it if needed.
+    fn create_layout(&self, source: &auth::Source) -> Result<layout::Root> {
+        let dir = self.source_dir(source)?;
+        if !dir.is_dir() {
+            fs::create_dir(&dir).context(format!("Failed to create {:?}", &dir))?;
+            layout::sync_dir(&self.root)?;
+        }
+        Ok(layout::Root::new(dir))
+    }
+
+    /// Returns the layout of the data stored by `source`, if it stored anything yet.
+    fn layout(&self, source: &auth::Source) -> Result<Option<layout::Root>> {
+        let dir = self.source_dir(source)?;
+        if !dir.is_dir() {
+            return Ok(None);
+        }
+        Ok(Some(layout::Root::new(dir)))
+    }
+
+    fn source_dir(&self, source: &auth::Source) -> Result<PathBuf> {
+        // The id comes from a validated certificate, but it still ends up in a
+        // path: only accept a conservative set of characters.
+        let id = source.id();
+        if id.is_empty()
+            || id.starts_with('.')
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+        {
+            return Err(anyhow!("Invalid source id {:?}", id));
+        }
+        Ok(self.root.join(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn write_layout() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let store = Store::new(tmpdir.path())?;
+        let source = auth::Source::new("123.src.piston.com");
+        let file_id = model::FileId::try_from([1u8; model::FILE_ID_LEN].as_slice())?;
+        let block_id = model::BlockId::try_from([2u8; model::BLOCK_ID_LEN].as_slice())?;
+        let block = model::Block {
+            verified: vec![3],
+            protected: vec![4].into(),
+        };
+
+        store
+            .put_block(&source, file_id.clone(), block_id.clone(), block)
+            .await?;
+        store
+            .put_descriptor(
+                &source,
+                file_id.clone(),
+                7,
+                0,
+                model::Descriptor {
+                    verified: vec![1],
+                    protected: vec![2],
+                },
+            )
+            .await?;
+
+        let dir = tmpdir
+            .path()
+            .join("123.src.piston.com")
+            .join(hex::encode(file_id.as_bytes()));
+        let block_name = format!("{}.blk", hex::encode(block_id.as_bytes()));
+        assert!(dir.join(&block_name).exists());
+        assert!(dir.join(format!("{}.sum", block_name)).exists());
+        assert!(dir.join("v7.dsc").exists());
+        assert!(dir.join("v7.dsc.sum").exists());
+        assert_eq!(store.list(&source).await?, vec![(file_id, vec![7])]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replace_descriptors() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let store = Store::new(tmpdir.path())?;
+        let source = auth::Source::new("123.src.piston.com");
+        let file_id = model::FileId::try_from([1u8; model::FILE_ID_LEN].as_slice())?;
+        let descriptor = |protected: u8| model::Descriptor {
+            verified: vec![1],
+            protected: vec![protected],
+        };
+        let dir = tmpdir
+            .path()
+            .join("123.src.piston.com")
+            .join(hex::encode(file_id.as_bytes()));
+        let entries = || -> Result<Vec<String>> {
+            let mut names = fs::read_dir(&dir)?
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                .collect::<Result<Vec<_>>>()?;
+            names.sort();
+            Ok(names)
+        };
+
+        store
+            .put_descriptor(&source, file_id.clone(), 7, 0, descriptor(2))
+            .await?;
+        store
+            .put_descriptor(&source, file_id.clone(), 7, 0, descriptor(3))
+            .await?;
+        let file = layout::Root::new(dir.parent().unwrap().to_owned()).existing_file(&file_id)?;
+        assert_eq!(
+            file.read_descriptor_checksum(7, 0)?,
+            Some(layout::checksum(&[1], &[3]))
+        );
+
+        // Replacing a descriptor is interrupted, as it can't replace a directory: its old
+        // checksum is gone rather than left with the wrong descriptor.
+        file.write_descriptor_checksum(8, 0, &layout::checksum(&[1], &[2]))?;
+        fs::create_dir_all(dir.join("v8.dsc").join("entry"))?;
+        assert!(store
+            .put_descriptor(&source, file_id.clone(), 8, 0, descriptor(4))
+            .await
+            .is_err());
+        assert_eq!(entries()?, vec!["v7.dsc", "v7.dsc.sum", "v8.dsc"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_layout() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let store = Store::new(tmpdir.path())?;
+        let source = auth::Source::new("123.src.piston.com");
+        let other = auth::Source::new("456.src.piston.com");
+
+        // Nothing is stored yet.
+        assert!(store.list(&source).await?.is_empty());
+
+        let file_id = model::FileId::try_from([1u8; model::FILE_ID_LEN].as_slice())?;
+        let block_id = model::BlockId::try_from([2u8; model::BLOCK_ID_LEN].as_slice())?;
+        let descriptor = model::Descriptor {
+            verified: vec![1],
+            protected: vec![2],
+        };
+        let block = model::Block {
+            verified: vec![3],
+            protected: vec![4].into(),
+        };
+        store
+            .put_descriptor(&source, file_id.clone(), 3, 0, descriptor.clone())
+            .await?;
+        store
+            .put_block(&source, file_id.clone(), block_id.clone(), block.clone())
+            .await?;
+
+        assert_eq!(store.list(&source).await?, vec![(file_id.clone(), vec![3])]);
+        assert_eq!(
+            store.descriptor(&source, file_id.clone(), 3, 0).await?,
+            descriptor
+        );
+        assert_eq!(
+            store
+                .block(&source, file_id.clone(), block_id.clone())
+                .await?,
+            block
+        );
+
+        // Other sources can't see the data.
+        assert!(store.list(&other).await?.is_empty());
+        assert!(store
+            .descriptor(&other, file_id.clone(), 3, 0)
+            .await
+            .is_err());
+        assert!(store.block(&other, file_id, block_id).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prune_versions() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let store = Store::new(tmpdir.path())?;
+        let source = auth::Source::new("123.src.piston.com");
+        let other = auth::Source::new("456.src.piston.com");
+        let file_id = model::FileId::try_from([1u8; model::FILE_ID_LEN].as_slice())?;
+        let unknown = model::FileId::try_from([2u8; model::FILE_ID_LEN].as_slice())?;
+        let descriptor = model::Descriptor {
+            verified: vec![1],
+            protected: vec![2],
+        };
+        for (version, index) in [(1, 0), (1, 1), (2, 0), (3, 0)] {
+            store
+                .put_descriptor(&source, file_id.clone(), version, index, descriptor.clone())
+                .await?;
+        }
+
+        // Other sources can't remove the data.
+        assert_eq!(
+            store
+                .prune(&other, vec![(file_id.clone(), vec![1, 2])])
+                .await?,
+            0
+        );
+        assert_eq!(
+            store
+                .prune(
+                    &source,
+                    vec![(file_id.clone(), vec![1, 2, 4]), (unknown, vec![0])]
+                )
+                .await?,
+            3
+        );
+        assert_eq!(store.list(&source).await?, vec![(file_id, vec![3])]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_source() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let store = Store::new(tmpdir.path())?;
+
+        let file_id = model::FileId::try_from([1u8; model::FILE_ID_LEN].as_slice())?;
+        for id in ["", "..", "../a.src.piston.com", "a/b.src.piston.com"] {
+            let descriptor = model::Descriptor {
+                verified: vec![1],
+                protected: vec![2],
+            };
+            assert!(store
+                .put_descriptor(&auth::Source::new(id), file_id.clone(), 0, 0, descriptor)
+                .await
+                .is_err());
+        }
+        Ok(())
+    }
+}
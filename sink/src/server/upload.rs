@@ -0,0 +1,148 @@
+//! Reassembly of the frames received through the Upload RPC.
+
+use anyhow::{bail, Result};
+use crypto::model;
+use sink_proto::{upload_request::Frame, BlockStart};
+use storage::layout;
+
+/// Largest block accepted by the Sink. This bounds the memory used by each upload.
+pub const MAX_BLOCK_SIZE: u64 = 1 << 28;
+
+/// Complete data received through an upload.
+#[derive(Debug, PartialEq)]
+pub enum Item {
+    Block(model::Block),
+    Descriptor(model::Descriptor),
+}
+
+/// Turns a sequence of frames back into blocks and descriptors.
+#[derive(Default)]
+pub struct Assembler {
+    /// Block being received, with its verified part and expected size.
+    pending: Option<(Vec<u8>, usize, Vec<u8>)>,
+}
+
+impl Assembler {
+    /// Handles the next frame of the upload. Returns an item once it is complete.
+    pub fn push(&mut self, frame: Frame) -> Result<Option<Item>> {
+        match frame {
+            Frame::Block(BlockStart {
+                verified,
+                size,
+                protected,
+            }) => {
+                if self.pending.is_some() {
+                    bail!("Previous block is incomplete");
+                }
+                if size > MAX_BLOCK_SIZE {
+                    bail!("Block is too large");
+                }
+                // The buffer grows as frames arrive: the announced size is not trusted.
+                self.pending = Some((verified, size as usize, Vec::new()));
+                self.append(protected)
+            }
+            Frame::Continuation(data) => {
+                if self.pending.is_none() {
+                    bail!("No block in progress");
+                }
+                self.append(data)
+            }
+            Frame::Descriptor(descriptor) => {
+                if self.pending.is_some() {
+                    bail!("Previous block is incomplete");
+                }
+                Ok(Some(Item::Descriptor(layout::descriptor_from_proto(
+                    descriptor,
+                ))))
+            }
+        }
+    }
+
+    /// Checks that the upload did not end in the middle of a block.
+    pub fn finish(self) -> Result<()> {
+        match self.pending {
+            Some(_) => bail!("Truncated block"),
+            None => Ok(()),
+        }
+    }
+
+    fn append(&mut self, data: Vec<u8>) -> Result<Option<Item>> {
+        let Some((_, size, protected)) = &mut self.pending else {
+            return Ok(None);
+        };
+        if protected.len() + data.len() > *size {
+            bail!("Block is larger than announced");
+        }
+        protected.extend_from_slice(&data);
+        if protected.len() < *size {
+            return Ok(None);
+        }
+        let (verified, _, protected) = self.pending.take().unwrap();
+        Ok(Some(Item::Block(model::Block {
+            verified,
+            protected: protected.into(),
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start(size: u64, protected: &[u8]) -> Frame {
+        Frame::Block(BlockStart {
+            verified: vec![1],
+            size,
+            protected: protected.to_vec(),
+        })
+    }
+
+    #[test]
+    fn reassemble_blocks() -> Result<()> {
+        let mut assembler = Assembler::default();
+        assert_eq!(assembler.push(start(5, b"ab"))?, None);
+        assert_eq!(assembler.push(Frame::Continuation(b"cd".to_vec()))?, None);
+        assert_eq!(
+            assembler.push(Frame::Continuation(b"e".to_vec()))?,
+            Some(Item::Block(model::Block {
+                verified: vec![1],
+                protected: b"abcde".to_vec().into(),
+            }))
+        );
+        // Empty blocks are complete right away.
+        assert!(assembler.push(start(0, b""))?.is_some());
+        assert!(assembler
+            .push(Frame::Descriptor(layout_proto::Descriptor::default()))?
+            .is_some());
+        assembler.finish()
+    }
+
+    #[test]
+    fn reject_invalid_sequences() {
+        let mut assembler = Assembler::default();
+        assert!(assembler.push(Frame::Continuation(b"a".to_vec())).is_err());
+
+        let mut assembler = Assembler::default();
+        assert!(assembler.push(start(MAX_BLOCK_SIZE + 1, b"")).is_err());
+
+        let mut assembler = Assembler::default();
+        assert!(assembler.push(start(2, b"abc")).is_err());
+
+        let mut assembler = Assembler::default();
+        assembler.push(start(2, b"a")).unwrap();
+        assert!(assembler.push(start(2, b"a")).is_err());
+
+        let mut assembler = Assembler::default();
+        assembler.push(start(2, b"a")).unwrap();
+        assert!(assembler.finish().is_err());
+    }
+
+    #[test]
+    fn grow_as_frames_arrive() -> Result<()> {
+        let mut assembler = Assembler::default();
+        assembler.push(start(MAX_BLOCK_SIZE, b"ab"))?;
+        let (_, _, protected) = assembler.pending.as_ref().unwrap();
+        assert!(protected.capacity() < 1 << 10);
+        Ok(())
+    }
+}
@@ -3,20 +3,180 @@ use std::sync::Arc;
 use anyhow::Context;
 use mockall::automock;
 use settings::connection;
-use sink_proto::{sink_client::SinkClient, StoreRequest};
-use tokio::sync::Mutex;
+use sink_proto::{
+    fetch_blocks_reply, sink_client::SinkClient, split_block, store_request, upload_request,
+    FetchBlocksReply, FetchBlocksRequest, FetchDescriptorRequest, ListRequest, PruneRequest,
+    StoreRequest, UploadRequest,
+};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_stream::wrappers::ReceiverStream;
+
+pub use sink_proto::StoredFile;
 use tonic::async_trait;
 use tonic::{
     transport::{Channel, ClientTlsConfig},
-    Request, Result,
+    Request, Result, Status,
 };
 
 /// A Sink is responsible for storing data from a Source.
 #[automock]
 #[async_trait]
 pub trait Sink {
-    // Send a chunk of bytes to the Sink for storage.
-    async fn store(&self, chunk: &[u8]) -> Result<()>;
+    /// Send an encrypted block to the Sink for storage.
+    async fn store_block(&self, block: layout_proto::Block) -> Result<()>;
+
+    /// Send an encrypted descriptor to the Sink for storage.
+    async fn store_descriptor(&self, descriptor: layout_proto::Descriptor) -> Result<()>;
+
+    /// Start streaming blocks and descriptors to the Sink for storage, with no limit on the
+    /// size of blocks.
+    async fn upload(&self) -> Result<Upload>;
+
+    /// List the files stored on the Sink by this Source, with their versions.
+    async fn list(&self) -> Result<Vec<StoredFile>>;
+
+    /// Fetch a descriptor stored on the Sink by this Source.
+    async fn fetch_descriptor(
+        &self,
+        file_id: &[u8],
+        version: u32,
+        index: u32,
+    ) -> Result<layout_proto::Descriptor>;
+
+    /// Fetch blocks stored on the Sink by this Source, in the requested order. All the blocks
+    /// are kept in memory, so callers should bound how many they request at once.
+    async fn fetch_blocks(
+        &self,
+        file_id: &[u8],
+        block_ids: &[Vec<u8>],
+    ) -> Result<Vec<layout_proto::Block>>;
+
+    /// Remove versions of files stored on the Sink by this Source. Returns how many descriptors
+    /// were removed.
+    async fn prune(&self, files: Vec<StoredFile>) -> Result<u64>;
+}
+
+/// Data sent to the Sink as part of an Upload.
+#[derive(Debug, PartialEq)]
+pub enum UploadItem {
+    Block(layout_proto::Block),
+    Descriptor(layout_proto::Descriptor),
+}
+
+/// An upload in progress. Items queued on `items` are sent in order; once `items` is dropped,
+/// `done` resolves when the Sink has durably stored all of them, or on the first failure.
+pub struct Upload {
+    pub items: mpsc::Sender<UploadItem>,
+    pub done: oneshot::Receiver<Result<()>>,
+}
+
+impl Upload {
+    /// Returns a new Upload, along with the ends used to serve it.
+    pub fn channel() -> (
+        Upload,
+        mpsc::Receiver<UploadItem>,
+        oneshot::Sender<Result<()>>,
+    ) {
+        let (items_tx, items_rx) = mpsc::channel(1);
+        let (done_tx, done_rx) = oneshot::channel();
+        (
+            Upload {
+                items: items_tx,
+                done: done_rx,
+            },
+            items_rx,
+            done_tx,
+        )
+    }
+}
+
+/// Splits an item into the frames sent over an upload stream.
+fn frames(item: UploadItem) -> Vec<UploadRequest> {
+    let block = match item {
+        UploadItem::Descriptor(descriptor) => {
+            return vec![UploadRequest {
+                frame: Some(upload_request::Frame::Descriptor(descriptor)),
+            }]
+        }
+        UploadItem::Block(block) => block,
+    };
+    let (start, continuations) = split_block(block);
+    let mut frames = vec![UploadRequest {
+        frame: Some(upload_request::Frame::Block(start)),
+    }];
+    frames.extend(continuations.into_iter().map(|part| UploadRequest {
+        frame: Some(upload_request::Frame::Continuation(part)),
+    }));
+    frames
+}
+
+/// Reassembles the blocks streamed in frames by FetchBlocks, checking they are the ones
+/// requested.
+struct Fetched<'a> {
+    block_ids: &'a [Vec<u8>],
+    blocks: Vec<layout_proto::Block>,
+    /// Block being received, with its announced size.
+    pending: Option<(layout_proto::Block, usize)>,
+}
+
+impl<'a> Fetched<'a> {
+    fn new(block_ids: &'a [Vec<u8>]) -> Self {
+        Fetched {
+            block_ids,
+            blocks: Vec::with_capacity(block_ids.len()),
+            pending: None,
+        }
+    }
+
+    /// Handles the next frame of the stream.
+    fn push(&mut self, reply: FetchBlocksReply) -> Result<()> {
+        match reply.frame {
+            Some(fetch_blocks_reply::Frame::Block(start)) => {
+                if self.pending.is_some() {
+                    return Err(Status::data_loss("Truncated block"));
+                }
+                if self.block_ids.get(self.blocks.len()) != Some(&reply.block_id) {
+                    return Err(Status::data_loss("Unexpected block"));
+                }
+                let block = layout_proto::Block {
+                    verified: start.verified,
+                    protected: vec![],
+                };
+                self.pending = Some((block, start.size as usize));
+                self.append(start.protected)
+            }
+            Some(fetch_blocks_reply::Frame::Continuation(data)) => {
+                if self.pending.is_none() {
+                    return Err(Status::data_loss("No block in progress"));
+                }
+                self.append(data)
+            }
+            None => Err(Status::data_loss("Empty frame")),
+        }
+    }
+
+    fn append(&mut self, data: Vec<u8>) -> Result<()> {
+        let Some((block, size)) = &mut self.pending else {
+            return Ok(());
+        };
+        if block.protected.len() + data.len() > *size {
+            return Err(Status::data_loss("Block is larger than announced"));
+        }
+        block.protected.extend_from_slice(&data);
+        if block.protected.len() == *size {
+            let (block, _) = self.pending.take().unwrap();
+            self.blocks.push(block);
+        }
+        Ok(())
+    }
+
+    /// Returns the blocks, once they were all received.
+    fn finish(self) -> Result<Vec<layout_proto::Block>> {
+        if self.pending.is_some() || self.blocks.len() != self.block_ids.len() {
+            return Err(Status::data_loss("Missing blocks"));
+        }
+        Ok(self.blocks)
+    }
 }
 
 /// A SinkBuilder is responsible for creating a Sink.
@@ -59,21 +219,165 @@ impl SinkBuilder<SinkImpl> for SinkBuilderImpl {
             .await
             .context("failed to connect")?;
 
-        Ok(SinkImpl {
+        Ok(SinkImpl::new(channel))
+    }
+}
+
+impl SinkImpl {
+    /// Returns a Sink using an established channel.
+    pub fn new(channel: Channel) -> Self {
+        SinkImpl {
             stub: Arc::new(Mutex::new(SinkClient::new(channel))),
-        })
+        }
+    }
+
+    async fn store(&self, data: store_request::Data) -> Result<()> {
+        let mut stub = self.stub.lock().await;
+        stub.store(Request::new(StoreRequest { data: Some(data) }))
+            .await?;
+
+        Ok(())
     }
 }
 
 #[async_trait]
 impl Sink for SinkImpl {
-    async fn store(&self, chunk: &[u8]) -> Result<()> {
+    async fn store_block(&self, block: layout_proto::Block) -> Result<()> {
+        self.store(store_request::Data::Block(block)).await
+    }
+
+    async fn store_descriptor(&self, descriptor: layout_proto::Descriptor) -> Result<()> {
+        self.store(store_request::Data::Descriptor(descriptor))
+            .await
+    }
+
+    async fn upload(&self) -> Result<Upload> {
+        // The upload may last a while: use a separate handle on the channel so that other calls
+        // can proceed meanwhile.
+        let mut stub = self.stub.lock().await.clone();
+        let (upload, mut items, done) = Upload::channel();
+        let (frames_tx, frames_rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            while let Some(item) = items.recv().await {
+                for frame in frames(item) {
+                    // The stream failed, the error is reported through `done`.
+                    if frames_tx.send(frame).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        tokio::spawn(async move {
+            let result = stub.upload(ReceiverStream::new(frames_rx)).await;
+            // The caller may not be waiting for the result anymore.
+            let _ = done.send(result.map(|_| ()));
+        });
+        Ok(upload)
+    }
+
+    async fn list(&self) -> Result<Vec<StoredFile>> {
         let mut stub = self.stub.lock().await;
-        stub.store(Request::new(StoreRequest {
-            data: chunk.to_owned(),
-        }))
-        .await?;
+        let reply = stub.list(Request::new(ListRequest {})).await?;
 
-        Ok(())
+        Ok(reply.into_inner().file)
+    }
+
+    async fn fetch_descriptor(
+        &self,
+        file_id: &[u8],
+        version: u32,
+        index: u32,
+    ) -> Result<layout_proto::Descriptor> {
+        let mut stub = self.stub.lock().await;
+        let reply = stub
+            .fetch_descriptor(Request::new(FetchDescriptorRequest {
+                file_id: file_id.to_owned(),
+                version,
+                index,
+            }))
+            .await?;
+
+        reply
+            .into_inner()
+            .descriptor
+            .ok_or_else(|| Status::data_loss("Missing descriptor"))
+    }
+
+    async fn fetch_blocks(
+        &self,
+        file_id: &[u8],
+        block_ids: &[Vec<u8>],
+    ) -> Result<Vec<layout_proto::Block>> {
+        let mut stub = self.stub.lock().await;
+        let mut stream = stub
+            .fetch_blocks(Request::new(FetchBlocksRequest {
+                file_id: file_id.to_owned(),
+                block_id: block_ids.to_owned(),
+            }))
+            .await?
+            .into_inner();
+
+        let mut fetched = Fetched::new(block_ids);
+        while let Some(reply) = stream.message().await? {
+            fetched.push(reply)?;
+        }
+        fetched.finish()
+    }
+
+    async fn prune(&self, files: Vec<StoredFile>) -> Result<u64> {
+        let mut stub = self.stub.lock().await;
+        let reply = stub
+            .prune(Request::new(PruneRequest { file: files }))
+            .await?;
+
+        Ok(reply.into_inner().descriptors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sink_proto::{BlockStart, FRAME_SIZE};
+
+    #[test]
+    fn split_blocks() {
+        let descriptor = layout_proto::Descriptor {
+            protected: vec![1; 2 * FRAME_SIZE],
+            verified: vec![2],
+        };
+        assert_eq!(
+            frames(UploadItem::Descriptor(descriptor.clone())),
+            vec![UploadRequest {
+                frame: Some(upload_request::Frame::Descriptor(descriptor)),
+            }]
+        );
+
+        let block = layout_proto::Block {
+            protected: vec![3; 2 * FRAME_SIZE + 1],
+            verified: vec![4],
+        };
+        let frames = frames(UploadItem::Block(block));
+        assert_eq!(frames.len(), 3);
+        assert_eq!(
+            frames[0].frame,
+            Some(upload_request::Frame::Block(BlockStart {
+                verified: vec![4],
+                size: 2 * FRAME_SIZE as u64 + 1,
+                protected: vec![3; FRAME_SIZE],
+            }))
+        );
+        assert_eq!(
+            frames[2].frame,
+            Some(upload_request::Frame::Continuation(vec![3]))
+        );
+    }
+
+    #[test]
+    fn empty_block() {
+        let block = layout_proto::Block {
+            protected: vec![],
+            verified: vec![4],
+        };
+        assert_eq!(frames(UploadItem::Block(block)).len(), 1);
     }
 }
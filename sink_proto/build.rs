@@ -1,5 +1,8 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("cargo:rerun-if-changed=sink.proto");
-    tonic_build::compile_protos("sink.proto")?;
+    println!("cargo:rerun-if-changed=../layout_proto/layout.proto");
+    tonic_build::configure()
+        .extern_path(".piston.layout", "::layout_proto")
+        .compile_protos(&["sink.proto"], &[".", "../layout_proto"])?;
     Ok(())
 }
@@ -1 +1,16 @@
 tonic::include_proto!("piston.sink"); // The string specified here must match the proto package name
+
+/// Size of the frames that blocks are split into, well below tonic's message size limit.
+pub const FRAME_SIZE: usize = 1 << 20;
+
+/// Splits a block into the frame starting it, and the continuation frames carrying the rest of
+/// its protected part.
+pub fn split_block(block: layout_proto::Block) -> (BlockStart, Vec<Vec<u8>>) {
+    let mut parts = block.protected.chunks(FRAME_SIZE);
+    let start = BlockStart {
+        verified: block.verified,
+        size: block.protected.len() as u64,
+        protected: parts.next().unwrap_or_default().to_vec(),
+    };
+    (start, parts.map(|part| part.to_vec()).collect())
+}
@@ -1,6 +1,8 @@
 //! Load and manipulate settings for a Sink.
 use anyhow::Context;
 use settings::{connection, process, server};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 use thiserror::Error;
@@ -16,6 +18,13 @@ pub struct Settings {
     connection: connection::Settings,
     process: process::Settings,
     server: server::Settings,
+    storage: Storage,
+}
+
+/// Storage-related settings.
+pub struct Storage {
+    root: PathBuf,
+    gc_grace: Duration,
 }
 
 impl Settings {
@@ -34,6 +43,23 @@ impl Settings {
     pub fn process(&self) -> &process::Settings {
         &self.process
     }
+
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+}
+
+impl Storage {
+    /// Returns the directory under which the data of all Sources is stored.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns how long unreferenced blocks are kept, as the descriptors referencing them may
+    /// still be on their way.
+    pub fn gc_grace(&self) -> Duration {
+        self.gc_grace
+    }
 }
 
 /// All the customizable options for creating a fresh config.
@@ -65,7 +91,7 @@ impl Builder {
         self
     }
 
-    pub fn path(anchor: &settings::Anchor) -> std::path::PathBuf {
+    pub fn path(anchor: &settings::Anchor) -> PathBuf {
         settings::path(NAME, anchor)
     }
 
@@ -89,6 +115,7 @@ impl Builder {
             server: server::wire::Settings {
                 address: self.address,
             },
+            storage: wire::Storage::default(),
         };
         settings::save(&settings, NAME, anchor)?;
         Ok(())
@@ -125,6 +152,31 @@ mod wire {
         pub connection: connection::wire::Settings,
         pub process: process::wire::Settings,
         pub server: server::wire::Settings,
+        /// Missing from configs written before data was stored on disk.
+        #[serde(default)]
+        pub storage: Storage,
+    }
+
+    /// Settings related to data storage.
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Storage {
+        pub root: settings::ConfigPath,
+        /// Duration like "1d" for which unreferenced blocks are kept.
+        #[serde(default = "default_gc_grace")]
+        pub gc_grace: String,
+    }
+
+    impl Default for Storage {
+        fn default() -> Self {
+            Storage {
+                root: "storage".into(),
+                gc_grace: default_gc_grace(),
+            }
+        }
+    }
+
+    pub fn default_gc_grace() -> String {
+        "1d".into()
     }
 }
 
@@ -137,6 +189,18 @@ impl settings::Anchored for Settings {
             connection: connection::Settings::anchor(&wire.connection, anchor)?,
             process: process::Settings::anchor(&wire.process, anchor)?,
             server: server::Settings::anchor(&wire.server, anchor)?,
+            storage: Storage::anchor(&wire.storage, anchor)?,
+        })
+    }
+}
+
+impl settings::Anchored for Storage {
+    type Wire = wire::Storage;
+
+    fn anchor(wire: &Self::Wire, anchor: &settings::Anchor) -> anyhow::Result<Self> {
+        Ok(Storage {
+            root: wire.root.path(anchor),
+            gc_grace: humantime::parse_duration(&wire.gc_grace).context("Invalid gc_grace")?,
         })
     }
 }
@@ -161,7 +225,7 @@ mod test {
     fn roundtrip() -> anyhow::Result<()> {
         let tmpdir = TempDir::new()?;
         let cfg = tmpdir.path().join("cfg");
-        let anchor = settings::get_anchor(Some(cfg))?;
+        let anchor = settings::get_anchor(Some(cfg.clone()))?;
         Builder::default()
             .address("127.0.0.1:1234")
             .certificate("")
@@ -172,7 +236,34 @@ mod test {
         assert_eq!(settings.server().address().port(), 1234);
         assert_eq!(settings.broker().name(), constants::BROKER_NAME);
         assert_eq!(settings.broker().address(), constants::BROKER_ADDRESS);
+        assert_eq!(settings.storage().root(), cfg.join("storage"));
+        assert_eq!(settings.storage().gc_grace(), Duration::from_secs(86400));
         // TODO: validate certificates
         Ok(())
     }
+
+    #[test]
+    fn load_without_storage() -> anyhow::Result<()> {
+        let tmpdir = TempDir::new()?;
+        let cfg = tmpdir.path().join("cfg");
+        let anchor = settings::get_anchor(Some(cfg.clone()))?;
+        Builder::default()
+            .address("127.0.0.1:1234")
+            .certificate("")
+            .private_key("")
+            .save(&anchor)?;
+        // As written before the storage settings existed.
+        let path = Builder::path(&anchor);
+        let content = std::fs::read_to_string(&path)?;
+        let start = content.find("[storage]").unwrap();
+        let end = content[start + 1..]
+            .find("\n[")
+            .map_or(content.len(), |end| start + 2 + end);
+        std::fs::write(&path, format!("{}{}", &content[..start], &content[end..]))?;
+        assert!(!std::fs::read_to_string(&path)?.contains("storage"));
+
+        let settings = load_impl(&anchor)?;
+        assert_eq!(settings.storage().root(), cfg.join("storage"));
+        Ok(())
+    }
 }
@@ -0,0 +1,106 @@
+//! Loading and saving of the keyfile, which may be sealed with a passphrase so that a copy of the
+//! disk does not expose the keys.
+
+use anyhow::{anyhow, Context, Result};
+use crypto::key::{self, Keyring};
+use source_settings::Passphrase;
+use std::path::Path;
+
+/// How a keyfile is sealed.
+#[derive(Debug, PartialEq)]
+pub struct Seal {
+    pub passphrase: String,
+    pub kdf: key::Kdf,
+}
+
+/// Loads the keys from `path`, unlocking them with the passphrase from `passphrase` if sealed.
+/// Returns how the keyfile is sealed too, to seal it the same way again when changing it.
+pub fn load(path: &Path, passphrase: &Passphrase) -> Result<(Keyring, Option<Seal>)> {
+    let Some(kdf) = key::sealed_kdf(path)? else {
+        return Ok((Keyring::from_file(path)?, None));
+    };
+    let passphrase = read_passphrase(passphrase, "Passphrase of the Source key: ")?;
+    let keyring = Keyring::from_sealed_file(path, &passphrase)?;
+    Ok((keyring, Some(Seal { passphrase, kdf })))
+}
+
+/// Saves the keys to `path`, sealed as described by `seal` if any.
+pub fn save(path: &Path, keyring: &Keyring, seal: Option<&Seal>) -> Result<()> {
+    match seal {
+        Some(seal) => keyring.to_sealed_file(path, &seal.passphrase, seal.kdf.clone()),
+        None => keyring.to_file(path),
+    }
+}
+
+/// Reads a passphrase from its configured source, showing `prompt` if asking on the terminal.
+pub fn read_passphrase(passphrase: &Passphrase, prompt: &str) -> Result<String> {
+    let passphrase = match passphrase {
+        Passphrase::Prompt => rpassword::prompt_password(prompt)?,
+        Passphrase::Env(name) => {
+            std::env::var(name).with_context(|| format!("Failed to read ${}", name))?
+        }
+        Passphrase::Fd(fd) => {
+            let data = std::fs::read_to_string(format!("/dev/fd/{}", fd))
+                .with_context(|| format!("Failed to read file descriptor {}", fd))?;
+            data.lines().next().unwrap_or_default().to_string()
+        }
+    };
+    if passphrase.is_empty() {
+        return Err(anyhow!("Empty passphrase"));
+    }
+    Ok(passphrase)
+}
+
+/// Reads a new passphrase, twice when asking on the terminal to catch typos.
+pub fn new_passphrase(passphrase: &Passphrase) -> Result<String> {
+    let first = read_passphrase(passphrase, "New passphrase of the Source key: ")?;
+    if *passphrase == Passphrase::Prompt
+        && read_passphrase(passphrase, "Repeat the passphrase: ")? != first
+    {
+        return Err(anyhow!("Passphrases do not match"));
+    }
+    Ok(first)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passphrase_from_env() -> Result<()> {
+        std::env::set_var("PISTON_TEST_PASSPHRASE", "secret");
+        let passphrase = Passphrase::Env("PISTON_TEST_PASSPHRASE".into());
+        assert_eq!(read_passphrase(&passphrase, "")?, "secret");
+        assert!(read_passphrase(&Passphrase::Env("PISTON_TEST_UNSET".into()), "").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sealed_roundtrip() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("keyfile");
+        let rnd = crypto::Random::new();
+        save(&path, &Keyring::from(rnd.generate_root_key()?), None)?;
+        let (keyring, seal) = load(&path, &Passphrase::Prompt)?;
+        assert_eq!(seal, None);
+
+        // The KDF is kept when sealing again.
+        std::env::set_var("PISTON_TEST_SEALED", "secret");
+        let source = Passphrase::Env("PISTON_TEST_SEALED".into());
+        let seal = Seal {
+            passphrase: "secret".into(),
+            kdf: key::Kdf::Scrypt {
+                log_n: 4,
+                r: 8,
+                p: 1,
+            },
+        };
+        save(&path, &keyring, Some(&seal))?;
+        let (loaded, loaded_seal) = load(&path, &source)?;
+        assert_eq!(loaded.latest().key(), keyring.latest().key());
+        assert_eq!(loaded_seal.as_ref(), Some(&seal));
+        save(&path, &loaded, loaded_seal.as_ref())?;
+        assert_eq!(key::sealed_kdf(&path)?, Some(seal.kdf));
+        Ok(())
+    }
+}
@@ -1,32 +1,401 @@
 use ::settings::process;
 use anyhow::{Context, Result};
-use std::{path::Path, sync::Arc};
+use clap::{Parser, Subcommand};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+mod keyfile;
+mod prune;
+mod restore;
+mod rotate;
 mod server;
+mod snapshot;
 mod state;
+mod verify;
+
+#[derive(Parser, Debug)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Back up the configured roots to a Sink. This is the default. Keeps running until SIGTERM
+    /// or SIGINT when a schedule is configured.
+    Backup,
+    /// Restore files from encrypted data, read from a Sink or from a local copy.
+    Restore {
+        /// Root directory of a local copy of the encrypted data, in the canonical layout. The
+        /// data is fetched from a Sink if not provided.
+        #[arg(long)]
+        from: Option<PathBuf>,
+        /// Directory under which files are restored.
+        #[arg(long)]
+        to: PathBuf,
+        /// Restore the owner and group of files. Defaults to whether running as root.
+        #[arg(long)]
+        chown: Option<bool>,
+        /// Restore the files as they were in this snapshot, instead of their latest version.
+        #[arg(long)]
+        snapshot: Option<u32>,
+        /// Original paths of the files or directories to restore. Everything is restored if
+        /// none is provided.
+        paths: Vec<PathBuf>,
+    },
+    /// List the snapshots taken at the end of each complete pass.
+    Snapshots {
+        /// Root directory of a local copy of the encrypted data, in the canonical layout. The
+        /// data is fetched from a Sink if not provided.
+        #[arg(long)]
+        from: Option<PathBuf>,
+    },
+    /// List the files added, removed and changed between two snapshots.
+    Diff {
+        /// Root directory of a local copy of the encrypted data, in the canonical layout. The
+        /// data is fetched from a Sink if not provided.
+        #[arg(long)]
+        from: Option<PathBuf>,
+        /// Earlier snapshot.
+        before: u32,
+        /// Later snapshot.
+        after: u32,
+    },
+    /// Drop the snapshots the retention policy does not keep, and the file versions only they
+    /// reference, from the Sink.
+    Prune {
+        /// Only report what would be dropped.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Add a new root key, which new data is encrypted under once the backup restarts. Older keys
+    /// are kept to decrypt older data.
+    RotateKey,
+    /// Seal the keyfile with a new passphrase, read from the configured source. The passphrase is
+    /// then needed to start the Source. Also changes the passphrase of a sealed keyfile.
+    SealKey {
+        /// Function deriving the key from the passphrase.
+        #[arg(long, value_enum, default_value_t = Kdf::Argon2id)]
+        kdf: Kdf,
+    },
+    /// Store the keyfile without a passphrase again.
+    UnsealKey,
+    /// Print the root keys as a code to write down or hand to escrow, from which `import-key`
+    /// recovers the keyfile. Keep it as safe as the keyfile.
+    ExportKey {
+        /// Split the keys into this many codes with Shamir's secret sharing instead.
+        #[arg(long, requires = "threshold")]
+        shares: Option<u8>,
+        /// Number of the shares needed to recover the keys.
+        #[arg(long, requires = "shares")]
+        threshold: Option<u8>,
+    },
+    /// Recreate a missing keyfile from the code printed by `export-key`, or from enough shares.
+    /// The codes are read from the standard input, one per line, if not provided.
+    ImportKey {
+        /// Seal the keyfile with a new passphrase, read from the configured source.
+        #[arg(long)]
+        seal: bool,
+        codes: Vec<String>,
+    },
+    /// Encrypt the descriptors stored on the Sink again under the latest root key. Blocks remain
+    /// encrypted under the key they were sent with.
+    Reencrypt,
+    /// Decrypt and check everything stored for this Source, and print a report as JSON. Fails if
+    /// anything could not be verified.
+    Verify {
+        /// Root directory of a local copy of the encrypted data, in the canonical layout. The
+        /// data is fetched from a Sink if not provided.
+        #[arg(long)]
+        from: Option<PathBuf>,
+        /// Fraction of the blocks to decrypt, between 0 and 1. Every block is decrypted by
+        /// default.
+        #[arg(long)]
+        sample: Option<f64>,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Debug)]
+enum Kdf {
+    Argon2id,
+    Scrypt,
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    nix::unistd::geteuid().is_root()
+}
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
 
 // Creates a new Source key and saves it to the specified path.
-fn new_source(path: &Path, rnd: &crypto::Random) -> anyhow::Result<crypto::key::Durable> {
-    let durable = rnd.generate_root_key()?;
-    durable.to_file(path)?;
-    Ok(durable)
+fn new_source(path: &Path, rnd: &crypto::Random) -> anyhow::Result<crypto::key::Keyring> {
+    let keyring = crypto::key::Keyring::from(rnd.generate_root_key()?);
+    keyring.to_file(path)?;
+    Ok(keyring)
 }
 
 /// Runs a Source binary, which is in charge of a user's data source.
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = Args::parse();
     let settings = source_settings::load().context("Failed to load the Source settings")?;
     process::init(settings.process());
 
+    match args.command.unwrap_or(Command::Backup) {
+        Command::Backup => backup(&settings).await,
+        Command::Restore {
+            from,
+            to,
+            chown,
+            snapshot,
+            paths,
+        } => {
+            let keys = load_keys(&settings)?;
+            let selection = if paths.is_empty() {
+                restore::Selection::All
+            } else {
+                restore::Selection::Under(paths)
+            };
+            let options = restore::Options {
+                ownership: chown.unwrap_or_else(is_root),
+                snapshot,
+            };
+            let repository = repository(&settings, from).await?;
+            let report = restore::restore(repository.as_ref(), &keys, &selection, &to, &options)
+                .await
+                .context("Failed to restore")?;
+            tracing::info!(
+                "restored {} files, {} failures",
+                report.restored.len(),
+                report.failed
+            );
+            if report.failed > 0 {
+                anyhow::bail!("{} files could not be restored", report.failed);
+            }
+            Ok(())
+        }
+        Command::Snapshots { from } => {
+            let keys = load_keys(&settings)?;
+            let repository = repository(&settings, from).await?;
+            for version in snapshot::list(repository.as_ref()).await? {
+                let manifest = snapshot::read(repository.as_ref(), &keys, version).await?;
+                let time = std::time::UNIX_EPOCH
+                    + std::time::Duration::from_nanos(manifest.time.max(0) as u64);
+                println!(
+                    "{}\t{}\t{}\t{} files",
+                    version,
+                    humantime::format_rfc3339_seconds(time),
+                    manifest.hostname,
+                    manifest.files.len()
+                );
+            }
+            Ok(())
+        }
+        Command::Diff {
+            from,
+            before,
+            after,
+        } => {
+            let keys = load_keys(&settings)?;
+            let repository = repository(&settings, from).await?;
+            let diff = snapshot::diff(repository.as_ref(), &keys, before, after).await?;
+            for path in &diff.added {
+                println!("+ {}", path);
+            }
+            for path in &diff.removed {
+                println!("- {}", path);
+            }
+            for path in &diff.changed {
+                println!("M {}", path);
+            }
+            Ok(())
+        }
+        Command::Prune { dry_run } => {
+            let keys = load_keys(&settings)?;
+            let store =
+                state::Store::new(settings.backup().db(), Arc::new(crypto::Random::new())).await?;
+            let connection = settings.connection().info();
+            let broker = broker_client::new(connection, settings.broker()).await?;
+            let peer = server::peer::new(broker, connection.clone());
+            let result =
+                prune::prune(&peer, &store, &keys, settings.backup().retention(), dry_run).await;
+            store.shutdown().await?;
+            let plan = result.context("Failed to prune")?;
+            let versions: usize = plan.versions.iter().map(|(_, v)| v.len()).sum();
+            println!(
+                "{} snapshots {:?} and {} file versions",
+                if dry_run { "would drop" } else { "dropped" },
+                plan.snapshots,
+                versions
+            );
+            Ok(())
+        }
+        Command::RotateKey => {
+            let backup = settings.backup();
+            let version = rotate::rotate(
+                backup.keyfile(),
+                backup.passphrase(),
+                &crypto::Random::new(),
+            )
+            .context("Failed to rotate the Source key")?;
+            println!("added key version {}", version);
+            Ok(())
+        }
+        Command::SealKey { kdf } => {
+            let backup = settings.backup();
+            let (keyring, _) = keyfile::load(backup.keyfile(), backup.passphrase())
+                .context("Failed to load the Source key")?;
+            let passphrase = keyfile::new_passphrase(backup.passphrase())?;
+            let kdf = match kdf {
+                Kdf::Argon2id => crypto::key::Kdf::argon2id(),
+                Kdf::Scrypt => crypto::key::Kdf::scrypt(),
+            };
+            keyring
+                .to_sealed_file(backup.keyfile(), &passphrase, kdf)
+                .context("Failed to seal the Source key")
+        }
+        Command::UnsealKey => {
+            let backup = settings.backup();
+            let (keyring, _) = keyfile::load(backup.keyfile(), backup.passphrase())
+                .context("Failed to load the Source key")?;
+            keyring
+                .to_file(backup.keyfile())
+                .context("Failed to save the Source key")
+        }
+        Command::ExportKey { shares, threshold } => {
+            let backup = settings.backup();
+            let (keyring, _) = keyfile::load(backup.keyfile(), backup.passphrase())
+                .context("Failed to load the Source key")?;
+            match (shares, threshold) {
+                (Some(shares), Some(threshold)) => {
+                    for (n, code) in crypto::escrow::split(&keyring, threshold, shares)?
+                        .iter()
+                        .enumerate()
+                    {
+                        println!("share {}/{}: {}", n + 1, shares, code);
+                    }
+                }
+                _ => println!("{}", crypto::escrow::export(&keyring)),
+            }
+            Ok(())
+        }
+        Command::ImportKey { seal, codes } => {
+            let backup = settings.backup();
+            if backup.keyfile().exists() {
+                anyhow::bail!("{:?} already exists", backup.keyfile());
+            }
+            let codes = if codes.is_empty() {
+                std::io::stdin()
+                    .lines()
+                    .map(|line| Ok(line?.trim().to_string()))
+                    .filter(|line| !matches!(line, Ok(line) if line.is_empty()))
+                    .collect::<Result<_>>()?
+            } else {
+                codes
+            };
+            // Accept the lines printed for shares as they are.
+            let codes: Vec<&str> = codes
+                .iter()
+                .map(|code| code.rsplit(':').next().unwrap_or_default())
+                .collect();
+            let keyring = crypto::escrow::import(&codes).context("Failed to recover the keys")?;
+            let seal = if seal {
+                Some(keyfile::Seal {
+                    passphrase: keyfile::new_passphrase(backup.passphrase())?,
+                    kdf: crypto::key::Kdf::argon2id(),
+                })
+            } else {
+                None
+            };
+            keyfile::save(backup.keyfile(), &keyring, seal.as_ref())
+                .context("Failed to save the Source key")?;
+            let versions: Vec<u16> = keyring.keys().iter().map(|key| key.version()).collect();
+            println!("recovered key versions {:?}", versions);
+            Ok(())
+        }
+        Command::Reencrypt => {
+            let keys = load_keys(&settings)?;
+            let connection = settings.connection().info();
+            let broker = broker_client::new(connection, settings.broker()).await?;
+            let peer = server::peer::new(broker, connection.clone());
+            let report = rotate::reencrypt(&peer, &peer, &keys)
+                .await
+                .context("Failed to encrypt the descriptors again")?;
+            println!(
+                "encrypted {} of {} descriptors again under key version {}",
+                report.reencrypted,
+                report.descriptors,
+                keys.key_version()
+            );
+            Ok(())
+        }
+        Command::Verify { from, sample } => {
+            let keys = load_keys(&settings)?;
+            let rnd = Arc::new(crypto::Random::new());
+            // Digests are only known locally, the blocks are checked without them otherwise.
+            let store = state::Store::new(settings.backup().db(), rnd.clone()).await?;
+            let digests = store.digests().await;
+            store.shutdown().await?;
+            let repository = repository(&settings, from).await?;
+            let report = verify::verify(
+                repository.as_ref(),
+                &keys,
+                rnd.as_ref(),
+                &digests?,
+                &verify::Options { sample },
+            )
+            .await
+            .context("Failed to verify")?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if !report.failures.is_empty() {
+                anyhow::bail!("{} failures", report.failures.len());
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Loads the existing Source key. A key is never generated outside of backups, as it would not
+/// decrypt anything.
+fn load_keys(settings: &source_settings::Settings) -> Result<crypto::Keys> {
+    let backup = settings.backup();
+    let (keyring, _) = keyfile::load(backup.keyfile(), backup.passphrase())
+        .context("Failed to load the Source key")?;
+    Ok(crypto::Keys::new(keyring))
+}
+
+/// Returns the encrypted data under `from`, or on a Sink if not provided.
+async fn repository(
+    settings: &source_settings::Settings,
+    from: Option<PathBuf>,
+) -> Result<Box<dyn restore::Repository + Send + Sync>> {
+    Ok(match from {
+        Some(from) => Box::new(storage::layout::Root::new(from)),
+        None => {
+            let connection = settings.connection().info();
+            let broker = broker_client::new(connection, settings.broker()).await?;
+            Box::new(server::peer::new(broker, connection.clone()))
+        }
+    })
+}
+
+async fn backup(settings: &source_settings::Settings) -> Result<()> {
     let rnd = Arc::new(crypto::Random::new());
-    // TODO: we should be very conservative about regenerating the key, in case something requires
-    // manual intervention.
-    let durable = crypto::key::Durable::from_file(settings.backup().keyfile())
-        .or_else(|_| new_source(settings.backup().keyfile(), rnd.as_ref()))?;
-    let source_key = crypto::Keys::new(durable);
+    // A key is only generated for a new Source: any other failure to load it requires manual
+    // intervention, as a new key would not decrypt what is stored already.
+    let source_key = match settings.backup().keyfile().exists() {
+        true => load_keys(settings)?,
+        false => crypto::Keys::new(new_source(settings.backup().keyfile(), rnd.as_ref())?),
+    };
 
     let server = server::builder()
-        .settings(&settings)
+        .settings(settings)
         .crypto(rnd, source_key)
         .build()
         .await
@@ -0,0 +1,170 @@
+//! Pruning drops the snapshots the retention policy does not keep, along with the file versions
+//! no kept snapshot needs anymore. Blocks stay on the Sink until it collects them.
+
+use crate::{
+    restore::Repository,
+    server::peer::{FileList, Peer},
+    snapshot,
+    state::Store,
+};
+use anyhow::Result;
+use crypto::model::{self, FileId, Manifest, Version};
+use source_settings::Retention;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+
+/// What a prune drops.
+#[derive(Debug, Default, PartialEq)]
+pub struct Plan {
+    pub snapshots: Vec<Version>,
+    /// Versions of the files other than the snapshots, grouped by file.
+    pub versions: FileList,
+}
+
+/// Computes what to drop from the `stored` versions, given the manifest of every stored snapshot
+/// in increasing order.
+///
+/// A version is only dropped if no kept snapshot references it, and a snapshot references the
+/// same or a later version of its file. Versions stored since the latest snapshot of their file,
+/// while watching for instance, are kept until a snapshot supersedes them.
+pub fn plan(retention: &Retention, manifests: &[(Version, Manifest)], stored: &FileList) -> Plan {
+    let times: Vec<SystemTime> = manifests
+        .iter()
+        .map(|(_, manifest)| {
+            SystemTime::UNIX_EPOCH + Duration::from_nanos(manifest.time.max(0) as u64)
+        })
+        .collect();
+    let mut plan = Plan::default();
+    let mut referenced = HashSet::new();
+    let mut covered: HashMap<&FileId, Version> = HashMap::new();
+    for (keep, (version, manifest)) in retention.keep(&times).into_iter().zip(manifests) {
+        for (file_id, version) in &manifest.files {
+            let latest = covered.entry(file_id).or_default();
+            *latest = (*latest).max(*version);
+            if keep {
+                referenced.insert((file_id, *version));
+            }
+        }
+        if !keep {
+            plan.snapshots.push(*version);
+        }
+    }
+    for (file_id, versions) in stored {
+        if *file_id == model::SNAPSHOTS {
+            continue;
+        }
+        let Some(latest) = covered.get(file_id) else {
+            continue;
+        };
+        let dropped: Vec<Version> = versions
+            .iter()
+            .filter(|version| **version <= *latest && !referenced.contains(&(file_id, **version)))
+            .copied()
+            .collect();
+        if !dropped.is_empty() {
+            plan.versions.push((file_id.clone(), dropped));
+        }
+    }
+    plan
+}
+
+/// Drops what the retention policy does not keep, from the local state then from the Sink, so
+/// that a failure never leaves the Source reusing blocks the Sink may collect. Only computes the
+/// plan if `dry_run`.
+pub async fn prune<P: Peer + Repository + Sync>(
+    peer: &P,
+    store: &Store,
+    keys: &crypto::Keys,
+    retention: &Retention,
+    dry_run: bool,
+) -> Result<Plan> {
+    if retention.is_empty() {
+        tracing::info!("no retention policy, keeping everything");
+        return Ok(Plan::default());
+    }
+    let stored = peer.list().await?;
+    let mut manifests = vec![];
+    for version in snapshot::list(peer).await? {
+        manifests.push((version, snapshot::read(peer, keys, version).await?));
+    }
+    let plan = plan(retention, &manifests, &stored);
+    if dry_run || (plan.snapshots.is_empty() && plan.versions.is_empty()) {
+        return Ok(plan);
+    }
+
+    let versions = plan
+        .versions
+        .iter()
+        .flat_map(|(file_id, versions)| {
+            versions
+                .iter()
+                .map(move |version| (file_id.clone(), *version))
+        })
+        .collect();
+    store.forget(versions, plan.snapshots.clone()).await?;
+    let mut files = plan.versions.clone();
+    files.push((model::SNAPSHOTS, plan.snapshots.clone()));
+    let descriptors = peer.prune(files).await?;
+    tracing::info!("pruned {} descriptors", descriptors);
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(n: u8) -> FileId {
+        FileId::try_from([n; model::FILE_ID_LEN].as_slice()).unwrap()
+    }
+
+    fn manifest(day: i64, files: &[(u8, Version)]) -> Manifest {
+        Manifest {
+            time: day * 86400 * 1_000_000_000,
+            files: files
+                .iter()
+                .map(|(n, version)| (file(*n), *version))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn keep_everything_by_default() {
+        let manifests = vec![(0, manifest(0, &[(1, 0)])), (1, manifest(1, &[(1, 1)]))];
+        let stored = vec![(file(1), vec![0, 1])];
+        assert_eq!(
+            plan(&Retention::default(), &manifests, &stored),
+            Plan::default()
+        );
+    }
+
+    #[test]
+    fn drop_unreferenced_versions() {
+        let manifests = vec![
+            (0, manifest(0, &[(1, 0), (2, 0), (3, 0)])),
+            (1, manifest(1, &[(1, 2), (2, 0)])),
+            (2, manifest(2, &[(1, 3), (2, 0)])),
+        ];
+        let stored = vec![
+            // Version 1 was never part of a snapshot, version 4 is newer than all of them.
+            (file(1), vec![0, 1, 2, 3, 4]),
+            (file(2), vec![0]),
+            // Removed before the second snapshot.
+            (file(3), vec![0]),
+            // Only stored since the latest snapshot.
+            (file(4), vec![0]),
+            (model::SNAPSHOTS, vec![0, 1, 2]),
+        ];
+        let retention = Retention {
+            last: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            plan(&retention, &manifests, &stored),
+            Plan {
+                snapshots: vec![0],
+                versions: vec![(file(1), vec![0, 1]), (file(3), vec![0])],
+            }
+        );
+    }
+}
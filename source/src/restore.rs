@@ -0,0 +1,972 @@
+//! Rebuilds files from their encrypted descriptors and blocks.
+
+use crate::snapshot;
+use anyhow::{anyhow, Context, Result};
+use crypto::model::{self, BlockId, FileId, Version};
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    path::{Component, Path, PathBuf},
+};
+use storage::{layout, metadata};
+use tonic::async_trait;
+
+/// Where the encrypted data to restore is read from.
+#[async_trait]
+pub trait Repository {
+    /// Returns all the files known to the repository.
+    async fn files(&self) -> Result<Vec<FileId>>;
+
+    /// Returns all the versions available for a file.
+    async fn versions(&self, file_id: &FileId) -> Result<Vec<Version>>;
+
+    /// Fetches one of the descriptors of a specific version of a file.
+    async fn descriptor(
+        &self,
+        file_id: &FileId,
+        version: Version,
+        index: u16,
+    ) -> Result<model::Descriptor>;
+
+    /// Fetches a block of a file.
+    async fn block(&self, file_id: &FileId, block_id: &BlockId) -> Result<model::Block>;
+}
+
+#[async_trait]
+impl Repository for layout::Root {
+    async fn files(&self) -> Result<Vec<FileId>> {
+        layout::Root::files(self)
+    }
+
+    async fn versions(&self, file_id: &FileId) -> Result<Vec<Version>> {
+        self.existing_file(file_id)?.versions()
+    }
+
+    async fn descriptor(
+        &self,
+        file_id: &FileId,
+        version: Version,
+        index: u16,
+    ) -> Result<model::Descriptor> {
+        self.existing_file(file_id)?.read_descriptor(version, index)
+    }
+
+    async fn block(&self, file_id: &FileId, block_id: &BlockId) -> Result<model::Block> {
+        self.existing_file(file_id)?.read_block(block_id)
+    }
+}
+
+/// Which files must be restored.
+#[derive(Debug, Clone)]
+pub enum Selection {
+    /// Every file in the repository.
+    All,
+    /// Only the files at or under the given paths.
+    Under(Vec<PathBuf>),
+}
+
+impl Selection {
+    fn matches(&self, path: &Path) -> bool {
+        match self {
+            Selection::All => true,
+            Selection::Under(roots) => roots.iter().any(|root| path.starts_with(root)),
+        }
+    }
+}
+
+/// How files are restored.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Restores the owner and group of files, which usually requires running as root.
+    pub ownership: bool,
+    /// Restores the files of this snapshot, instead of the latest version of every file.
+    pub snapshot: Option<Version>,
+}
+
+/// Outcome of a restore operation.
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    /// Files that were written to the target.
+    pub restored: Vec<PathBuf>,
+    /// Number of files that could not be restored.
+    pub failed: usize,
+}
+
+/// Version of a selected file, to restore.
+struct Pending {
+    original: PathBuf,
+    destination: PathBuf,
+    protected: model::ProtectedDescriptor,
+    chunks: Vec<model::BlockRef>,
+}
+
+/// Restores the selected files from `repository` under `target`, as they were in the snapshot
+/// given in `options` or else in their latest version.
+///
+/// Failing files are reported but don't interrupt the restoration of the others.
+pub async fn restore<R: Repository + Sync + ?Sized>(
+    repository: &R,
+    keys: &crypto::Keys,
+    selection: &Selection,
+    target: &Path,
+    options: &Options,
+) -> Result<Report> {
+    let mut report = Report::default();
+    // Hardlinks are restored once the files they link to exist, then symlinks, so that no file
+    // is written through them, and directories last.
+    let mut links = vec![];
+    let mut symlinks = vec![];
+    let mut dirs = vec![];
+    let mut written = HashMap::new();
+    let files: Vec<(FileId, Option<Version>)> = match options.snapshot {
+        Some(snapshot) => snapshot::read(repository, keys, snapshot)
+            .await?
+            .files
+            .into_iter()
+            .map(|(file_id, version)| (file_id, Some(version)))
+            .collect(),
+        None => repository
+            .files()
+            .await?
+            .into_iter()
+            .filter(|file_id| *file_id != model::SNAPSHOTS)
+            .map(|file_id| (file_id, None))
+            .collect(),
+    };
+    for (file_id, version) in files {
+        let file = match prepare(repository, keys, &file_id, version, selection, target).await {
+            Ok(Some(file)) => file,
+            Ok(None) => continue,
+            Err(err) => {
+                tracing::error!("failed to restore {:?}: {:?}", file_id, err);
+                report.failed += 1;
+                continue;
+            }
+        };
+        match file.protected.kind {
+            model::FileKind::Hardlink(_) => {
+                links.push(file);
+                continue;
+            }
+            model::FileKind::Directory(_) => {
+                dirs.push(file);
+                continue;
+            }
+            model::FileKind::Symlink(_) => {
+                symlinks.push(file);
+                continue;
+            }
+            _ => {}
+        }
+        match write(repository, keys, &file, target, options).await {
+            Ok(()) => {
+                written.insert(file.original, file.destination.clone());
+                report.restored.push(file.destination);
+            }
+            Err(err) => {
+                tracing::error!("failed to restore {:?}: {:?}", file_id, err);
+                report.failed += 1;
+            }
+        }
+    }
+    restore_links(
+        repository,
+        keys,
+        links,
+        written,
+        target,
+        options,
+        &mut report,
+    )
+    .await;
+    for file in symlinks {
+        match write(repository, keys, &file, target, options).await {
+            Ok(()) => report.restored.push(file.destination),
+            Err(err) => {
+                tracing::error!("failed to restore {:?}: {:?}", file.original, err);
+                report.failed += 1;
+            }
+        }
+    }
+    restore_dirs(dirs, target, options, &mut report);
+    Ok(report)
+}
+
+/// Restores hardlinks. Files linked together, directly or not, end up as links to a single
+/// file: the one restored already if any, or else the first of them, written from its content.
+async fn restore_links<R: Repository + Sync + ?Sized>(
+    repository: &R,
+    keys: &crypto::Keys,
+    mut links: Vec<Pending>,
+    written: HashMap<PathBuf, PathBuf>,
+    target: &Path,
+    options: &Options,
+    report: &mut Report,
+) {
+    let mut groups = Groups::default();
+    for file in &links {
+        if let model::FileKind::Hardlink(other) = &file.protected.kind {
+            groups.join(&file.original, Path::new(other));
+        }
+    }
+    let mut restored: HashMap<PathBuf, PathBuf> = written
+        .into_iter()
+        .map(|(original, destination)| (groups.find(&original), destination))
+        .collect();
+
+    links.sort_by(|a, b| a.original.cmp(&b.original));
+    for file in links {
+        let group = groups.find(&file.original);
+        let result = match restored.get(&group) {
+            Some(existing) => link(existing, &file.destination, target),
+            None => write(repository, keys, &file, target, options).await,
+        };
+        match result {
+            Ok(()) => {
+                restored.entry(group).or_insert(file.destination.clone());
+                report.restored.push(file.destination);
+            }
+            Err(err) => {
+                tracing::error!("failed to restore {:?}: {:?}", file.original, err);
+                report.failed += 1;
+            }
+        }
+    }
+}
+
+/// Creates directories, even empty ones, and applies their metadata. Entries are written before,
+/// and deeper directories first, so that nothing changes their times afterwards nor is denied by
+/// their permissions.
+fn restore_dirs(mut dirs: Vec<Pending>, target: &Path, options: &Options, report: &mut Report) {
+    dirs.sort_by(|a, b| b.destination.cmp(&a.destination));
+    for dir in dirs {
+        tracing::info!("restoring {:?} to {:?}", &dir.original, &dir.destination);
+        let result = prepare_destination(&dir.destination, target)
+            .and_then(|()| Ok(fs::create_dir_all(&dir.destination)?))
+            .and_then(|()| apply_metadata(&dir, options));
+        match result {
+            Ok(()) => report.restored.push(dir.destination),
+            Err(err) => {
+                tracing::error!("failed to restore {:?}: {:?}", dir.original, err);
+                report.failed += 1;
+            }
+        }
+    }
+}
+
+/// Paths joined into groups, each pointing at another path of its group until the one standing
+/// for the whole group.
+#[derive(Default)]
+struct Groups(HashMap<PathBuf, PathBuf>);
+
+impl Groups {
+    fn find(&self, path: &Path) -> PathBuf {
+        let mut current = path;
+        while let Some(next) = self.0.get(current) {
+            current = next;
+        }
+        current.to_owned()
+    }
+
+    fn join(&mut self, a: &Path, b: &Path) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.0.insert(a, b);
+        }
+    }
+}
+
+/// Reads a version of a single file if it is selected, by default the latest one.
+async fn prepare<R: Repository + Sync + ?Sized>(
+    repository: &R,
+    keys: &crypto::Keys,
+    file_id: &FileId,
+    version: Option<Version>,
+    selection: &Selection,
+    target: &Path,
+) -> Result<Option<Pending>> {
+    let version = match version {
+        Some(version) => version,
+        // Every version was pruned, the blocks remain until the Sink collects them.
+        None => match repository.versions(file_id).await?.iter().max() {
+            Some(version) => *version,
+            None => return Ok(None),
+        },
+    };
+    let (protected, chunks) = read_version(repository, keys, file_id, version).await?;
+
+    let original = PathBuf::from(&protected.filename);
+    if !selection.matches(&original) {
+        return Ok(None);
+    }
+    // The root directory is the target itself.
+    let relative = relative_path(&original)?;
+    let destination = match relative.as_os_str().is_empty() {
+        true => target.to_owned(),
+        false => target.join(relative),
+    };
+    Ok(Some(Pending {
+        original,
+        destination,
+        protected,
+        chunks,
+    }))
+}
+
+/// Writes a file to its destination, replacing any existing file, then applies its metadata.
+async fn write<R: Repository + Sync + ?Sized>(
+    repository: &R,
+    keys: &crypto::Keys,
+    file: &Pending,
+    target: &Path,
+    options: &Options,
+) -> Result<()> {
+    tracing::info!("restoring {:?} to {:?}", &file.original, &file.destination);
+    prepare_destination(&file.destination, target)?;
+    match &file.protected.kind {
+        model::FileKind::Regular | model::FileKind::Hardlink(_) => {
+            write_content(repository, keys, file).await?
+        }
+        kind => create_special(&file.destination, kind)?,
+    }
+    apply_metadata(file, options)
+}
+
+fn apply_metadata(file: &Pending, options: &Options) -> Result<()> {
+    if let Some(md) = &file.protected.metadata {
+        metadata::apply(md, &file.destination, options.ownership)
+            .context("failed to apply the metadata")?;
+    }
+    Ok(())
+}
+
+/// Links `destination` to a file restored already.
+fn link(existing: &Path, destination: &Path, target: &Path) -> Result<()> {
+    tracing::info!("linking {:?} to {:?}", destination, existing);
+    prepare_destination(destination, target)?;
+    Ok(fs::hard_link(existing, destination)?)
+}
+
+/// Creates the parent directories of `destination` under `target`, and removes any file already
+/// there. Writing through an existing symlink could otherwise modify a file outside the target,
+/// so none of the parents may be one.
+fn prepare_destination(destination: &Path, target: &Path) -> Result<()> {
+    let relative = destination.strip_prefix(target)?;
+    fs::create_dir_all(target)?;
+    // The target itself, where the root directory is restored, is never replaced.
+    if relative.as_os_str().is_empty() {
+        return Ok(());
+    }
+    let mut current = target.to_owned();
+    for component in relative.parent().into_iter().flat_map(Path::components) {
+        current.push(component);
+        match fs::symlink_metadata(&current) {
+            Ok(md) if md.is_symlink() => {
+                return Err(anyhow!(
+                    "Refusing to restore through the symlink {:?}",
+                    current
+                ))
+            }
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => fs::create_dir(&current)?,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    match fs::symlink_metadata(destination) {
+        Ok(md) if !md.is_dir() => Ok(fs::remove_file(destination)?),
+        _ => Ok(()),
+    }
+}
+
+/// Creates a file which has no content, like a symlink or a device node.
+#[cfg(unix)]
+fn create_special(destination: &Path, kind: &model::FileKind) -> Result<()> {
+    use nix::sys::stat::{mknod, Mode, SFlag};
+    // Permissions are applied along with the rest of the metadata.
+    let mode = Mode::from_bits_truncate(0o644);
+    match kind {
+        model::FileKind::Symlink(target) => std::os::unix::fs::symlink(target, destination)?,
+        model::FileKind::Fifo => nix::unistd::mkfifo(destination, mode)?,
+        model::FileKind::CharDevice(rdev) => {
+            mknod(destination, SFlag::S_IFCHR, mode, *rdev as nix::libc::dev_t)?
+        }
+        model::FileKind::BlockDevice(rdev) => {
+            mknod(destination, SFlag::S_IFBLK, mode, *rdev as nix::libc::dev_t)?
+        }
+        other => return Err(anyhow!("Not a special file: {:?}", other)),
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_special(_destination: &Path, kind: &model::FileKind) -> Result<()> {
+    Err(anyhow!("Can't restore {:?} on this platform", kind))
+}
+
+/// Writes the content of a regular file.
+async fn write_content<R: Repository + Sync + ?Sized>(
+    repository: &R,
+    keys: &crypto::Keys,
+    file: &Pending,
+) -> Result<()> {
+    let mut out = fs::File::create(&file.destination)?;
+    let mut size = 0;
+    for chunk in &file.chunks {
+        // Shared content lives under the file it was first uploaded for.
+        let block = repository.block(&chunk.file_id, &chunk.block_id).await?;
+        let (block_verified, block_protected) = keys.decrypt_block(&block)?;
+        if block_verified.file_id != chunk.file_id || block_verified.block_id != chunk.block_id {
+            return Err(anyhow!("Block stored at the wrong location"));
+        }
+        // Padding is kept separately, only the chunk is part of the file.
+        out.write_all(&block_protected.chunk)?;
+        size += block_protected.chunk.len() as u64;
+    }
+    out.sync_all()?;
+    if size != file.protected.size {
+        return Err(anyhow!(
+            "Restored {} bytes, expected {}",
+            size,
+            file.protected.size
+        ));
+    }
+    Ok(())
+}
+
+/// Fetches and decrypts all the descriptors of a file version. Returns its metadata and the
+/// blocks holding its content, in order.
+pub async fn read_version<R: Repository + Sync + ?Sized>(
+    repository: &R,
+    keys: &crypto::Keys,
+    file_id: &FileId,
+    version: Version,
+) -> Result<(model::ProtectedDescriptor, Vec<model::BlockRef>)> {
+    let first = repository.descriptor(file_id, version, 0).await?;
+    let (verified, protected) = keys.decrypt_descriptor(&first)?;
+    let mut parts = vec![verified];
+    for index in 1..parts[0].total {
+        let descriptor = repository.descriptor(file_id, version, index).await?;
+        parts.push(keys.decrypt_descriptor(&descriptor)?.0);
+    }
+    Ok((protected, assemble(file_id, version, parts)?))
+}
+
+/// Concatenates the blocks referenced by the descriptors of a file version, given in order.
+/// Fails unless the descriptors form a complete and consistent set.
+pub fn assemble(
+    file_id: &FileId,
+    version: Version,
+    parts: Vec<model::VerifiedDescriptor>,
+) -> Result<Vec<model::BlockRef>> {
+    let total = parts.first().context("No descriptor")?.total;
+    if usize::from(total) != parts.len() {
+        return Err(anyhow!(
+            "Expected {} descriptors, got {}",
+            total,
+            parts.len()
+        ));
+    }
+    let mut chunks = vec![];
+    for (index, part) in (0..total).zip(parts) {
+        if &part.file_id != file_id || part.version != version {
+            return Err(anyhow!("Descriptor stored at the wrong location"));
+        }
+        if part.index != index {
+            return Err(anyhow!("Missing descriptor {}", index));
+        }
+        if part.total != total {
+            return Err(anyhow!("Inconsistent number of descriptors"));
+        }
+        chunks.extend(part.chunks);
+    }
+    Ok(chunks)
+}
+
+/// Turns the original path of a file into a path relative to the restoration target, which is
+/// empty for the root directory.
+fn relative_path(original: &Path) -> Result<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in original.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => {
+                return Err(anyhow!(
+                    "Refusing to restore {:?} outside the target",
+                    original
+                ))
+            }
+        }
+    }
+    Ok(relative)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::RandomApi;
+    use storage::testing::Fixture;
+
+    fn protected(filename: &str, size: u64) -> model::ProtectedDescriptor {
+        model::ProtectedDescriptor {
+            filename: filename.to_string(),
+            size,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn restore_everything() -> Result<()> {
+        let src = tempfile::tempdir()?;
+        let dst = tempfile::tempdir()?;
+        let fixture = Fixture::new(src.path())?;
+        fixture.add("/home/a/file", 0, &[b"abc", b"def"])?;
+        fixture.add("/home/b/empty", 0, &[b""])?;
+
+        let pruned = fixture.add("/home/pruned", 0, &[b"ghi"])?;
+        fixture.root.existing_file(&pruned)?.remove_version(0)?;
+
+        let report = restore(
+            &fixture.root,
+            &fixture.keys,
+            &Selection::All,
+            dst.path(),
+            &Options::default(),
+        )
+        .await?;
+
+        assert_eq!(report.failed, 0);
+        assert_eq!(report.restored.len(), 2);
+        assert_eq!(fs::read(dst.path().join("home/a/file"))?, b"abcdef");
+        assert_eq!(fs::read(dst.path().join("home/b/empty"))?, b"");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restore_subtree() -> Result<()> {
+        let src = tempfile::tempdir()?;
+        let dst = tempfile::tempdir()?;
+        let fixture = Fixture::new(src.path())?;
+        fixture.add("/home/a/file", 0, &[b"abc"])?;
+        fixture.add("/home/ab/file", 0, &[b"def"])?;
+
+        let selection = Selection::Under(vec!["/home/a".into()]);
+        let report = restore(
+            &fixture.root,
+            &fixture.keys,
+            &selection,
+            dst.path(),
+            &Options::default(),
+        )
+        .await?;
+
+        assert_eq!(report.restored, vec![dst.path().join("home/a/file")]);
+        assert!(!dst.path().join("home/ab").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restore_latest_version() -> Result<()> {
+        let src = tempfile::tempdir()?;
+        let dst = tempfile::tempdir()?;
+        let fixture = Fixture::new(src.path())?;
+        let file_id = fixture.add("file", 0, &[b"old"])?;
+        fixture.add_version(&file_id, "file", 1, &[b"new"])?;
+
+        let selection = Selection::Under(vec!["file".into()]);
+        restore(
+            &fixture.root,
+            &fixture.keys,
+            &selection,
+            dst.path(),
+            &Options::default(),
+        )
+        .await?;
+
+        assert_eq!(fs::read(dst.path().join("file"))?, b"new");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restore_snapshot() -> Result<()> {
+        let src = tempfile::tempdir()?;
+        let dst = tempfile::tempdir()?;
+        let fixture = Fixture::new(src.path())?;
+        let kept = fixture.add("kept", 0, &[b"old"])?;
+        fixture.add_version(&kept, "kept", 1, &[b"new"])?;
+        let removed = fixture.add("removed", 0, &[b"abc"])?;
+        fixture.add("added", 0, &[b"def"])?;
+        fixture.snapshot(0, vec![(kept.clone(), 0), (removed, 0)])?;
+        fixture.snapshot(1, vec![(kept, 1)])?;
+
+        // Snapshots are not restored as files.
+        let latest = dst.path().join("latest");
+        let report = restore(
+            &fixture.root,
+            &fixture.keys,
+            &Selection::All,
+            &latest,
+            &Options::default(),
+        )
+        .await?;
+        assert_eq!(report.restored.len(), 3);
+
+        let options = Options {
+            snapshot: Some(0),
+            ..Default::default()
+        };
+        let first = dst.path().join("first");
+        let report = restore(
+            &fixture.root,
+            &fixture.keys,
+            &Selection::All,
+            &first,
+            &options,
+        )
+        .await?;
+        assert_eq!(report.restored.len(), 2);
+        assert_eq!(fs::read(first.join("kept"))?, b"old");
+        assert_eq!(fs::read(first.join("removed"))?, b"abc");
+        assert!(!first.join("added").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn report_failures() -> Result<()> {
+        let src = tempfile::tempdir()?;
+        let dst = tempfile::tempdir()?;
+        let fixture = Fixture::new(src.path())?;
+        fixture.add("good", 0, &[b"abc"])?;
+        fixture.add("bad", 0, &[b"def"])?;
+
+        // Restoring with the wrong key fails for every file.
+        let other = crypto::Keys::new(fixture.rnd.generate_root_key()?);
+        let report = restore(
+            &fixture.root,
+            &other,
+            &Selection::All,
+            dst.path(),
+            &Options::default(),
+        )
+        .await?;
+        assert_eq!(report.failed, 2);
+
+        // A missing block only fails its own file.
+        let file_id = fixture.add("missing", 0, &[b"ghi"])?;
+        let dir = src.path().join(hex::encode(file_id.as_bytes()));
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "blk") {
+                fs::remove_file(path)?;
+            }
+        }
+        let report = restore(
+            &fixture.root,
+            &fixture.keys,
+            &Selection::All,
+            dst.path(),
+            &Options::default(),
+        )
+        .await?;
+        assert_eq!(report.failed, 1);
+        assert_eq!(report.restored.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restore_split_descriptors() -> Result<()> {
+        let src = tempfile::tempdir()?;
+        let dst = tempfile::tempdir()?;
+        let mut fixture = Fixture::new(src.path())?;
+        fixture.max_refs = 2;
+        let file_id = fixture.add("file", 0, &[b"a", b"b", b"c", b"d", b"e"])?;
+
+        restore(
+            &fixture.root,
+            &fixture.keys,
+            &Selection::All,
+            dst.path(),
+            &Options::default(),
+        )
+        .await?;
+        assert_eq!(fs::read(dst.path().join("file"))?, b"abcde");
+
+        // A missing part makes the whole version unusable.
+        fs::remove_file(
+            src.path()
+                .join(hex::encode(file_id.as_bytes()))
+                .join("v0.1.dsc"),
+        )?;
+        let report = restore(
+            &fixture.root,
+            &fixture.keys,
+            &Selection::All,
+            dst.path(),
+            &Options::default(),
+        )
+        .await?;
+        assert_eq!(report.failed, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restore_shared_blocks() -> Result<()> {
+        let src = tempfile::tempdir()?;
+        let dst = tempfile::tempdir()?;
+        let fixture = Fixture::new(src.path())?;
+        let original = fixture.rnd.generate_file_id()?;
+        let blocks = fixture.add_version(&original, "original", 0, &[b"abc", b"def"])?;
+
+        // The copy only has descriptors, pointing at the blocks of the original.
+        let copy = fixture.rnd.generate_file_id()?;
+        let shared = [blocks[1].clone(), blocks[0].clone()];
+        fixture.describe(&copy, 0, &shared, protected("copy", 6))?;
+
+        let report = restore(
+            &fixture.root,
+            &fixture.keys,
+            &Selection::All,
+            dst.path(),
+            &Options::default(),
+        )
+        .await?;
+        assert_eq!(report.failed, 0);
+        assert_eq!(fs::read(dst.path().join("copy"))?, b"defabc");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn restore_links_and_special_files() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+        let src = tempfile::tempdir()?;
+        let dst = tempfile::tempdir()?;
+        let fixture = Fixture::new(src.path())?;
+        let special = |filename: &str, kind| -> Result<()> {
+            let file_id = fixture.rnd.generate_file_id()?;
+            let protected = model::ProtectedDescriptor {
+                kind,
+                ..protected(filename, 0)
+            };
+            fixture.describe(&file_id, 0, &[], protected)
+        };
+        special("link", model::FileKind::Symlink("../missing".to_string()))?;
+        special("fifo", model::FileKind::Fifo)?;
+
+        // Links were all described with their content, and point to each other.
+        for (filename, other) in [("c", "b"), ("b", "c"), ("a", "c")] {
+            let file_id = fixture.rnd.generate_file_id()?;
+            let blocks = fixture.add_version(&file_id, filename, 0, &[b"shared"])?;
+            let protected = model::ProtectedDescriptor {
+                kind: model::FileKind::Hardlink(other.to_string()),
+                ..protected(filename, 6)
+            };
+            fixture.describe(&file_id, 1, &blocks, protected)?;
+        }
+
+        let report = restore(
+            &fixture.root,
+            &fixture.keys,
+            &Selection::All,
+            dst.path(),
+            &Options::default(),
+        )
+        .await?;
+        assert_eq!(report.failed, 0);
+        assert_eq!(report.restored.len(), 5);
+        let target = dst.path();
+        assert_eq!(fs::read_link(target.join("link"))?, Path::new("../missing"));
+        assert!(std::os::unix::fs::FileTypeExt::is_fifo(
+            &fs::symlink_metadata(target.join("fifo"))?.file_type()
+        ));
+        let a = fs::metadata(target.join("a"))?;
+        assert_eq!(a.nlink(), 3);
+        assert_eq!(fs::metadata(target.join("c"))?.ino(), a.ino());
+        assert_eq!(fs::read(target.join("b"))?, b"shared");
+
+        // Restoring again replaces the files.
+        let report = restore(
+            &fixture.root,
+            &fixture.keys,
+            &Selection::All,
+            dst.path(),
+            &Options::default(),
+        )
+        .await?;
+        assert_eq!(report.failed, 0);
+        assert_eq!(fs::metadata(target.join("a"))?.nlink(), 3);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn refuse_writing_through_symlinks() -> Result<()> {
+        let src = tempfile::tempdir()?;
+        let dst = tempfile::tempdir()?;
+        let outside = tempfile::tempdir()?;
+        let fixture = Fixture::new(src.path())?;
+        // A symlink to a directory outside the target, with a file under it from before it was
+        // replaced by the symlink.
+        let protected = model::ProtectedDescriptor {
+            kind: model::FileKind::Symlink(outside.path().to_string_lossy().into_owned()),
+            ..protected("a", 0)
+        };
+        fixture.describe(&fixture.rnd.generate_file_id()?, 0, &[], protected)?;
+        fixture.add("a/passwd", 0, &[b"a"])?;
+        // A symlink already in the target.
+        std::os::unix::fs::symlink(outside.path(), dst.path().join("b"))?;
+        fixture.add("b/passwd", 0, &[b"b"])?;
+
+        let report = restore(
+            &fixture.root,
+            &fixture.keys,
+            &Selection::All,
+            dst.path(),
+            &Options::default(),
+        )
+        .await?;
+        // The symlink restored last cannot replace the directory of the older file.
+        assert_eq!(report.failed, 2);
+        assert_eq!(fs::read_dir(outside.path())?.count(), 0);
+        assert_eq!(fs::read(dst.path().join("a/passwd"))?, b"a");
+        assert_eq!(fs::read_link(dst.path().join("b"))?, outside.path());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn restore_metadata() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+        let src = tempfile::tempdir()?;
+        let dst = tempfile::tempdir()?;
+        let fixture = Fixture::new(src.path())?;
+        let file_id = fixture.rnd.generate_file_id()?;
+        let blocks = fixture.add_version(&file_id, "script", 0, &[b"#!/bin/sh"])?;
+        let metadata = model::Metadata {
+            mode: 0o750,
+            uid: 12345,
+            gid: 12345,
+            mtime: 1_600_000_000_123_456_789,
+            atime: 1_700_000_000_000_000_000,
+            xattrs: vec![],
+        };
+        let protected = model::ProtectedDescriptor {
+            metadata: Some(metadata.clone()),
+            ..protected("script", 9)
+        };
+        fixture.describe(&file_id, 1, &blocks, protected)?;
+
+        // The owner is only restored when asked to.
+        let report = restore(
+            &fixture.root,
+            &fixture.keys,
+            &Selection::All,
+            dst.path(),
+            &Options::default(),
+        )
+        .await?;
+        assert_eq!(report.failed, 0);
+        let md = fs::metadata(dst.path().join("script"))?;
+        assert_eq!(md.mode() & 0o7777, 0o750);
+        assert_eq!(md.mtime(), 1_600_000_000);
+        assert_eq!(md.mtime_nsec(), 123_456_789);
+        assert_ne!(md.uid(), 12345);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn restore_directories() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+        let src = tempfile::tempdir()?;
+        let dst = tempfile::tempdir()?;
+        let fixture = Fixture::new(src.path())?;
+        let dir = |filename: &str, entries: &[&str], mode| -> Result<()> {
+            let protected = model::ProtectedDescriptor {
+                kind: model::FileKind::Directory(entries.iter().map(|e| e.to_string()).collect()),
+                metadata: Some(model::Metadata {
+                    mode,
+                    mtime: 1_000_000_000,
+                    ..Default::default()
+                }),
+                ..protected(filename, 0)
+            };
+            fixture.describe(&fixture.rnd.generate_file_id()?, 0, &[], protected)
+        };
+        // The root is restored as the target.
+        dir("/", &["data"], 0o711)?;
+        // Read-only directories still get their entries.
+        dir("/data", &["empty", "readonly"], 0o755)?;
+        dir("/data/empty", &[], 0o700)?;
+        dir("/data/readonly", &["file"], 0o555)?;
+        fixture.add("/data/readonly/file", 0, &[b"abc"])?;
+
+        let target = dst.path().join("restored");
+        let report = restore(
+            &fixture.root,
+            &fixture.keys,
+            &Selection::All,
+            &target,
+            &Options::default(),
+        )
+        .await?;
+        assert_eq!(report.failed, 0);
+        assert_eq!(report.restored.len(), 5);
+        assert!(report.restored.contains(&target));
+        assert_eq!(fs::read(target.join("data/readonly/file"))?, b"abc");
+        for (path, mode) in [
+            ("", 0o711),
+            ("data", 0o755),
+            ("data/empty", 0o700),
+            ("data/readonly", 0o555),
+        ] {
+            let md = fs::metadata(target.join(path))?;
+            assert_eq!(md.mode() & 0o7777, mode);
+            // Creating the entries did not change the times restored.
+            assert_eq!(md.mtime(), 1);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_incomplete_descriptors() -> Result<()> {
+        let rnd = crypto::Random::new();
+        let file_id = rnd.generate_file_id()?;
+        let part = |index, total| model::VerifiedDescriptor {
+            file_id: file_id.clone(),
+            version: 3,
+            index,
+            total,
+            chunks: vec![model::BlockRef {
+                file_id: file_id.clone(),
+                block_id: rnd.generate_block_id().unwrap(),
+            }],
+        };
+
+        assert_eq!(
+            assemble(&file_id, 3, vec![part(0, 2), part(1, 2)])?.len(),
+            2
+        );
+        assert!(assemble(&file_id, 3, vec![]).is_err());
+        // Missing index.
+        assert!(assemble(&file_id, 3, vec![part(0, 2)]).is_err());
+        assert!(assemble(&file_id, 3, vec![part(0, 2), part(0, 2)]).is_err());
+        // Disagreeing totals.
+        assert!(assemble(&file_id, 3, vec![part(0, 2), part(1, 3)]).is_err());
+        // Parts of another version.
+        assert!(assemble(&file_id, 4, vec![part(0, 1)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_parent_components() {
+        assert!(relative_path(Path::new("/a/../b")).is_err());
+        assert_eq!(relative_path(Path::new("/")).unwrap(), PathBuf::new());
+        assert_eq!(
+            relative_path(Path::new("/a/./b")).unwrap(),
+            PathBuf::from("a/b")
+        );
+    }
+}
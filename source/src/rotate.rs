@@ -0,0 +1,144 @@
+//! Rotation of the root key. New data is encrypted under the latest key, and the versions of the
+//! root key are recorded in the verified parts, so that older keys keep decrypting older data.
+//! Descriptors can then be encrypted again under the latest key. Blocks can't, as their nonce is
+//! their id: they stay readable with the older keys, which are never dropped.
+
+use crate::{keyfile, restore::Repository, server::peer::Peer};
+use anyhow::{Context, Result};
+use source_settings::Passphrase;
+use std::path::Path;
+
+/// Adds a new root key to the keyring in `keyfile`, sealed again the same way if it is. Returns
+/// its version.
+pub fn rotate(keyfile: &Path, passphrase: &Passphrase, rnd: &crypto::Random) -> Result<u16> {
+    let (mut keyring, seal) =
+        keyfile::load(keyfile, passphrase).context("Failed to load the Source key")?;
+    let version = keyring.next_version()?;
+    keyring.rotate(rnd.generate_key(version)?)?;
+    keyfile::save(keyfile, &keyring, seal.as_ref())?;
+    Ok(version)
+}
+
+/// Outcome of a re-encryption.
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    pub descriptors: u64,
+    /// Descriptors encrypted again, as they were under an older key.
+    pub reencrypted: u64,
+}
+
+/// Encrypts every descriptor in `repository` under the latest key, unless it already is, and
+/// sends it to replace the original through `peer`.
+pub async fn reencrypt<R: Repository + Sync + ?Sized, P: Peer + Sync>(
+    repository: &R,
+    peer: &P,
+    keys: &crypto::Keys,
+) -> Result<Report> {
+    let mut report = Report::default();
+    for file_id in repository.files().await? {
+        for version in repository.versions(&file_id).await? {
+            let mut total = 1;
+            let mut index = 0;
+            while index < total {
+                let descriptor = repository.descriptor(&file_id, version, index).await?;
+                if index == 0 {
+                    total = crypto::peek_descriptor(&descriptor)?.total;
+                }
+                report.descriptors += 1;
+                if let Some(descriptor) = keys
+                    .reencrypt_descriptor(&descriptor)
+                    .with_context(|| format!("Failed to decrypt {:?} v{}", file_id, version))?
+                {
+                    peer.send_descriptor(&descriptor).await?;
+                    report.reencrypted += 1;
+                }
+                index += 1;
+            }
+        }
+    }
+    peer.flush().await?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::peer::MockPeer;
+    use crypto::{
+        key::{self, Keyring},
+        model, RandomApi,
+    };
+    use std::sync::{Arc, Mutex};
+    use storage::layout;
+
+    #[test]
+    fn rotate_keyfile() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let keyfile = tmpdir.path().join("keyfile");
+        let rnd = crypto::Random::new();
+        Keyring::from(rnd.generate_root_key()?).to_file(&keyfile)?;
+
+        assert_eq!(rotate(&keyfile, &Passphrase::Prompt, &rnd)?, 1);
+        assert_eq!(rotate(&keyfile, &Passphrase::Prompt, &rnd)?, 2);
+        let keyring = Keyring::from_file(&keyfile)?;
+        assert_eq!(keyring.latest().version(), 2);
+        assert!(keyring.get(0).is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reencrypt_descriptors() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let root = layout::Root::new(tmpdir.path().to_owned());
+        let rnd = crypto::Random::new();
+        let old_key = [1u8; key::KEY_LEN];
+        let old = crypto::Keys::new(key::Durable::new(old_key.into(), 0));
+        let keys = crypto::Keys::new(Keyring::new(vec![
+            key::Durable::new(old_key.into(), 0),
+            rnd.generate_key(1)?,
+        ])?);
+
+        let file_id = rnd.generate_file_id()?;
+        let chunks: Vec<model::BlockRef> = (0..3)
+            .map(|_| {
+                Ok(model::BlockRef {
+                    file_id: file_id.clone(),
+                    block_id: rnd.generate_block_id()?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let file = root.file(&file_id)?;
+        // Version 0 under the old key, version 1 under the new one.
+        for (version, keys) in [(0, &old), (1, &keys)] {
+            let descriptors =
+                keys.encrypt_descriptors(&file_id, version, &chunks, Default::default(), 1)?;
+            for (index, descriptor) in (0..).zip(&descriptors) {
+                file.write_descriptor(descriptor, version, index)?;
+            }
+        }
+
+        let sent = Arc::new(Mutex::new(vec![]));
+        let mut peer = MockPeer::new();
+        let record = sent.clone();
+        peer.expect_send_descriptor().returning(move |descriptor| {
+            record.lock().unwrap().push(descriptor.clone());
+            Ok(())
+        });
+        peer.expect_flush().times(1).returning(|| Ok(()));
+
+        let report = reencrypt(&root, &peer, &keys).await?;
+        assert_eq!(
+            report,
+            Report {
+                descriptors: 6,
+                reencrypted: 3,
+            }
+        );
+        for descriptor in sent.lock().unwrap().iter() {
+            assert_eq!(crypto::descriptor_key_version(descriptor)?, 1);
+            let (verified, _) = keys.decrypt_descriptor(descriptor)?;
+            assert_eq!(verified.version, 0);
+        }
+        Ok(())
+    }
+}
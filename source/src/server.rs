@@ -1,132 +1,1123 @@
 use self::{builder::Builder, peer::Peer};
-use crate::state::{Change, Store};
-use anyhow::Result;
+use crate::state::{Change, Content, Store};
+use anyhow::{Context, Result};
 use crypto::{self, model};
+use source_settings::Schedule;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
-use storage::filesystem::{AsyncFileOps, ShallowInfo, WalkEvent};
+use std::time::{Duration, SystemTime};
+use storage::filesystem::{AsyncFileOps, Chunking, FileType, ShallowInfo, Stat, WalkEvent};
+use storage::filter::Filter;
 use storage::fingerprint::Fingerprinter;
-use tokio::sync::mpsc;
+use storage::metadata;
+use tokio::sync::{mpsc, watch};
 
 mod builder;
-mod peer;
+pub mod peer;
+
+/// Files found by a full walk of the roots, which a snapshot is made of.
+struct Pass {
+    started: SystemTime,
+    seen: Vec<PathBuf>,
+    /// Paths which could not be read, with whatever they contain.
+    unreadable: Vec<PathBuf>,
+}
+
+impl Pass {
+    fn new() -> Self {
+        Pass {
+            started: SystemTime::now(),
+            seen: vec![],
+            unreadable: vec![],
+        }
+    }
+
+    fn record(&mut self, update: &WalkEvent) {
+        match update {
+            WalkEvent::File(info) => self.seen.push(info.file().to_owned()),
+            WalkEvent::Error(path, _) => self.unreadable.push(path.clone()),
+            WalkEvent::Walking | WalkEvent::Walked => {}
+        }
+    }
+}
+
+/// Context of the errors reading a file. They only skip the file during a pass, which is checked
+/// again on the next one, while errors of the local state or of the Sink end the pass.
+#[derive(thiserror::Error, Debug)]
+#[error("failed to read {0:?}")]
+struct Unreadable(PathBuf);
 
 /// A Source server, which watches the filesystem and backs data up to a Sink.
 pub struct Server<P: Peer> {
     roots: Vec<PathBuf>,
+    filter: Filter,
     peer: P,
     fops: AsyncFileOps,
     fp: Fingerprinter,
     store: Store,
+    rnd: crypto::SharedRandom,
     source_key: crypto::Keys,
+    max_refs: usize,
+    chunking: Chunking,
+    paranoid: bool,
+    schedule: Schedule,
+    /// How often watched roots are reconciled, if they are watched instead of following the
+    /// schedule.
+    watch: Option<Duration>,
 }
 
-const CHUNK_SIZE: usize = 2usize.pow(22); // 23 breaks the current gRPC limit.
-
 pub fn builder() -> Builder {
     Builder::default()
 }
 
 impl<P: Peer> Server<P> {
-    /// Run the server. For now, the server shuts down after scanning the filesystem once.
+    /// Runs the server until its schedule has no further pass, or until the process is asked to
+    /// terminate. The file being backed up is always completed before shutting down.
     pub async fn serve(self) -> Result<()> {
-        self.single_pass().await?;
+        let result = self.run(stop_signal()?).await;
 
         self.store.shutdown().await?;
+        result
+    }
+
+    /// Checks the filesystem on schedule, or as it changes, until `stop` becomes true.
+    async fn run(&self, mut stop: watch::Receiver<bool>) -> Result<()> {
+        if let Some(reconcile) = self.watch {
+            return self.watch(reconcile, stop).await;
+        }
+        while !*stop.borrow() {
+            let started = SystemTime::now();
+            match self.single_pass(stop.clone()).await {
+                Ok(()) => {}
+                // A one-off run reports failures, a continuous one tries again on schedule.
+                Err(err) if matches!(self.schedule, Schedule::Once) => return Err(err),
+                Err(err) => tracing::error!("pass failed: {:?}", err),
+            }
+            let Some(next) = self.schedule.next(started, SystemTime::now())? else {
+                break;
+            };
+            let delay = next
+                .duration_since(SystemTime::now())
+                .unwrap_or(Duration::ZERO);
+            tracing::info!("next pass in {:?}", delay);
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {},
+                Ok(()) = stop.changed() => {},
+            }
+        }
         Ok(())
     }
 
-    /// Checks the filesystem once, and sends any changed files to the Sink.
-    async fn single_pass(&self) -> Result<()> {
+    /// Sends files to the Sink as they change, until `stop` becomes true.
+    #[cfg(target_os = "linux")]
+    async fn watch(&self, reconcile: Duration, mut stop: watch::Receiver<bool>) -> Result<()> {
+        tracing::info!("watching {:?}", &self.roots);
+        let (tx, mut rx) = mpsc::channel(1);
+        let watcher =
+            storage::watch::Watcher::new(self.roots.clone(), self.filter.clone(), reconcile);
+        let watch_op = watcher.run(tx);
+        tokio::pin!(watch_op);
+
+        // Full walk in progress, which ends with a snapshot.
+        let mut pass = None;
+        loop {
+            tokio::select! {
+                Some(update) = rx.recv() => match update {
+                    WalkEvent::Walking => pass = Some(Pass::new()),
+                    WalkEvent::Walked => {
+                        if let Some(pass) = pass.take() {
+                            if let Err(err) = self.snapshot(pass).await {
+                                tracing::error!("snapshot failed: {:?}", err);
+                            }
+                        }
+                    }
+                    update => {
+                        if let Some(pass) = &mut pass {
+                            pass.record(&update);
+                        }
+                        // Failed files are retried when reconciling.
+                        if let Err(err) = self.handle(update).await {
+                            tracing::error!("backup failed: {:?}", err);
+                        }
+                    }
+                },
+                result = &mut watch_op => return result.context("watch failure"),
+                Ok(()) = stop.changed() => return Ok(()),
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn watch(&self, _reconcile: Duration, _stop: watch::Receiver<bool>) -> Result<()> {
+        anyhow::bail!("Watching for changes is only supported on Linux")
+    }
+
+    /// Checks the filesystem once, and sends any changed files to the Sink. Stops early, between
+    /// two files, once `stop` becomes true.
+    ///
+    /// A complete pass ends with a snapshot of the files it found, like every full walk when
+    /// watching. Interrupted passes are only part of the next snapshot, and a failed walk fails
+    /// the pass without any.
+    async fn single_pass(&self, mut stop: watch::Receiver<bool>) -> Result<()> {
         tracing::info!("starting full check on {:?}", &self.roots);
+        let mut pass = Pass::new();
 
         // Synchronous single pass: read all the files, chunk them.
         let (tx, mut rx) = mpsc::channel(1);
-        let walk_op = self.fops.walk(self.roots.clone(), tx);
+        let walk_op = self.fops.walk(self.roots.clone(), self.filter.clone(), tx);
         tokio::pin!(walk_op);
 
-        let mut done = false;
-        while !done {
+        let mut walking = true;
+        loop {
             tokio::select! {
-                Some(update) = rx.recv() => {
+                update = rx.recv() => {
                     match update {
-                        WalkEvent::File(info) => {
-                            // For now, abort at the first failure, to be able to detect.
-                            // TODO: distinguish permanent errors (db error for instance) and
-                            // transient issues.
-                            self.single_file(&info).await?;
-                        },
-                        WalkEvent::Error(path, err) => {
-                            tracing::info!("error accessing {:?}: {:?}", path, err);
-                        },
+                        // The walk is over and every file was handled.
+                        None => break,
+                        Some(update) => {
+                            pass.record(&update);
+                            match self.handle(update).await {
+                                // The file may have vanished since it was walked. Its previous
+                                // version, if any, is still part of the snapshot.
+                                Err(err) if err.downcast_ref::<Unreadable>().is_some() => {
+                                    tracing::warn!("skipping file: {:?}", err);
+                                }
+                                result => result?,
+                            }
+                        }
                     }
                 },
-                walk_done = &mut walk_op, if !done => {
-                    done = true;
-                    if let Err(err) = walk_done {
-                        tracing::error!("fs walk failure: {:?}", err);
-                    }
+                walk_done = &mut walk_op, if walking => {
+                    walking = false;
+                    // Files the walk missed would be missing from the snapshot, as if deleted.
+                    walk_done.context("fs walk failure")?;
+                },
+                Ok(()) = stop.changed() => {
+                    tracing::info!("interrupting the check");
+                    return Ok(());
                 },
             };
         }
+        self.snapshot(pass).await
+    }
+
+    /// Sends a snapshot made of the latest stored version of each file found by `pass`. Files
+    /// stored under the paths it could not read are kept as they were, rather than considered
+    /// deleted and eventually pruned.
+    async fn snapshot(&self, pass: Pass) -> Result<()> {
+        let mut paths = pass.seen;
+        let mut known: HashSet<PathBuf> = paths.iter().cloned().collect();
+        for unreadable in &pass.unreadable {
+            for path in self.store.paths_under(unreadable).await? {
+                if known.insert(path.clone()) {
+                    paths.push(path);
+                }
+            }
+        }
+        let time = pass
+            .started
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .context("Clock before the epoch")?
+            .as_nanos();
+        let manifest = model::Manifest {
+            time: i64::try_from(time)?,
+            hostname: hostname::get()?.to_string_lossy().to_string(),
+            roots: self
+                .roots
+                .iter()
+                .map(|root| root.to_string_lossy().to_string())
+                .collect(),
+            files: self.store.stored_versions(paths).await?,
+        };
+        let version = self.store.new_snapshot(manifest.time).await?;
+        let (blocks, descriptors) = self.source_key.encrypt_manifest(
+            self.rnd.as_ref(),
+            version,
+            &manifest,
+            self.max_refs,
+        )?;
+        for block in &blocks {
+            self.peer.send_block(block).await?;
+        }
+        for descriptor in &descriptors {
+            self.peer.send_descriptor(descriptor).await?;
+        }
+        self.peer.flush().await?;
+        tracing::info!(
+            "stored snapshot {} of {} files",
+            version,
+            manifest.files.len()
+        );
         Ok(())
     }
 
+    /// Handles a file found while walking or watching the filesystem.
+    async fn handle(&self, update: WalkEvent) -> Result<()> {
+        match update {
+            WalkEvent::File(info) => self.single_file(&info).await,
+            WalkEvent::Error(path, err) => {
+                tracing::warn!("error accessing {:?}: {:?}", path, err);
+                Ok(())
+            }
+            WalkEvent::Walking | WalkEvent::Walked => Ok(()),
+        }
+    }
+
     /// Checks a single file, and sends it to the Sink if it has changed.
     #[tracing::instrument(skip(self))]
     async fn single_file(&self, info: &ShallowInfo) -> Result<()> {
-        match self.store.check_shallow_change(info).await? {
-            Change::Changed(_) => {
-                tracing::info!("sending changed file");
+        let (previous, unchanged) = match self.store.check_shallow_change(info).await? {
+            Change::Changed(previous) => {
+                tracing::info!("checking changed file");
+                (previous, false)
+            }
+            Change::Unchanged(previous) if self.paranoid => {
+                tracing::info!("checking unchanged file");
+                (Some(previous), true)
             }
             Change::Unchanged(_) => {
                 tracing::info!("skipping unchanged file");
                 return Ok(());
             }
+        };
+        let file_id = self.store.file_id(info.file()).await?;
+        let (previous_content, previous_details) = match &previous {
+            Some(previous) => {
+                self.store
+                    .content(&previous.file_id, previous.version)
+                    .await?
+            }
+            None => (vec![], vec![]),
+        };
+        // Chunks that did not change since the previous version keep their block.
+        let known: HashMap<_, _> = previous_content.iter().cloned().collect();
+
+        // Blocks sent for this version, recorded once the Sink has stored them.
+        let mut sent = HashMap::new();
+        // Only regular files have content.
+        let content = match info.kind() {
+            FileType::Regular => self.read_content(info, &file_id, &known, &mut sent).await?,
+            _ => vec![],
+        };
+
+        let info = settled(info).await;
+        let protected = model::ProtectedDescriptor {
+            filename: info.file().to_string_lossy().to_string(),
+            size: info.len(),
+            kind: self.kind(&file_id, &info).await?,
+            metadata: Some(read_metadata(&info).await?),
+        };
+        // Reading a file only changes its access time, which alone does not make a new version.
+        let details = model::ProtectedDescriptor {
+            metadata: protected.metadata.clone().map(|metadata| model::Metadata {
+                atime: 0,
+                ..metadata
+            }),
+            ..protected.clone()
         }
-        let version = self.store.insert(info).await?;
-        let _descriptor = self.source_key.encrypt_descriptor(
-            model::VerifiedDescriptor {
-                file_id: version.file_id,
-                version: version.version,
-                index: 0,
-                total: 1,
-                chunks: vec![],
-            },
-            model::ProtectedDescriptor {
-                filename: info.file().to_string_lossy().to_string(),
-                size: info.len(),
-            },
+        .encode();
+        if let Some(previous) = previous {
+            if previous.len == info.len()
+                && content == previous_content
+                && details == previous_details
+            {
+                tracing::info!("file is unchanged");
+                return self.store.refresh(&previous, &info).await;
+            }
+            if unchanged {
+                tracing::warn!("file changed without any change to the metadata");
+            }
+        }
+        // The version is reserved without metadata, so that the file is checked again on the next
+        // pass unless the upload completes.
+        let version = self
+            .store
+            .insert(&ShallowInfo::new(info.file().to_owned(), info.len()))
+            .await?;
+
+        // Descriptors are sent last, so that they never reference missing blocks.
+        let descriptors = self.source_key.encrypt_descriptors(
+            &version.file_id,
+            version.version,
+            &content
+                .iter()
+                .map(|(_, block)| block.clone())
+                .collect::<Vec<_>>(),
+            protected,
+            self.max_refs,
         )?;
+        for descriptor in &descriptors {
+            self.peer.send_descriptor(descriptor).await?;
+        }
+        self.peer.flush().await?;
+        self.store.record_blocks(sent.into_iter().collect()).await?;
+        self.store
+            .record_content(&version.file_id, version.version, content, details)
+            .await?;
+        self.store.refresh(&version, &info).await
+    }
 
+    /// Reads, hashes and uploads the content of a regular file. Returns its chunks, in order.
+    async fn read_content(
+        &self,
+        info: &ShallowInfo,
+        file_id: &model::FileId,
+        known: &HashMap<Vec<u8>, model::BlockRef>,
+        sent: &mut HashMap<Vec<u8>, model::BlockRef>,
+    ) -> Result<Content> {
         let (chunk_in, mut chunk_out) = mpsc::channel(1);
 
-        let reader = self.fops.read_chunks(info.file(), CHUNK_SIZE, chunk_in);
+        let reader = self.fops.read_chunks(info.file(), self.chunking, chunk_in);
         tokio::pin!(reader);
 
+        let mut content = vec![];
         loop {
             tokio::select! {
                 Some(data) = chunk_out.recv() => {
                     let chunk = self.fp.hash(data).await;
                     tracing::info!("hashed to {:?}", chunk.digest());
-                    if let Err(err) = self.peer.send(&chunk).await {
-                        tracing::error!("failed to send chunk: {:?}", err);
-                    }
+                    let block = self.upload_chunk(file_id, &chunk, known, sent).await?;
+                    content.push((chunk.digest().to_vec(), block));
                 }
 
                 done = &mut reader => {
                     match done {
                         Ok(size) => {
-                            tracing::info!("hashed and inserted file of size {}", size);
-                            self.store.insert(info).await?;
-                            return Ok(());
+                            tracing::info!("hashed and sent file of size {}", size);
+                            break;
                         },
                         Err(err) => {
                             tracing::error!("failed to read file: {:?}", err);
-                            return Err(err);
+                            return Err(err.context(Unreadable(info.file().to_owned())));
                         }
                     }
                 }
             }
         }
+        // The reader may complete while chunks are still queued.
+        while let Some(data) = chunk_out.recv().await {
+            let chunk = self.fp.hash(data).await;
+            let block = self.upload_chunk(file_id, &chunk, known, sent).await?;
+            content.push((chunk.digest().to_vec(), block));
+        }
+        Ok(content)
+    }
+
+    /// Returns how to recreate a file. Regular files with several links are described as links
+    /// to the first other path backed up with the same inode, if any.
+    async fn kind(&self, file_id: &model::FileId, info: &ShallowInfo) -> Result<model::FileKind> {
+        Ok(match info.kind() {
+            FileType::Regular if info.links() > 1 => {
+                let links = self.store.links(file_id, info.stat()).await?;
+                // Paths are remembered after their file is gone, and inodes get reused.
+                match links
+                    .into_iter()
+                    .find(|other| same_inode(other, info.stat()))
+                {
+                    Some(other) => model::FileKind::Hardlink(other.to_string_lossy().to_string()),
+                    None => model::FileKind::Regular,
+                }
+            }
+            FileType::Regular => model::FileKind::Regular,
+            FileType::Symlink(target) => {
+                model::FileKind::Symlink(target.to_string_lossy().to_string())
+            }
+            FileType::Fifo => model::FileKind::Fifo,
+            FileType::CharDevice(rdev) => model::FileKind::CharDevice(*rdev),
+            FileType::BlockDevice(rdev) => model::FileKind::BlockDevice(*rdev),
+            FileType::Directory(entries) => model::FileKind::Directory(
+                entries
+                    .iter()
+                    .map(|entry| entry.to_string_lossy().to_string())
+                    .collect(),
+            ),
+        })
+    }
+
+    /// Returns a block holding the chunk's content. Blocks already stored for identical content,
+    /// in this file or any other, are reused instead of sending the chunk again.
+    async fn upload_chunk(
+        &self,
+        file_id: &model::FileId,
+        chunk: &model::Chunk,
+        known: &HashMap<Vec<u8>, model::BlockRef>,
+        sent: &mut HashMap<Vec<u8>, model::BlockRef>,
+    ) -> Result<model::BlockRef> {
+        let digest = chunk.digest().to_vec();
+        if let Some(block) = known.get(&digest).or_else(|| sent.get(&digest)) {
+            return Ok(block.clone());
+        }
+        if let Some(block) = self.store.lookup_block(&digest).await? {
+            tracing::debug!("reusing block {:?}", block);
+            return Ok(block);
+        }
+        let block = model::BlockRef {
+            file_id: file_id.clone(),
+            block_id: self.send_chunk(file_id, chunk).await?,
+        };
+        sent.insert(digest, block.clone());
+        Ok(block)
+    }
+
+    /// Encrypts a chunk into a new block and sends it to the Sink. Returns the id of the block.
+    async fn send_chunk(
+        &self,
+        file_id: &model::FileId,
+        chunk: &model::Chunk,
+    ) -> Result<model::BlockId> {
+        let block_id = self.rnd.generate_block_id()?;
+        let block = self.source_key.encrypt_block(
+            model::VerifiedBlock {
+                file_id: file_id.clone(),
+                block_id: block_id.clone(),
+            },
+            model::ProtectedBlock {
+                chunk: chunk.bytes(),
+                padding: vec![],
+            },
+        )?;
+        self.peer.send_block(&block).await?;
+        Ok(block_id)
+    }
+}
+
+/// Returns a receiver which becomes true once the process receives SIGTERM or SIGINT.
+fn stop_signal() -> Result<watch::Receiver<bool>> {
+    let (tx, rx) = watch::channel(false);
+    #[cfg(unix)]
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = terminate.recv() => {},
+            _ = tokio::signal::ctrl_c() => {},
+        }
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+        tracing::info!("shutting down once the current file is backed up");
+        // Keep the sender alive so that receivers do not see it as dropped.
+        let _ = tx.send(true);
+        std::future::pending::<()>().await;
+    });
+    Ok(rx)
+}
+
+/// Returns whether `path` is currently the file with this inode.
+fn same_inode(path: &std::path::Path, stat: &Stat) -> bool {
+    std::fs::symlink_metadata(path).is_ok_and(|md| {
+        let other = Stat::new(&md);
+        other.inode == stat.inode && other.device == stat.device
+    })
+}
+
+/// Returns the metadata of a file once it was read. Reading restores the access time, which
+/// updates the ctime, so the metadata from the walk would never match on the next pass. The
+/// metadata from the walk is kept if the file was modified in the meantime.
+async fn settled(info: &ShallowInfo) -> ShallowInfo {
+    // Other files are not read.
+    if info.kind() != &FileType::Regular {
+        return info.clone();
+    }
+    let path = info.file().to_owned();
+    let after = tokio::task::spawn_blocking(move || {
+        std::fs::metadata(&path).map(|md| ShallowInfo::from_metadata(path, &md))
+    })
+    .await;
+    match after {
+        Ok(Ok(after))
+            if after.len() == info.len()
+                && after.stat().mtime == info.stat().mtime
+                && after.stat().inode == info.stat().inode
+                && after.stat().device == info.stat().device =>
+        {
+            after
+        }
+        _ => info.clone(),
+    }
+}
+
+/// Reads the POSIX metadata of a file. Regular files and directories may be symlinks given as
+/// roots, which are followed like when walking.
+async fn read_metadata(info: &ShallowInfo) -> Result<model::Metadata> {
+    let path = info.file().to_owned();
+    let follow = matches!(info.kind(), FileType::Regular | FileType::Directory(_));
+    let metadata = tokio::task::spawn_blocking(move || metadata::read(&path, follow)).await?;
+    metadata.with_context(|| Unreadable(info.file().to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot;
+    use crypto::RandomApi;
+    use peer::MockPeer;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn send_encrypted_file() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("file");
+        std::fs::write(&path, "some content")?;
+
+        let blocks = Arc::new(Mutex::new(vec![]));
+        let descriptors = Arc::new(Mutex::new(vec![]));
+        let mut peer = MockPeer::new();
+        let sent = blocks.clone();
+        peer.expect_send_block().returning(move |block| {
+            sent.lock().unwrap().push(model::Block {
+                verified: block.verified.clone(),
+                protected: block.protected.clone(),
+            });
+            Ok(())
+        });
+        let sent = descriptors.clone();
+        peer.expect_send_descriptor().returning(move |descriptor| {
+            sent.lock().unwrap().push(model::Descriptor {
+                verified: descriptor.verified.clone(),
+                protected: descriptor.protected.clone(),
+            });
+            Ok(())
+        });
+        peer.expect_flush().returning(|| Ok(())).times(1);
+
+        let rnd = Arc::new(crypto::Random::new());
+        let server = Server {
+            roots: vec![],
+            filter: Filter::default(),
+            peer,
+            fops: AsyncFileOps::new().await,
+            fp: Fingerprinter::new(1)?,
+            store: Store::new_for_test(rnd.clone()).await?,
+            source_key: crypto::Keys::new(rnd.generate_root_key()?),
+            rnd,
+            max_refs: 10,
+            chunking: Chunking::Fixed(1024),
+            paranoid: false,
+            schedule: Schedule::Once,
+            watch: None,
+        };
+        server
+            .single_file(&ShallowInfo::new(path.clone(), 12))
+            .await?;
+
+        // The descriptor references the single block, which contains the file content.
+        let descriptors = descriptors.lock().unwrap();
+        assert_eq!(descriptors.len(), 1);
+        let (verified, protected) = server.source_key.decrypt_descriptor(&descriptors[0])?;
+        assert_eq!(protected.filename, path.to_string_lossy());
+        assert_eq!(protected.size, 12);
+
+        let blocks = blocks.lock().unwrap();
+        assert_eq!(blocks.len(), 1);
+        let (block, content) = server.source_key.decrypt_block(&blocks[0])?;
+        assert_eq!(
+            verified.chunks,
+            vec![model::BlockRef {
+                file_id: block.file_id.clone(),
+                block_id: block.block_id
+            }]
+        );
+        assert_eq!(block.file_id, verified.file_id);
+        assert_eq!(content.chunk.as_ref(), b"some content");
+        Ok(())
+    }
+
+    /// Counts the blocks and keeps the descriptors sent through a MockPeer.
+    #[derive(Clone, Default)]
+    struct Sent {
+        blocks: Arc<Mutex<usize>>,
+        descriptors: Arc<Mutex<Vec<model::Descriptor>>>,
+    }
+
+    impl Sent {
+        fn peer(&self) -> MockPeer {
+            let mut peer = MockPeer::new();
+            let blocks = self.blocks.clone();
+            peer.expect_send_block().returning(move |_| {
+                *blocks.lock().unwrap() += 1;
+                Ok(())
+            });
+            let descriptors = self.descriptors.clone();
+            peer.expect_send_descriptor().returning(move |descriptor| {
+                descriptors.lock().unwrap().push(descriptor.clone());
+                Ok(())
+            });
+            peer.expect_flush().returning(|| Ok(()));
+            peer
+        }
+
+        fn blocks(&self) -> usize {
+            *self.blocks.lock().unwrap()
+        }
+
+        /// Decrypts the verified part of the descriptors sent so far.
+        fn descriptors(&self, keys: &crypto::Keys) -> Result<Vec<model::VerifiedDescriptor>> {
+            self.descriptors
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|descriptor| Ok(keys.decrypt_descriptor(descriptor)?.0))
+                .collect()
+        }
+    }
+
+    async fn test_server(peer: MockPeer, paranoid: bool) -> Result<Server<MockPeer>> {
+        let rnd = Arc::new(crypto::Random::new());
+        Ok(Server {
+            roots: vec![],
+            filter: Filter::default(),
+            peer,
+            fops: AsyncFileOps::new().await,
+            fp: Fingerprinter::new(1)?,
+            store: Store::new_for_test(rnd.clone()).await?,
+            source_key: crypto::Keys::new(rnd.generate_root_key()?),
+            rnd,
+            max_refs: 10,
+            chunking: Chunking::Fixed(4),
+            paranoid,
+            schedule: Schedule::Once,
+            watch: None,
+        })
+    }
+
+    fn stat(path: &std::path::Path) -> Result<ShallowInfo> {
+        Ok(ShallowInfo::from_metadata(
+            path.to_owned(),
+            &std::fs::metadata(path)?,
+        ))
+    }
+
+    #[tokio::test]
+    async fn reuse_identical_content() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let first = tmpdir.path().join("first");
+        let second = tmpdir.path().join("second");
+        std::fs::write(&first, "aaaabbbb")?;
+        std::fs::write(&second, "bbbbaaaacccc")?;
+
+        let sent = Sent::default();
+        let server = test_server(sent.peer(), false).await?;
+        server.single_file(&ShallowInfo::new(first, 8)).await?;
+        server.single_file(&ShallowInfo::new(second, 12)).await?;
+
+        // Only "cccc" is new in the second file.
+        assert_eq!(sent.blocks(), 3);
+        let descriptors = sent.descriptors(&server.source_key)?;
+        let (first, second) = (&descriptors[0], &descriptors[1]);
+        assert_eq!(second.chunks[0], first.chunks[1]);
+        assert_eq!(second.chunks[1], first.chunks[0]);
+        assert_eq!(second.chunks[2].file_id, second.file_id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_changed_chunks() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("log");
+        std::fs::write(&path, "aaaabbbb")?;
+
+        let sent = Sent::default();
+        let server = test_server(sent.peer(), false).await?;
+        server
+            .single_file(&ShallowInfo::new(path.clone(), 8))
+            .await?;
+        std::fs::write(&path, "aaaabbbbcc")?;
+        server
+            .single_file(&ShallowInfo::new(path.clone(), 10))
+            .await?;
+
+        // Only the appended chunk is sent for the new version.
+        assert_eq!(sent.blocks(), 3);
+        let descriptors = sent.descriptors(&server.source_key)?;
+        let (old, new) = (&descriptors[0], &descriptors[1]);
+        assert_eq!((old.version, new.version), (0, 1));
+        assert_eq!(new.chunks.len(), 3);
+        assert_eq!(new.chunks[..2], old.chunks[..]);
+
+        let (content, _) = server.store.content(&new.file_id, 1).await?;
+        assert_eq!(content.len(), 3);
+        assert_eq!(content[2].1, new.chunks[2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn recheck_same_length_edits() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("config");
+        std::fs::write(&path, "key=1")?;
+
+        let sent = Sent::default();
+        let server = test_server(sent.peer(), false).await?;
+        server.single_file(&stat(&path)?).await?;
+        // Unchanged metadata: the file is not even read.
+        server.single_file(&stat(&path)?).await?;
+        assert_eq!(sent.descriptors(&server.source_key)?.len(), 1);
+
+        // Touching the file makes it checked again, and only its new mtime is sent.
+        let blocks = sent.blocks();
+        filetime::set_file_mtime(&path, filetime::FileTime::from_unix_time(1000, 0))?;
+        server.single_file(&stat(&path)?).await?;
+        assert_eq!(sent.descriptors(&server.source_key)?.len(), 2);
+        assert_eq!(sent.blocks(), blocks);
+        server.single_file(&stat(&path)?).await?;
+
+        // An edit keeping the same length is sent.
+        std::fs::write(&path, "key=2")?;
+        server.single_file(&stat(&path)?).await?;
+        let descriptors = sent.descriptors(&server.source_key)?;
+        assert_eq!(descriptors.len(), 3);
+        assert_eq!(descriptors[2].version, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn paranoid_rehash() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("page");
+        std::fs::write(&path, "abcd")?;
+
+        let sent = Sent::default();
+        let server = test_server(sent.peer(), true).await?;
+        server.single_file(&stat(&path)?).await?;
+        let recorded = stat(&path)?;
+
+        // The content is read again, and found to be the same.
+        server.single_file(&recorded).await?;
+        assert_eq!(sent.descriptors(&server.source_key)?.len(), 1);
+
+        // Content changed behind unchanged metadata is still detected.
+        std::fs::write(&path, "abce")?;
+        server.single_file(&recorded).await?;
+        assert_eq!(sent.descriptors(&server.source_key)?.len(), 2);
+        assert_eq!(sent.blocks(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stop_between_passes() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        std::fs::write(tmpdir.path().join("file"), "abcd")?;
+
+        let sent = Sent::default();
+        let mut server = test_server(sent.peer(), false).await?;
+        server.roots = vec![tmpdir.path().to_owned()];
+        server.schedule = Schedule::Interval(Duration::from_secs(3600));
+        let (stop_tx, stop) = watch::channel(false);
+        let run = server.run(stop);
+        tokio::pin!(run);
+
+        // The first pass starts right away, then the server waits for the next one.
+        while sent.descriptors.lock().unwrap().is_empty() {
+            tokio::select! {
+                result = &mut run => panic!("stopped early: {:?}", result),
+                _ = tokio::time::sleep(Duration::from_millis(10)) => {},
+            }
+        }
+        stop_tx.send(true)?;
+        tokio::time::timeout(Duration::from_secs(10), run).await??;
+        // The file, its directory, then the manifest of the snapshot.
+        assert_eq!(sent.blocks(), 2);
+        let descriptors = sent.descriptors(&server.source_key)?;
+        assert_eq!(descriptors.len(), 3);
+        assert_eq!(descriptors[2].file_id, model::SNAPSHOTS);
+        assert_eq!(descriptors[2].version, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retry_failed_uploads() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        std::fs::write(tmpdir.path().join("file"), "abcd")?;
+
+        let mut peer = MockPeer::new();
+        peer.expect_send_block().returning(|_| Ok(()));
+        peer.expect_send_descriptor().returning(|_| Ok(()));
+        let flushes = Arc::new(Mutex::new(0));
+        let attempts = flushes.clone();
+        peer.expect_flush().returning(move || {
+            *attempts.lock().unwrap() += 1;
+            Err(anyhow::anyhow!("sink unavailable"))
+        });
+        let mut server = test_server(peer, false).await?;
+        server.roots = vec![tmpdir.path().to_owned()];
+
+        // A single pass reports the failure.
+        let (stop_tx, stop) = watch::channel(false);
+        assert!(server.run(stop.clone()).await.is_err());
+
+        // A continuous run keeps trying the same file.
+        server.schedule = Schedule::Interval(Duration::from_millis(1));
+        let run = server.run(stop);
+        tokio::pin!(run);
+        while *flushes.lock().unwrap() < 3 {
+            tokio::select! {
+                result = &mut run => panic!("stopped early: {:?}", result),
+                _ = tokio::time::sleep(Duration::from_millis(10)) => {},
+            }
+        }
+        stop_tx.send(true)?;
+        tokio::time::timeout(Duration::from_secs(10), run).await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skip_vanished_files() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let file = tmpdir.path().join("file");
+        std::fs::write(&file, "abcd")?;
+        std::fs::create_dir(tmpdir.path().join("dir"))?;
+
+        // The file is removed while it is uploaded, before its metadata is read.
+        let sent = Sent::default();
+        let mut peer = MockPeer::new();
+        let removed = file.clone();
+        peer.expect_send_block().returning(move |_| {
+            // Blocks of the snapshot come later.
+            let _ = std::fs::remove_file(&removed);
+            Ok(())
+        });
+        let descriptors = sent.descriptors.clone();
+        peer.expect_send_descriptor().returning(move |descriptor| {
+            descriptors.lock().unwrap().push(descriptor.clone());
+            Ok(())
+        });
+        peer.expect_flush().returning(|| Ok(()));
+        let mut server = test_server(peer, false).await?;
+        server.roots = vec![tmpdir.path().to_owned()];
+        let (_stop_tx, stop) = watch::channel(false);
+        server.single_pass(stop).await?;
+
+        // The other files are still backed up, then the snapshot.
+        let descriptors = sent.descriptors(&server.source_key)?;
+        assert_eq!(descriptors.len(), 3);
+        assert_eq!(descriptors[2].file_id, model::SNAPSHOTS);
+        assert!(server.store.stored_versions(vec![file]).await?.is_empty());
+        Ok(())
+    }
+
+    /// Returns a peer storing what it is sent under `dir`, in the canonical layout.
+    fn storing_peer(dir: &std::path::Path) -> MockPeer {
+        let mut peer = MockPeer::new();
+        let root = storage::layout::Root::new(dir.to_owned());
+        peer.expect_send_block().returning(move |block| {
+            let verified = crypto::peek_block(block)?;
+            root.file(&verified.file_id)?
+                .write_block(block, &verified.block_id)
+        });
+        let root = storage::layout::Root::new(dir.to_owned());
+        peer.expect_send_descriptor().returning(move |descriptor| {
+            let verified = crypto::peek_descriptor(descriptor)?;
+            root.file(&verified.file_id)?.write_descriptor(
+                descriptor,
+                verified.version,
+                verified.index,
+            )
+        });
+        peer.expect_flush().returning(|| Ok(()));
+        peer
+    }
+
+    #[tokio::test]
+    async fn keep_unreadable_files_in_snapshots() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let kept = tmpdir.path().join("kept");
+        let lost = tmpdir.path().join("lost");
+        for dir in [&kept, &lost] {
+            std::fs::create_dir(dir)?;
+            std::fs::write(dir.join("file"), "abcd")?;
+        }
+        let repository = tmpdir.path().join("repository");
+        std::fs::create_dir(&repository)?;
+        let mut server = test_server(storing_peer(&repository), false).await?;
+        server.roots = vec![kept.clone(), lost.clone()];
+        let (_stop_tx, stop) = watch::channel(false);
+        server.single_pass(stop.clone()).await?;
+
+        // A root can't be read anymore, like an unmounted disk: its files are not deleted.
+        std::fs::remove_dir_all(&lost)?;
+        std::fs::write(kept.join("new"), "efgh")?;
+        server.single_pass(stop).await?;
+
+        let root = storage::layout::Root::new(repository);
+        let files =
+            |manifest: model::Manifest| -> HashSet<_> { manifest.files.into_iter().collect() };
+        let first = files(snapshot::read(&root, &server.source_key, 0).await?);
+        let second = files(snapshot::read(&root, &server.source_key, 1).await?);
+        let lost = server
+            .store
+            .stored_versions(vec![lost.clone(), lost.join("file")])
+            .await?;
+        assert_eq!(lost.len(), 2);
+        assert_eq!(first.len(), 4);
+        assert_eq!(second.len(), 5);
+        assert!(lost.iter().all(|version| second.contains(version)));
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn send_watched_changes() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("file");
+        std::fs::write(&path, "abcd")?;
+
+        let sent = Sent::default();
+        let mut server = test_server(sent.peer(), false).await?;
+        server.roots = vec![tmpdir.path().to_owned()];
+        server.watch = Some(Duration::from_secs(3600));
+        let (stop_tx, stop) = watch::channel(false);
+        let run = server.run(stop);
+        tokio::pin!(run);
+
+        let wait_for = |count: usize| {
+            let sent = sent.clone();
+            async move {
+                while sent.descriptors.lock().unwrap().len() < count {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+        };
+        // Existing files and the root directory are sent first, with a snapshot of them, then
+        // changes as they happen.
+        tokio::select! {
+            result = &mut run => panic!("stopped early: {:?}", result),
+            _ = wait_for(3) => {},
+        }
+        std::fs::write(&path, "efgh")?;
+        tokio::select! {
+            result = &mut run => panic!("stopped early: {:?}", result),
+            _ = wait_for(4) => {},
+        }
+        stop_tx.send(true)?;
+        tokio::time::timeout(Duration::from_secs(10), run).await??;
+        let descriptors = sent.descriptors(&server.source_key)?;
+        assert_eq!(descriptors[2].file_id, model::SNAPSHOTS);
+        assert_ne!(descriptors[3].file_id, model::SNAPSHOTS);
+        assert_eq!(sent.blocks(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn split_descriptors() -> anyhow::Result<()> {
+        let rnd = crypto::Random::new();
+        let keys = crypto::Keys::new(rnd.generate_root_key()?);
+        let file_id = rnd.generate_file_id()?;
+        let chunks = (0..5)
+            .map(|_| {
+                Ok(model::BlockRef {
+                    file_id: file_id.clone(),
+                    block_id: rnd.generate_block_id()?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let protected = model::ProtectedDescriptor {
+            filename: "file".to_string(),
+            size: 5,
+            ..Default::default()
+        };
+
+        let descriptors = keys.encrypt_descriptors(&file_id, 3, &chunks, protected.clone(), 2)?;
+        let parts = descriptors
+            .iter()
+            .map(|descriptor| keys.decrypt_descriptor(descriptor))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(parts.len(), 3);
+        // Only the first part carries the metadata.
+        assert_eq!(parts[0].1, protected);
+        assert_eq!(parts[2].1, model::ProtectedDescriptor::default());
+        let verified = parts.into_iter().map(|(verified, _)| verified).collect();
+        assert_eq!(crate::restore::assemble(&file_id, 3, verified)?, chunks);
+
+        // Empty files still get a descriptor.
+        let descriptors = keys.encrypt_descriptors(&file_id, 3, &[], protected, 2)?;
+        assert_eq!(descriptors.len(), 1);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn send_links_and_special_files() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let (a, b, link) = (
+            tmpdir.path().join("a"),
+            tmpdir.path().join("b"),
+            tmpdir.path().join("link"),
+        );
+        std::fs::write(&a, "aaaa")?;
+        std::fs::hard_link(&a, &b)?;
+        std::os::unix::fs::symlink("a", &link)?;
+
+        let sent = Sent::default();
+        let server = test_server(sent.peer(), false).await?;
+        let entry = |path: &std::path::Path| -> Result<ShallowInfo> {
+            let md = std::fs::symlink_metadata(path)?;
+            ShallowInfo::from_entry(path.to_owned(), &md)?.context("not a file")
+        };
+        for path in [&b, &a, &link] {
+            server.single_file(&entry(path)?).await?;
+        }
+
+        let kinds = sent
+            .descriptors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|descriptor| Ok(server.source_key.decrypt_descriptor(descriptor)?.1.kind))
+            .collect::<Result<Vec<_>>>()?;
+        let path = |path: &std::path::Path| path.to_string_lossy().to_string();
+        assert_eq!(
+            kinds,
+            vec![
+                model::FileKind::Regular,
+                model::FileKind::Hardlink(path(&b)),
+                model::FileKind::Symlink("a".to_string()),
+            ]
+        );
+        // The content is only sent once, and symlinks have none.
+        assert_eq!(sent.blocks(), 1);
+
+        // Retargeting a symlink is a change, even though it still has no content.
+        std::fs::remove_file(&link)?;
+        std::os::unix::fs::symlink("b", &link)?;
+        server.single_file(&entry(&link)?).await?;
+        let descriptors = sent.descriptors(&server.source_key)?;
+        assert_eq!(descriptors.len(), 4);
+        assert_eq!(descriptors[3].version, 1);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn send_metadata_changes() -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("file");
+        std::fs::write(&path, "content")?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640))?;
+
+        let sent = Sent::default();
+        let server = test_server(sent.peer(), false).await?;
+        let metadata = || -> Result<model::Metadata> {
+            let descriptors = sent.descriptors.lock().unwrap();
+            let last = descriptors.last().context("nothing sent")?;
+            let (_, protected) = server.source_key.decrypt_descriptor(last)?;
+            protected.metadata.context("no metadata")
+        };
+        server.single_file(&stat(&path)?).await?;
+        assert_eq!(metadata()?.mode, 0o640);
+        let blocks = sent.blocks();
+
+        // Changing permissions makes a new version, without sending the content again.
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        server.single_file(&stat(&path)?).await?;
+        assert_eq!(metadata()?.mode, 0o600);
+        assert_eq!(sent.descriptors.lock().unwrap().len(), 2);
+        assert_eq!(sent.blocks(), blocks);
+
+        // Only accessing the file does not.
+        filetime::set_file_atime(&path, filetime::FileTime::from_unix_time(1, 0))?;
+        server.single_file(&stat(&path)?).await?;
+        assert_eq!(sent.descriptors.lock().unwrap().len(), 2);
+        Ok(())
     }
 }
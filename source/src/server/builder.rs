@@ -1,19 +1,27 @@
 use super::{peer, Server};
 use crate::state::Store;
 use settings::connection;
-use source_settings::Settings;
+use source_settings::{Schedule, Settings};
 use std::path::{Path, PathBuf};
-use storage::filesystem::AsyncFileOps;
+use std::time::Duration;
+use storage::filesystem::{AsyncFileOps, Chunking};
+use storage::filter::Filter;
 use storage::fingerprint::{self, Fingerprinter};
 
 #[derive(Default)]
 pub struct Builder {
     roots: Vec<PathBuf>,
+    filter: Filter,
     connection: Option<connection::Info>,
     broker: Option<broker_client::Settings>,
     db: PathBuf,
     rnd: Option<crypto::SharedRandom>,
     source_key: Option<crypto::Keys>,
+    max_refs: Option<usize>,
+    chunking: Option<Chunking>,
+    paranoid: bool,
+    schedule: Schedule,
+    watch: Option<Duration>,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -24,6 +32,10 @@ pub enum BuilderError {
     MissingBrokerInfo,
     #[error("Missing Crypto")]
     MissingCrypto,
+    #[error("Missing descriptor size")]
+    MissingMaxRefs,
+    #[error("Missing chunking")]
+    MissingChunking,
     #[error("Invalid fingerprinter")]
     FingerprinterError(#[from] fingerprint::FingerprinterError),
     #[error("Unknown error")]
@@ -33,9 +45,15 @@ pub enum BuilderError {
 impl Builder {
     pub fn settings(self, settings: &Settings) -> Builder {
         self.roots(settings.backup().roots().clone())
+            .filter(settings.backup().filter().clone())
             .connection(settings.connection())
             .broker(settings.broker())
             .db(settings.backup().db())
+            .max_refs(settings.backup().max_refs_per_descriptor())
+            .chunking(settings.backup().chunking())
+            .paranoid(settings.backup().paranoid())
+            .schedule(settings.backup().schedule().clone())
+            .watch(settings.backup().watch())
     }
 
     pub fn roots(mut self, roots: Vec<PathBuf>) -> Builder {
@@ -43,6 +61,12 @@ impl Builder {
         self
     }
 
+    /// Only backs up the files under the roots accepted by `filter`.
+    pub fn filter(mut self, filter: Filter) -> Builder {
+        self.filter = filter;
+        self
+    }
+
     pub fn connection(mut self, connection: &connection::Settings) -> Builder {
         self.connection = Some(connection.info().clone());
         self
@@ -58,6 +82,34 @@ impl Builder {
         self
     }
 
+    pub fn max_refs(mut self, max_refs: usize) -> Builder {
+        self.max_refs = Some(max_refs);
+        self
+    }
+
+    pub fn chunking(mut self, chunking: Chunking) -> Builder {
+        self.chunking = Some(chunking);
+        self
+    }
+
+    /// Re-hashes files even when their metadata is unchanged.
+    pub fn paranoid(mut self, paranoid: bool) -> Builder {
+        self.paranoid = paranoid;
+        self
+    }
+
+    pub fn schedule(mut self, schedule: Schedule) -> Builder {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Watches the roots for changes instead of following the schedule, fully checking them
+    /// every `reconcile`.
+    pub fn watch(mut self, reconcile: Option<Duration>) -> Builder {
+        self.watch = reconcile;
+        self
+    }
+
     pub fn crypto(mut self, rnd: crypto::SharedRandom, source_key: crypto::Keys) -> Builder {
         self.rnd = Some(rnd);
         self.source_key = Some(source_key);
@@ -71,14 +123,23 @@ impl Builder {
         let peer = peer::new(broker, connection);
         let rnd = self.rnd.ok_or(BuilderError::MissingCrypto)?;
         let source_key = self.source_key.ok_or(BuilderError::MissingCrypto)?;
+        let max_refs = self.max_refs.ok_or(BuilderError::MissingMaxRefs)?;
+        let chunking = self.chunking.ok_or(BuilderError::MissingChunking)?;
 
         Ok(Server {
             roots: self.roots,
+            filter: self.filter,
             peer,
             fops: AsyncFileOps::new().await,
             fp: Fingerprinter::new(1)?,
-            store: Store::new(&self.db, rnd).await?,
+            store: Store::new(&self.db, rnd.clone()).await?,
+            rnd,
             source_key,
+            max_refs,
+            chunking,
+            paranoid: self.paranoid,
+            schedule: self.schedule,
+            watch: self.watch,
         })
     }
 }
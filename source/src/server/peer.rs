@@ -1,16 +1,21 @@
 //! This module abstracts the work needed to connect to a sink
 //! through the Broker, possibly using a proxy, etc.
 
-use anyhow::{Context, Result};
+use crate::restore;
+use anyhow::{anyhow, Context, Result};
 use broker_client::{Broker, BrokerImpl, SinkLocation};
-use bytes::Bytes;
 use crypto::model;
 use mockall::automock;
 use rpcutil::{Backoff, ExpBackoff};
 use settings::connection;
-use sink_client::{Sink, SinkBuilder, SinkBuilderImpl};
+use sink_client::{Sink, SinkBuilder, SinkBuilderImpl, Upload, UploadItem};
 use std::marker::PhantomData;
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use storage::layout;
 use tokio::sync::{
     mpsc::{self, Receiver, Sender},
     oneshot,
@@ -20,8 +25,37 @@ use tonic::async_trait;
 #[automock]
 #[async_trait]
 pub trait Peer {
-    /// Send a chunk to the sink. Blocks until the sink is available.
-    async fn send(&self, data: &model::Chunk) -> anyhow::Result<()>;
+    /// Send an encrypted block to the sink. Blocks until the sink is available, but the block
+    /// is only known to be stored once `flush` succeeds.
+    async fn send_block(&self, block: &model::Block) -> anyhow::Result<()>;
+
+    /// Send an encrypted descriptor to the sink. Blocks until the sink is available, but the
+    /// descriptor is only known to be stored once `flush` succeeds.
+    async fn send_descriptor(&self, descriptor: &model::Descriptor) -> anyhow::Result<()>;
+
+    /// Waits until everything sent so far is durably stored by the sink.
+    async fn flush(&self) -> anyhow::Result<()>;
+
+    /// List the files stored on the sink, with their versions in increasing order.
+    async fn list(&self) -> anyhow::Result<Vec<(model::FileId, Vec<model::Version>)>>;
+
+    /// Fetch an encrypted descriptor from the sink.
+    async fn fetch_descriptor(
+        &self,
+        file_id: &model::FileId,
+        version: model::Version,
+        index: u16,
+    ) -> anyhow::Result<model::Descriptor>;
+
+    /// Fetch an encrypted block from the sink.
+    async fn fetch_block(
+        &self,
+        file_id: &model::FileId,
+        block_id: &model::BlockId,
+    ) -> anyhow::Result<model::Block>;
+
+    /// Remove versions of files from the sink. Returns how many descriptors were removed.
+    async fn prune(&self, files: FileList) -> anyhow::Result<u64>;
 }
 
 /// Returns a new PeerImpl that will immediately start connecting to a Sink using id as its local identity.
@@ -32,18 +66,121 @@ pub fn new(broker: BrokerImpl, id: connection::Info) -> PeerImpl {
 /// Default implementation of a Peer.
 pub struct PeerImpl {
     tx: Sender<PeerOp>,
+    /// Files listed by the last call to `Repository::files`, so that looking up the versions of
+    /// each file does not list them all again. Cleared when the stored files change.
+    listed: Mutex<Option<HashMap<model::FileId, Vec<model::Version>>>>,
 }
 
 #[async_trait]
 impl Peer for PeerImpl {
-    async fn send(&self, data: &model::Chunk) -> anyhow::Result<()> {
+    async fn send_block(&self, block: &model::Block) -> anyhow::Result<()> {
+        self.send(UploadItem::Block(layout::block_to_proto(block)))
+            .await
+    }
+
+    async fn send_descriptor(&self, descriptor: &model::Descriptor) -> anyhow::Result<()> {
+        self.forget_list();
+        self.send(UploadItem::Descriptor(layout::descriptor_to_proto(
+            descriptor,
+        )))
+        .await
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(PeerOp::Flush(tx)).await?;
+        rx.await?
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<(model::FileId, Vec<model::Version>)>> {
         let (tx, rx) = oneshot::channel();
-        self.tx.send(PeerOp::Send(data.bytes(), tx)).await?;
+        self.tx.send(PeerOp::List(tx)).await?;
         rx.await?
     }
+
+    async fn fetch_descriptor(
+        &self,
+        file_id: &model::FileId,
+        version: model::Version,
+        index: u16,
+    ) -> anyhow::Result<model::Descriptor> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(PeerOp::FetchDescriptor(file_id.clone(), version, index, tx))
+            .await?;
+        rx.await?
+    }
+
+    async fn fetch_block(
+        &self,
+        file_id: &model::FileId,
+        block_id: &model::BlockId,
+    ) -> anyhow::Result<model::Block> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(PeerOp::FetchBlock(file_id.clone(), block_id.clone(), tx))
+            .await?;
+        rx.await?
+    }
+
+    async fn prune(&self, files: FileList) -> anyhow::Result<u64> {
+        self.forget_list();
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(PeerOp::Prune(files, tx)).await?;
+        rx.await?
+    }
+}
+
+#[async_trait]
+impl restore::Repository for PeerImpl {
+    async fn files(&self) -> anyhow::Result<Vec<model::FileId>> {
+        let files = self.list().await?;
+        let file_ids = files.iter().map(|(file_id, _)| file_id.clone()).collect();
+        *self.listed.lock().unwrap() = Some(files.into_iter().collect());
+        Ok(file_ids)
+    }
+
+    async fn versions(&self, file_id: &model::FileId) -> anyhow::Result<Vec<model::Version>> {
+        if self.listed.lock().unwrap().is_none() {
+            self.files().await?;
+        }
+        self.listed
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|listed| listed.get(file_id).cloned())
+            .context("Unknown file")
+    }
+
+    async fn descriptor(
+        &self,
+        file_id: &model::FileId,
+        version: model::Version,
+        index: u16,
+    ) -> anyhow::Result<model::Descriptor> {
+        self.fetch_descriptor(file_id, version, index).await
+    }
+
+    async fn block(
+        &self,
+        file_id: &model::FileId,
+        block_id: &model::BlockId,
+    ) -> anyhow::Result<model::Block> {
+        self.fetch_block(file_id, block_id).await
+    }
 }
 
 impl PeerImpl {
+    fn forget_list(&self) {
+        *self.listed.lock().unwrap() = None;
+    }
+
+    async fn send(&self, data: UploadItem) -> anyhow::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(PeerOp::Send(data, tx)).await?;
+        rx.await?
+    }
+
     /// Returns a new Peer, with the given parameters.
     fn new<B, S, K>(broker: B, sink_builder: S, id: connection::Info, params: Params) -> PeerImpl
     where
@@ -58,6 +195,7 @@ impl PeerImpl {
             sink_builder,
             id,
             params,
+            abandoned: false,
             sink: PhantomData,
         };
         tokio::spawn(async move {
@@ -66,12 +204,31 @@ impl PeerImpl {
             }
         });
 
-        PeerImpl { tx }
+        PeerImpl {
+            tx,
+            listed: Mutex::new(None),
+        }
     }
 }
 
+pub type FileList = Vec<(model::FileId, Vec<model::Version>)>;
+
 enum PeerOp {
-    Send(Bytes, oneshot::Sender<Result<()>>),
+    Send(UploadItem, oneshot::Sender<Result<()>>),
+    Flush(oneshot::Sender<Result<()>>),
+    List(oneshot::Sender<Result<FileList>>),
+    FetchDescriptor(
+        model::FileId,
+        model::Version,
+        u16,
+        oneshot::Sender<Result<model::Descriptor>>,
+    ),
+    FetchBlock(
+        model::FileId,
+        model::BlockId,
+        oneshot::Sender<Result<model::Block>>,
+    ),
+    Prune(FileList, oneshot::Sender<Result<u64>>),
 }
 
 struct PeerActor<B, S, K>
@@ -85,6 +242,8 @@ where
     sink_builder: S,
     params: Params,
     id: connection::Info,
+    /// Whether data sent since the last flush was lost with the connection to the Sink.
+    abandoned: bool,
     sink: PhantomData<K>,
 }
 
@@ -122,22 +281,97 @@ where
 
     /// Serves requests on the given Sink. Returns true if there is no more work to do.
     async fn serve_with_sink(&mut self, sink: Arc<K>) -> ActorState<K> {
+        let mut upload = None;
         loop {
             match self.rx.recv().await {
                 Some(PeerOp::Send(data, tx)) => {
-                    let result = sink.store(&data).await.context("Sink error");
+                    // Data is pipelined over a single upload until the next flush.
+                    let result = match upload.take() {
+                        Some(current) => Ok(current),
+                        None => sink.upload().await.context("Sink error"),
+                    };
+                    let result = match result {
+                        Ok(current) => match current.items.send(data).await {
+                            Ok(()) => {
+                                upload = Some(current);
+                                Ok(())
+                            }
+                            // The upload stopped early, find out why.
+                            Err(_) => finish(current).await,
+                        },
+                        Err(err) => Err(err),
+                    };
 
                     // We might hide connection errors here to let the picker find the new location of the sink.
                     // TODO: only return on connection errors, surface other errors to the caller.
                     if result.is_err() {
-                        tracing::info!("Failed to send chunk: {:?}", result);
+                        tracing::info!("Failed to send data: {:?}", result);
                         return ActorState::Connecting;
                     }
                     if let Err(err) = tx.send(result) {
                         tracing::error!("Failed to send result to caller: {:?}", err);
                     }
                 }
+                Some(PeerOp::Flush(tx)) => {
+                    let result = match upload.take() {
+                        Some(current) => finish(current).await,
+                        None => Ok(()),
+                    };
+                    let failed = result.is_err();
+                    let result = match std::mem::take(&mut self.abandoned) {
+                        true => result.and(Err(anyhow!("Upload interrupted by a lost connection"))),
+                        false => result,
+                    };
+                    if let Err(err) = tx.send(result) {
+                        tracing::error!("Failed to send result to caller: {:?}", err);
+                    }
+                    if failed {
+                        return ActorState::Connecting;
+                    }
+                }
+                Some(PeerOp::List(tx)) => {
+                    let result = sink.list().await;
+                    if reply(tx, result, list_files) {
+                        return self.disconnect(upload);
+                    }
+                }
+                Some(PeerOp::FetchDescriptor(file_id, version, index, tx)) => {
+                    let result = sink
+                        .fetch_descriptor(file_id.as_bytes(), version, index.into())
+                        .await;
+                    if reply(tx, result, |pb| Ok(layout::descriptor_from_proto(pb))) {
+                        return self.disconnect(upload);
+                    }
+                }
+                Some(PeerOp::FetchBlock(file_id, block_id, tx)) => {
+                    let result = sink
+                        .fetch_blocks(file_id.as_bytes(), &[block_id.as_bytes().to_vec()])
+                        .await;
+                    if reply(tx, result, |mut pb| {
+                        Ok(layout::block_from_proto(pb.pop().context("Missing block")?))
+                    }) {
+                        return self.disconnect(upload);
+                    }
+                }
+                Some(PeerOp::Prune(files, tx)) => {
+                    let files = files
+                        .into_iter()
+                        .map(|(file_id, versions)| sink_client::StoredFile {
+                            file_id: file_id.as_bytes().to_vec(),
+                            version: versions,
+                        })
+                        .collect();
+                    let result = sink.prune(files).await;
+                    if reply(tx, result, Ok) {
+                        return self.disconnect(upload);
+                    }
+                }
                 None => {
+                    if let Some(current) = upload.take() {
+                        if let Err(err) = finish(current).await {
+                            tracing::error!("Failed to complete the upload: {:?}", err);
+                        }
+                    }
                     tracing::info!("Sink closed.");
                     return ActorState::Done;
                 }
@@ -145,6 +379,16 @@ where
         }
     }
 
+    /// Drops the connection to the Sink. The upload in progress, if any, is lost, which the next
+    /// flush reports.
+    fn disconnect(&mut self, upload: Option<Upload>) -> ActorState<K> {
+        if upload.is_some() {
+            tracing::warn!("Abandoning the upload in progress");
+            self.abandoned = true;
+        }
+        ActorState::Connecting
+    }
+
     /// Returns a Sink that can be used to send chunks to.
     async fn get_sink(&self) -> Arc<K> {
         let mut backoff: ExpBackoff = ExpBackoff::new(&self.params.backoff);
@@ -195,6 +439,39 @@ where
     }
 }
 
+/// Completes an upload, and waits until the sink has stored everything.
+async fn finish(upload: Upload) -> Result<()> {
+    drop(upload.items);
+    upload
+        .done
+        .await
+        .context("Upload interrupted")?
+        .context("Sink error")
+}
+
+/// Converts the result of a read from the sink and sends it to the caller. Unlike sending data,
+/// reads surface every error to the caller, as missing data is a normal outcome. Returns true if
+/// the sink is unavailable and a new one must be found.
+fn reply<T, U>(
+    tx: oneshot::Sender<Result<U>>,
+    result: tonic::Result<T>,
+    convert: impl FnOnce(T) -> Result<U>,
+) -> bool {
+    let unavailable = matches!(&result, Err(status) if status.code() == tonic::Code::Unavailable);
+    let result = result.context("Sink error").and_then(convert);
+    if let Err(err) = tx.send(result) {
+        tracing::error!("Failed to send result to caller: {:?}", err.err());
+    }
+    unavailable
+}
+
+fn list_files(files: Vec<sink_client::StoredFile>) -> Result<FileList> {
+    files
+        .into_iter()
+        .map(|file| Ok((file.file_id.as_slice().try_into()?, file.version)))
+        .collect()
+}
+
 /// Params are used to inject dependencies for testing.
 struct Params {
     backoff: Backoff,
@@ -267,11 +544,12 @@ mod tests {
             testcerts::broker_info(),
             Params::default(),
         );
-        let chunk = model::Chunk::new(Bytes::copy_from_slice([1, 2, 3].as_ref()), &[]);
+        let block = test_block();
 
         let start = tokio::time::Instant::now();
-        peer.send(&chunk).await.unwrap_err();
-        peer.send(&chunk).await?;
+        peer.send_block(&block).await.unwrap_err();
+        peer.send_block(&block).await?;
+        peer.flush().await?;
         let duration = tokio::time::Instant::now().duration_since(start);
 
         // The second call is not subject to waiting as we directly connect to the second peer.
@@ -346,6 +624,110 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn fetch_data() -> anyhow::Result<()> {
+        let mut mock_broker = MockBroker::new();
+        let mut mock_sink_builder = MockSinkBuilder::<MockSink>::new();
+        mock_broker
+            .expect_get_peers()
+            .returning(|| locations(&["1.2.3.4"]))
+            .times(1);
+        mock_sink_builder.expect_connect().returning(|_, _, _| {
+            let mut mock_sink = MockSink::new();
+            // Versions are looked up in the files listed.
+            mock_sink
+                .expect_list()
+                .returning(|| {
+                    Ok(vec![sink_client::StoredFile {
+                        file_id: vec![1; model::FILE_ID_LEN],
+                        version: vec![0, 2],
+                    }])
+                })
+                .times(2);
+            mock_sink
+                .expect_fetch_blocks()
+                .returning(|_, _| Ok(vec![]))
+                .times(1);
+            mock_sink
+                .expect_fetch_descriptor()
+                .returning(|_, _, _| Err(Status::not_found("missing")))
+                .times(1);
+            Ok(mock_sink)
+        });
+
+        let peer = PeerImpl::new(
+            mock_broker,
+            mock_sink_builder,
+            testcerts::broker_info(),
+            Params::default(),
+        );
+        let file_id = model::FileId::try_from([1; model::FILE_ID_LEN].as_slice())?;
+        assert_eq!(peer.list().await?, vec![(file_id.clone(), vec![0, 2])]);
+
+        // Errors are surfaced to the caller without losing the connection.
+        let block_id = model::BlockId::try_from([2; model::BLOCK_ID_LEN].as_slice())?;
+        assert!(peer.fetch_block(&file_id, &block_id).await.is_err());
+        assert!(peer.fetch_descriptor(&file_id, 2, 0).await.is_err());
+        assert_eq!(
+            restore::Repository::versions(&peer, &file_id).await?,
+            vec![0, 2]
+        );
+        assert_eq!(
+            restore::Repository::versions(&peer, &file_id).await?,
+            vec![0, 2]
+        );
+        let other = model::FileId::try_from([3; model::FILE_ID_LEN].as_slice())?;
+        assert!(restore::Repository::versions(&peer, &other).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn report_abandoned_uploads() -> anyhow::Result<()> {
+        let mut mock_broker = MockBroker::new();
+        let mut mock_sink_builder = MockSinkBuilder::<MockSink>::new();
+
+        // Setup: the first sink becomes unavailable while data is pending, the second works.
+        mock_broker
+            .expect_get_peers()
+            .returning(|| locations(&["1.2.3.4"]))
+            .times(1);
+        mock_broker
+            .expect_get_peers()
+            .returning(|| locations(&["5.6.7.8"]))
+            .times(1);
+        mock_sink_builder
+            .expect_connect()
+            .withf(move |_, id, addr| id == "sink" && addr == "1.2.3.4")
+            .returning(|connection, id, address| {
+                let mut mock_sink = good_sink(connection, id, address)?;
+                mock_sink
+                    .expect_list()
+                    .returning(|| Err(Status::unavailable("gone")))
+                    .times(1);
+                Ok(mock_sink)
+            });
+        mock_sink_builder
+            .expect_connect()
+            .withf(move |_, id, addr| id == "sink" && addr == "5.6.7.8")
+            .returning(good_sink);
+
+        let peer = PeerImpl::new(
+            mock_broker,
+            mock_sink_builder,
+            testcerts::broker_info(),
+            Params::default(),
+        );
+        let block = test_block();
+        peer.send_block(&block).await?;
+        peer.list().await.unwrap_err();
+        // The block sent before was never stored.
+        peer.flush().await.unwrap_err();
+
+        peer.send_block(&block).await?;
+        peer.flush().await?;
+        Ok(())
+    }
+
     /// Helper that generates a SinkLocation vector with the given addresses.
     fn locations(ips: &[&str]) -> Result<Arc<Vec<SinkLocation>>> {
         Ok(Arc::new(vec![SinkLocation::new(
@@ -357,7 +739,24 @@ mod tests {
     /// Helper that generates a Sink that will accept a single chunk.
     fn good_sink(_: &settings::connection::Info, _: &str, _: String) -> Result<MockSink> {
         let mut mock_sink = MockSink::new();
-        mock_sink.expect_store().returning(|_| Ok(())).times(1);
+        mock_sink
+            .expect_upload()
+            .returning(|| {
+                let (upload, mut items, done) = Upload::channel();
+                tokio::spawn(async move {
+                    let mut count = 0;
+                    while items.recv().await.is_some() {
+                        count += 1;
+                    }
+                    let result = match count {
+                        1 => Ok(()),
+                        _ => Err(Status::internal("unexpected items")),
+                    };
+                    let _ = done.send(result);
+                });
+                Ok(upload)
+            })
+            .times(1);
         Ok(mock_sink)
     }
 
@@ -365,13 +764,17 @@ mod tests {
     fn bad_sink(_: &settings::connection::Info, _: &str, _: String) -> Result<MockSink> {
         let mut mock_sink = MockSink::new();
         mock_sink
-            .expect_store()
-            .returning(|_| Err(Status::internal("boom")))
+            .expect_upload()
+            .returning(|| {
+                let (upload, _, done) = Upload::channel();
+                let _ = done.send(Err(Status::internal("boom")));
+                Ok(upload)
+            })
             .times(1);
         Ok(mock_sink)
     }
 
-    /// Helper that attempts to send a chunk to a Sink using the given mocks.
+    /// Helper that attempts to send a block to a Sink using the given mocks.
     async fn send_chunk(
         mock_broker: MockBroker,
         mock_sink_builder: MockSinkBuilder<MockSink>,
@@ -383,7 +786,15 @@ mod tests {
             Params::default(),
         );
 
-        let chunk = model::Chunk::new(Bytes::copy_from_slice([1, 2, 3].as_ref()), &[]);
-        peer.send(&chunk).await
+        peer.send_block(&test_block()).await?;
+        peer.flush().await
+    }
+
+    /// Helper that generates a block with arbitrary content.
+    fn test_block() -> model::Block {
+        model::Block {
+            verified: vec![1, 2, 3],
+            protected: Arc::new(vec![4, 5, 6]),
+        }
     }
 }
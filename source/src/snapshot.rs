@@ -0,0 +1,161 @@
+//! Snapshots group the file versions backed up by a pass of the Source. Their manifest is stored
+//! as the content of a reserved file, whose versions are the snapshots.
+
+use crate::restore::{self, Repository};
+use anyhow::{anyhow, Result};
+use crypto::model::{self, FileId, Manifest, Version};
+use std::collections::HashMap;
+
+/// Lists the snapshots available, in increasing order.
+pub async fn list<R: Repository + Sync + ?Sized>(repository: &R) -> Result<Vec<Version>> {
+    if !repository.files().await?.contains(&model::SNAPSHOTS) {
+        return Ok(vec![]);
+    }
+    repository.versions(&model::SNAPSHOTS).await
+}
+
+/// Fetches and decrypts the manifest of a snapshot.
+pub async fn read<R: Repository + Sync + ?Sized>(
+    repository: &R,
+    keys: &crypto::Keys,
+    version: Version,
+) -> Result<Manifest> {
+    let (protected, chunks) =
+        restore::read_version(repository, keys, &model::SNAPSHOTS, version).await?;
+    let mut data = vec![];
+    for chunk in chunks {
+        let block = repository.block(&chunk.file_id, &chunk.block_id).await?;
+        let (verified, protected) = keys.decrypt_block(&block)?;
+        if verified.file_id != chunk.file_id || verified.block_id != chunk.block_id {
+            return Err(anyhow!("Block stored at the wrong location"));
+        }
+        data.extend_from_slice(&protected.chunk);
+    }
+    if data.len() as u64 != protected.size {
+        return Err(anyhow!(
+            "Read {} bytes of manifest, expected {}",
+            data.len(),
+            protected.size
+        ));
+    }
+    Manifest::decode(&data)
+}
+
+/// Changes between two snapshots, as sorted original paths.
+#[derive(Debug, Default, PartialEq)]
+pub struct Diff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+/// Compares snapshot `to` with the earlier snapshot `from`.
+pub async fn diff<R: Repository + Sync + ?Sized>(
+    repository: &R,
+    keys: &crypto::Keys,
+    from: Version,
+    to: Version,
+) -> Result<Diff> {
+    let before: HashMap<FileId, Version> = read(repository, keys, from)
+        .await?
+        .files
+        .into_iter()
+        .collect();
+    let mut after: HashMap<FileId, Version> = read(repository, keys, to)
+        .await?
+        .files
+        .into_iter()
+        .collect();
+
+    let mut diff = Diff::default();
+    for (file_id, version) in before {
+        match after.remove(&file_id) {
+            None => diff
+                .removed
+                .push(filename(repository, keys, &file_id, version).await?),
+            Some(other) if other != version => diff
+                .changed
+                .push(filename(repository, keys, &file_id, other).await?),
+            Some(_) => {}
+        }
+    }
+    for (file_id, version) in after {
+        diff.added
+            .push(filename(repository, keys, &file_id, version).await?);
+    }
+    diff.added.sort();
+    diff.removed.sort();
+    diff.changed.sort();
+    Ok(diff)
+}
+
+/// Returns the original path of a file version, from its first descriptor.
+async fn filename<R: Repository + Sync + ?Sized>(
+    repository: &R,
+    keys: &crypto::Keys,
+    file_id: &FileId,
+    version: Version,
+) -> Result<String> {
+    let descriptor = repository.descriptor(file_id, version, 0).await?;
+    Ok(keys.decrypt_descriptor(&descriptor)?.1.filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::RandomApi;
+    use storage::testing::Fixture;
+
+    #[tokio::test]
+    async fn list_and_read() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let mut fixture = Fixture::new(tmpdir.path())?;
+        fixture.max_refs = 1;
+        assert!(list(&fixture.root).await?.is_empty());
+
+        let manifest = Manifest {
+            time: 1234,
+            hostname: "host".to_string(),
+            roots: vec!["/home".to_string()],
+            files: (0..1000)
+                .map(|version| Ok((fixture.rnd.generate_file_id()?, version)))
+                .collect::<Result<_>>()?,
+        };
+        fixture.manifest(3, &manifest)?;
+        fixture.manifest(5, &Manifest::default())?;
+
+        assert_eq!(list(&fixture.root).await?, vec![3, 5]);
+        assert_eq!(read(&fixture.root, &fixture.keys, 3).await?, manifest);
+        assert_eq!(
+            read(&fixture.root, &fixture.keys, 5).await?,
+            Manifest::default()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn diff_snapshots() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let fixture = Fixture::new(tmpdir.path())?;
+        let same = fixture.add("same", 0, &[])?;
+        let changed = fixture.add("changed", 0, &[])?;
+        fixture.add_version(&changed, "changed", 1, &[])?;
+        let removed = fixture.add("removed", 0, &[])?;
+        let added = fixture.add("added", 0, &[])?;
+        fixture.snapshot(
+            0,
+            vec![(same.clone(), 0), (changed.clone(), 0), (removed, 0)],
+        )?;
+        fixture.snapshot(1, vec![(added, 0), (changed, 1), (same, 0)])?;
+
+        assert_eq!(
+            diff(&fixture.root, &fixture.keys, 0, 1).await?,
+            Diff {
+                added: vec!["added".to_string()],
+                removed: vec!["removed".to_string()],
+                changed: vec!["changed".to_string()],
+            }
+        );
+        Ok(())
+    }
+}
@@ -1,9 +1,13 @@
 use anyhow::{Context, Result};
 use crypto::model::{self, FileId};
 use crypto::{self, RandomApi};
-use rusqlite::{params, Connection};
-use std::{path::Path, sync::Arc};
-use storage::filesystem::ShallowInfo;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use storage::filesystem::{ShallowInfo, Stat};
 use tokio::{
     sync::{
         mpsc::{self, Receiver, Sender},
@@ -18,13 +22,19 @@ pub struct Store {
     tx: Sender<StateOp>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Partial {
     pub file_id: model::FileId,
     pub version: model::Version,
     pub len: u64,
 }
 
+/// Chunks of a file version, in order, as their digest and the block holding them.
+pub type Content = Vec<(Vec<u8>, model::BlockRef)>;
+
+/// Digest of the content held by blocks, by the file and id of each block.
+pub type Digests = HashMap<(FileId, model::BlockId), Vec<u8>>;
+
 /// Whether a change took place in the file or not.  
 #[derive(Debug, PartialEq)]
 pub enum Change {
@@ -68,6 +78,158 @@ impl Store {
         rx.await.context("failed to get result")?
     }
 
+    /// Returns the id of a file, allocating one if the file is new.
+    pub async fn file_id(&self, path: &Path) -> anyhow::Result<FileId> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(StateOp::FileId(path.to_owned(), tx))
+            .await
+            .context("failed to send command")?;
+        rx.await.context("failed to get result")?
+    }
+
+    /// Updates the metadata of a stored version, once its content was found to be unchanged.
+    pub async fn refresh(&self, partial: &Partial, info: &ShallowInfo) -> anyhow::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(StateOp::Refresh(partial.clone(), info.clone(), tx))
+            .await
+            .context("failed to send command")?;
+        rx.await.context("failed to get result")?
+    }
+
+    /// Returns the block already holding content with this digest, if any.
+    pub async fn lookup_block(&self, digest: &[u8]) -> anyhow::Result<Option<model::BlockRef>> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(StateOp::LookupBlock(digest.to_vec(), tx))
+            .await
+            .context("failed to send command")?;
+        rx.await.context("failed to get result")?
+    }
+
+    /// Records blocks stored on the Sink, so that later chunks with the same digest reuse them.
+    pub async fn record_blocks(
+        &self,
+        blocks: Vec<(Vec<u8>, model::BlockRef)>,
+    ) -> anyhow::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(StateOp::RecordBlocks(blocks, tx))
+            .await
+            .context("failed to send command")?;
+        rx.await.context("failed to get result")?
+    }
+
+    /// Returns the chunks of a stored file version, and its details.
+    pub async fn content(
+        &self,
+        file_id: &FileId,
+        version: model::Version,
+    ) -> anyhow::Result<(Content, Vec<u8>)> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(StateOp::Content(file_id.clone(), version, tx))
+            .await
+            .context("failed to send command")?;
+        rx.await.context("failed to get result")?
+    }
+
+    /// Records the chunks of a file version stored on the Sink, in order, along with its
+    /// details: everything else describing the version, as an opaque value to compare with the
+    /// next versions.
+    pub async fn record_content(
+        &self,
+        file_id: &FileId,
+        version: model::Version,
+        content: Content,
+        details: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(StateOp::RecordContent(
+                file_id.clone(),
+                version,
+                content,
+                details,
+                tx,
+            ))
+            .await
+            .context("failed to send command")?;
+        rx.await.context("failed to get result")?
+    }
+
+    /// Returns the paths of the other files whose latest version was stored with the same inode,
+    /// in order. They may have changed since.
+    pub async fn links(&self, file_id: &FileId, stat: &Stat) -> anyhow::Result<Vec<PathBuf>> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(StateOp::Links(file_id.clone(), stat.clone(), tx))
+            .await
+            .context("failed to send command")?;
+        rx.await.context("failed to get result")?
+    }
+
+    /// Returns the paths of the known files at or under `path`, in order.
+    pub async fn paths_under(&self, path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(StateOp::PathsUnder(path.to_owned(), tx))
+            .await
+            .context("failed to send command")?;
+        rx.await.context("failed to get result")?
+    }
+
+    /// Returns the latest stored version of each file, skipping the files never stored.
+    pub async fn stored_versions(
+        &self,
+        paths: Vec<PathBuf>,
+    ) -> anyhow::Result<Vec<(FileId, model::Version)>> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(StateOp::StoredVersions(paths, tx))
+            .await
+            .context("failed to send command")?;
+        rx.await.context("failed to get result")?
+    }
+
+    /// Returns the digest of the content of every block referenced by a stored file version.
+    pub async fn digests(&self) -> anyhow::Result<Digests> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(StateOp::Digests(tx))
+            .await
+            .context("failed to send command")?;
+        rx.await.context("failed to get result")?
+    }
+
+    /// Reserves the number of a new snapshot, started at `time`. Numbers are never reused.
+    pub async fn new_snapshot(&self, time: i64) -> anyhow::Result<model::Version> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(StateOp::NewSnapshot(time, tx))
+            .await
+            .context("failed to send command")?;
+        rx.await.context("failed to get result")?
+    }
+
+    /// Forgets pruned file versions and snapshots. The latest version of a file is only cleared,
+    /// so that its number is never reused, and the file is sent again if it reappears. The latest
+    /// snapshot is kept for the same reason. Blocks no remaining version references are forgotten
+    /// too, so that they are never reused.
+    pub async fn forget(
+        &self,
+        versions: Vec<(FileId, model::Version)>,
+        snapshots: Vec<model::Version>,
+    ) -> anyhow::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(StateOp::Forget(versions, snapshots, tx))
+            .await
+            .context("failed to send command")?;
+        rx.await.context("failed to get result")?
+    }
+
     /// Creates a new in-memory Store.
     #[cfg(test)]
     pub async fn new_for_test(rnd: Arc<dyn RandomApi + Send + Sync>) -> anyhow::Result<Self> {
@@ -92,6 +254,22 @@ impl Store {
     }
 }
 
+/// Changes to the schema, in order. The database records how many were applied as its
+/// user_version.
+const MIGRATIONS: &[&str] = &[
+    // Metadata of the file when each version was stored, see Stat. Versions stored before are
+    // then always found changed, and their content checked again.
+    "
+    ALTER TABLE File ADD COLUMN mtime  INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE File ADD COLUMN ctime  INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE File ADD COLUMN inode  INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE File ADD COLUMN device INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE File ADD COLUMN mode   INTEGER NOT NULL DEFAULT 0;",
+    // Everything else describing each version, see Store::record_content.
+    "
+    ALTER TABLE File ADD COLUMN details BLOB NOT NULL DEFAULT x'';",
+];
+
 struct StoreRunner {
     db: Connection,
     rnd: Arc<dyn RandomApi + Send + Sync>,
@@ -113,7 +291,13 @@ impl StoreRunner {
         CREATE UNIQUE INDEX IF NOT EXISTS idx_file_path ON FileId(path);",
             (),
         )?;
+        // The id storing snapshots is reserved, without a path.
+        self.db.execute(
+            "INSERT OR IGNORE INTO FileId(id, path) VALUES(?1, NULL)",
+            (model::SNAPSHOTS.as_bytes(),),
+        )?;
 
+        // Columns added later come from MIGRATIONS.
         self.db.execute(
             "
         CREATE TABLE IF NOT EXISTS File (
@@ -124,6 +308,44 @@ impl StoreRunner {
         ) STRICT, WITHOUT ROWID;",
             (),
         )?;
+        self.migrate()?;
+
+        // Blocks known to be stored on the Sink, by digest of their content.
+        self.db.execute(
+            "
+        CREATE TABLE IF NOT EXISTS Block (
+            digest   BLOB PRIMARY KEY,
+            file_id  BLOB NOT NULL,
+            block_id BLOB NOT NULL
+        ) STRICT, WITHOUT ROWID;",
+            (),
+        )?;
+
+        // Ordered chunks of each file version, and the blocks holding them.
+        self.db.execute(
+            "
+        CREATE TABLE IF NOT EXISTS Content (
+            id       BLOB NOT NULL,
+            version  INTEGER NOT NULL,
+            position INTEGER NOT NULL,
+            digest   BLOB NOT NULL,
+            file_id  BLOB NOT NULL,
+            block_id BLOB NOT NULL,
+            PRIMARY KEY (id, version, position)
+        ) STRICT, WITHOUT ROWID;",
+            (),
+        )?;
+
+        // Snapshots taken, numbered as the versions of the reserved file storing them.
+        self.db.execute(
+            "
+        CREATE TABLE IF NOT EXISTS Snapshot (
+            version INTEGER PRIMARY KEY,
+            -- Start of the pass, in nanoseconds since the epoch.
+            time    INTEGER NOT NULL
+        ) STRICT;",
+            (),
+        )?;
 
         loop {
             match rx.blocking_recv() {
@@ -136,21 +358,85 @@ impl StoreRunner {
                 Some(StateOp::Insert(info, tx)) => {
                     tx.send(self.insert(&info)).unwrap();
                 }
+
+                Some(StateOp::FileId(path, tx)) => {
+                    tx.send(self.file_id(&path)).unwrap();
+                }
+
+                Some(StateOp::Refresh(partial, info, tx)) => {
+                    tx.send(self.refresh(&partial, &info)).unwrap();
+                }
+
+                Some(StateOp::LookupBlock(digest, tx)) => {
+                    tx.send(self.lookup_block(&digest)).unwrap();
+                }
+
+                Some(StateOp::RecordBlocks(blocks, tx)) => {
+                    tx.send(self.record_blocks(&blocks)).unwrap();
+                }
+
+                Some(StateOp::Content(file_id, version, tx)) => {
+                    tx.send(self.content(&file_id, version)).unwrap();
+                }
+
+                Some(StateOp::RecordContent(file_id, version, content, details, tx)) => {
+                    tx.send(self.record_content(&file_id, version, &content, &details))
+                        .unwrap();
+                }
+
+                Some(StateOp::Links(file_id, stat, tx)) => {
+                    tx.send(self.links(&file_id, &stat)).unwrap();
+                }
+
+                Some(StateOp::PathsUnder(path, tx)) => {
+                    tx.send(self.paths_under(&path)).unwrap();
+                }
+
+                Some(StateOp::StoredVersions(paths, tx)) => {
+                    tx.send(self.stored_versions(&paths)).unwrap();
+                }
+
+                Some(StateOp::Digests(tx)) => {
+                    tx.send(self.digests()).unwrap();
+                }
+
+                Some(StateOp::NewSnapshot(time, tx)) => {
+                    tx.send(self.new_snapshot(time)).unwrap();
+                }
+
+                Some(StateOp::Forget(versions, snapshots, tx)) => {
+                    tx.send(self.forget(&versions, &snapshots)).unwrap();
+                }
             }
         }
         Ok(())
     }
 
+    /// Brings the schema up to date, applying the migrations the database has not seen yet.
+    fn migrate(&mut self) -> anyhow::Result<()> {
+        let tx = self.db.transaction()?;
+        let applied: usize = tx.query_row("PRAGMA user_version", (), |row| row.get(0))?;
+        for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+            tx.execute_batch(migration)
+                .with_context(|| format!("failed to migrate to schema version {}", version + 1))?;
+        }
+        if applied < MIGRATIONS.len() {
+            tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
     fn shallow_check(&mut self, info: &ShallowInfo) -> anyhow::Result<Change> {
         let tx = self.db.transaction()?;
         let file_id = get_file_id(self.rnd.as_ref(), info.file(), &tx)?;
-        let partial = lookup_file(&file_id, &tx)?;
+        let stored = lookup_file(&file_id, &tx)?;
         tx.commit()?;
 
-        match partial {
+        match stored {
             None => Ok(Change::Changed(None)),
-            Some(partial) => {
-                if partial.len != info.len() {
+            Some((partial, stat)) => {
+                if partial.len != info.len() || &stat != info.stat() {
                     Ok(Change::Changed(Some(partial)))
                 } else {
                     Ok(Change::Unchanged(partial))
@@ -159,19 +445,37 @@ impl StoreRunner {
         }
     }
 
+    fn file_id(&mut self, path: &Path) -> anyhow::Result<FileId> {
+        let tx = self.db.transaction()?;
+        let file_id = get_file_id(self.rnd.as_ref(), path, &tx)?;
+        tx.commit()?;
+        Ok(file_id)
+    }
+
     fn insert(&mut self, info: &ShallowInfo) -> anyhow::Result<Partial> {
         let tx = self.db.transaction()?;
         let file_id = get_file_id(self.rnd.as_ref(), info.file(), &tx)?;
 
         let version = match lookup_file(&file_id, &tx)? {
             None => 0,
-            Some(partial) => partial.version + 1,
+            Some((partial, _)) => partial.version + 1,
         };
+        let stat = info.stat();
         tx.execute(
             "
-        INSERT INTO File(id, version, len) VALUES(?1, ?2, ?3) 
+        INSERT INTO File(id, version, len, mtime, ctime, inode, device, mode)
+        VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
         ",
-            (file_id.as_bytes(), version, info.len()),
+            (
+                file_id.as_bytes(),
+                version,
+                info.len(),
+                stat.mtime,
+                stat.ctime,
+                stat.inode as i64,
+                stat.device as i64,
+                stat.mode,
+            ),
         )?;
         tx.commit()?;
         Ok(Partial {
@@ -180,6 +484,264 @@ impl StoreRunner {
             len: info.len(),
         })
     }
+
+    fn refresh(&mut self, partial: &Partial, info: &ShallowInfo) -> anyhow::Result<()> {
+        let stat = info.stat();
+        let updated = self.db.execute(
+            "
+        UPDATE File SET mtime = ?3, ctime = ?4, inode = ?5, device = ?6, mode = ?7
+        WHERE id = ?1 AND version = ?2
+        ",
+            (
+                partial.file_id.as_bytes(),
+                partial.version,
+                stat.mtime,
+                stat.ctime,
+                stat.inode as i64,
+                stat.device as i64,
+                stat.mode,
+            ),
+        )?;
+        match updated {
+            1 => Ok(()),
+            _ => Err(anyhow::anyhow!("Unknown version {:?}", partial)),
+        }
+    }
+
+    fn lookup_block(&mut self, digest: &[u8]) -> anyhow::Result<Option<model::BlockRef>> {
+        let mut stmt = self
+            .db
+            .prepare_cached("SELECT file_id, block_id FROM Block WHERE digest = ?1")?;
+        let mut rows = stmt.query_map((digest,), |row| {
+            Ok((row.get::<usize, Vec<u8>>(0)?, row.get::<usize, Vec<u8>>(1)?))
+        })?;
+        match rows.next() {
+            None => Ok(None),
+            Some(row) => {
+                let (file_id, block_id) = row?;
+                Ok(Some(model::BlockRef {
+                    file_id: file_id.as_slice().try_into()?,
+                    block_id: block_id.as_slice().try_into()?,
+                }))
+            }
+        }
+    }
+
+    fn record_blocks(&mut self, blocks: &[(Vec<u8>, model::BlockRef)]) -> anyhow::Result<()> {
+        let tx = self.db.transaction()?;
+        {
+            // Keep the first block recorded for a given content.
+            let mut stmt = tx.prepare(
+                "INSERT OR IGNORE INTO Block(digest, file_id, block_id) VALUES(?1, ?2, ?3)",
+            )?;
+            for (digest, block) in blocks {
+                stmt.execute((digest, block.file_id.as_bytes(), block.block_id.as_bytes()))?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn content(
+        &mut self,
+        file_id: &FileId,
+        version: model::Version,
+    ) -> anyhow::Result<(Content, Vec<u8>)> {
+        let mut stmt = self.db.prepare_cached(
+            "SELECT digest, file_id, block_id FROM Content
+            WHERE id = ?1 AND version = ?2 ORDER BY position",
+        )?;
+        let rows = stmt.query_map((file_id.as_bytes(), version), |row| {
+            Ok((
+                row.get::<usize, Vec<u8>>(0)?,
+                row.get::<usize, Vec<u8>>(1)?,
+                row.get::<usize, Vec<u8>>(2)?,
+            ))
+        })?;
+        let mut content = vec![];
+        for row in rows {
+            let (digest, block_file_id, block_id) = row?;
+            content.push((
+                digest,
+                model::BlockRef {
+                    file_id: block_file_id.as_slice().try_into()?,
+                    block_id: block_id.as_slice().try_into()?,
+                },
+            ));
+        }
+        let details = self
+            .db
+            .prepare_cached("SELECT details FROM File WHERE id = ?1 AND version = ?2")?
+            .query_row((file_id.as_bytes(), version), |row| row.get(0))
+            .optional()?;
+        Ok((content, details.unwrap_or_default()))
+    }
+
+    fn record_content(
+        &mut self,
+        file_id: &FileId,
+        version: model::Version,
+        content: &[(Vec<u8>, model::BlockRef)],
+        details: &[u8],
+    ) -> anyhow::Result<()> {
+        let tx = self.db.transaction()?;
+        let updated = tx.execute(
+            "UPDATE File SET details = ?3 WHERE id = ?1 AND version = ?2",
+            (file_id.as_bytes(), version, details),
+        )?;
+        if updated != 1 {
+            return Err(anyhow::anyhow!("Unknown version {}", version));
+        }
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO Content(id, version, position, digest, file_id, block_id)
+                VALUES(?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for (position, (digest, block)) in content.iter().enumerate() {
+                stmt.execute((
+                    file_id.as_bytes(),
+                    version,
+                    position as u64,
+                    digest,
+                    block.file_id.as_bytes(),
+                    block.block_id.as_bytes(),
+                ))?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn links(&mut self, file_id: &FileId, stat: &Stat) -> anyhow::Result<Vec<PathBuf>> {
+        let mut stmt = self.db.prepare_cached(
+            "SELECT FileId.path FROM File JOIN FileId ON FileId.id = File.id
+            WHERE File.inode = ?1 AND File.device = ?2 AND File.id != ?3
+            AND File.version = (SELECT MAX(version) FROM File AS Latest WHERE Latest.id = File.id)
+            ORDER BY FileId.path",
+        )?;
+        let rows = stmt.query_map(
+            (stat.inode as i64, stat.device as i64, file_id.as_bytes()),
+            |row| row.get::<usize, Vec<u8>>(0),
+        )?;
+        let mut paths = vec![];
+        for row in rows {
+            paths.push(path_from_repr(row?));
+        }
+        Ok(paths)
+    }
+
+    fn paths_under(&mut self, path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let mut stmt = self
+            .db
+            .prepare_cached("SELECT path FROM FileId WHERE path IS NOT NULL ORDER BY path")?;
+        let rows = stmt.query_map((), |row| row.get::<usize, Vec<u8>>(0))?;
+        let mut paths = vec![];
+        for row in rows {
+            let known = path_from_repr(row?);
+            if known.starts_with(path) {
+                paths.push(known);
+            }
+        }
+        Ok(paths)
+    }
+
+    fn stored_versions(
+        &mut self,
+        paths: &[PathBuf],
+    ) -> anyhow::Result<Vec<(FileId, model::Version)>> {
+        // Versions are only described once stored on the Sink.
+        let mut stmt = self.db.prepare_cached(
+            "SELECT File.id, MAX(File.version) FROM FileId JOIN File ON File.id = FileId.id
+            WHERE FileId.path = ?1 AND File.details != x'' GROUP BY File.id",
+        )?;
+        let mut versions = vec![];
+        for path in paths {
+            let row = stmt
+                .query_row((canonical_path_repr(path),), |row| {
+                    Ok((row.get::<usize, Vec<u8>>(0)?, row.get(1)?))
+                })
+                .optional()?;
+            if let Some((file_id, version)) = row {
+                versions.push((file_id.as_slice().try_into()?, version));
+            }
+        }
+        Ok(versions)
+    }
+
+    fn digests(&mut self) -> anyhow::Result<Digests> {
+        let mut stmt = self
+            .db
+            .prepare_cached("SELECT DISTINCT file_id, block_id, digest FROM Content")?;
+        let rows = stmt.query_map((), |row| {
+            Ok((
+                row.get::<usize, Vec<u8>>(0)?,
+                row.get::<usize, Vec<u8>>(1)?,
+                row.get::<usize, Vec<u8>>(2)?,
+            ))
+        })?;
+        let mut digests = Digests::new();
+        for row in rows {
+            let (file_id, block_id, digest) = row?;
+            digests.insert(
+                (
+                    file_id.as_slice().try_into()?,
+                    block_id.as_slice().try_into()?,
+                ),
+                digest,
+            );
+        }
+        Ok(digests)
+    }
+
+    fn new_snapshot(&mut self, time: i64) -> anyhow::Result<model::Version> {
+        Ok(self.db.query_row(
+            "INSERT INTO Snapshot(version, time)
+            VALUES((SELECT COALESCE(MAX(version) + 1, 0) FROM Snapshot), ?1)
+            RETURNING version",
+            (time,),
+            |row| row.get(0),
+        )?)
+    }
+
+    fn forget(
+        &mut self,
+        versions: &[(FileId, model::Version)],
+        snapshots: &[model::Version],
+    ) -> anyhow::Result<()> {
+        let tx = self.db.transaction()?;
+        {
+            let mut delete = tx.prepare(
+                "DELETE FROM File WHERE id = ?1 AND version = ?2
+                AND version < (SELECT MAX(version) FROM File AS Latest WHERE Latest.id = ?1)",
+            )?;
+            // The metadata can't match any file, so that it is checked again.
+            let mut clear = tx.prepare(
+                "UPDATE File SET details = x'', mtime = 0, ctime = 0, inode = 0, device = 0, mode = 0
+                WHERE id = ?1 AND version = ?2",
+            )?;
+            let mut content = tx.prepare("DELETE FROM Content WHERE id = ?1 AND version = ?2")?;
+            for (file_id, version) in versions {
+                if delete.execute((file_id.as_bytes(), version))? == 0 {
+                    clear.execute((file_id.as_bytes(), version))?;
+                }
+                content.execute((file_id.as_bytes(), version))?;
+            }
+            let mut snapshot = tx.prepare(
+                "DELETE FROM Snapshot WHERE version = ?1
+                AND version < (SELECT MAX(version) FROM Snapshot)",
+            )?;
+            for version in snapshots {
+                snapshot.execute((version,))?;
+            }
+        }
+        tx.execute(
+            "DELETE FROM Block WHERE NOT EXISTS (SELECT 1 FROM Content
+            WHERE Content.file_id = Block.file_id AND Content.block_id = Block.block_id)",
+            (),
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
 }
 
 fn get_file_id(
@@ -219,18 +781,30 @@ fn get_file_id(
     Ok(id)
 }
 
+/// Returns the latest version of a file, along with its metadata at the time.
 fn lookup_file(
     file_id: &model::FileId,
     tx: &rusqlite::Transaction,
-) -> anyhow::Result<Option<Partial>> {
-    let mut stmt =
-        tx.prepare("SELECT version, len FROM File WHERE id = ?1 ORDER BY version DESC LIMIT 1")?;
+) -> anyhow::Result<Option<(Partial, Stat)>> {
+    let mut stmt = tx.prepare(
+        "SELECT version, len, mtime, ctime, inode, device, mode FROM File
+        WHERE id = ?1 ORDER BY version DESC LIMIT 1",
+    )?;
     let mut rows = stmt.query_map((file_id.as_bytes(),), |row| {
-        Ok(Partial {
-            file_id: file_id.clone(),
-            version: row.get(0)?,
-            len: row.get(1)?,
-        })
+        Ok((
+            Partial {
+                file_id: file_id.clone(),
+                version: row.get(0)?,
+                len: row.get(1)?,
+            },
+            Stat {
+                mtime: row.get(2)?,
+                ctime: row.get(3)?,
+                inode: row.get::<usize, i64>(4)? as u64,
+                device: row.get::<usize, i64>(5)? as u64,
+                mode: row.get(6)?,
+            },
+        ))
     })?;
 
     if let Some(row) = rows.next() {
@@ -244,6 +818,41 @@ fn lookup_file(
 enum StateOp {
     Insert(ShallowInfo, oneshot::Sender<anyhow::Result<Partial>>),
     ShallowCheck(ShallowInfo, oneshot::Sender<anyhow::Result<Change>>),
+    FileId(PathBuf, oneshot::Sender<anyhow::Result<FileId>>),
+    Refresh(Partial, ShallowInfo, oneshot::Sender<anyhow::Result<()>>),
+    LookupBlock(
+        Vec<u8>,
+        oneshot::Sender<anyhow::Result<Option<model::BlockRef>>>,
+    ),
+    RecordBlocks(
+        Vec<(Vec<u8>, model::BlockRef)>,
+        oneshot::Sender<anyhow::Result<()>>,
+    ),
+    Content(
+        FileId,
+        model::Version,
+        oneshot::Sender<anyhow::Result<(Content, Vec<u8>)>>,
+    ),
+    RecordContent(
+        FileId,
+        model::Version,
+        Content,
+        Vec<u8>,
+        oneshot::Sender<anyhow::Result<()>>,
+    ),
+    Links(FileId, Stat, oneshot::Sender<anyhow::Result<Vec<PathBuf>>>),
+    PathsUnder(PathBuf, oneshot::Sender<anyhow::Result<Vec<PathBuf>>>),
+    StoredVersions(
+        Vec<PathBuf>,
+        oneshot::Sender<anyhow::Result<Vec<(FileId, model::Version)>>>,
+    ),
+    Digests(oneshot::Sender<anyhow::Result<Digests>>),
+    NewSnapshot(i64, oneshot::Sender<anyhow::Result<model::Version>>),
+    Forget(
+        Vec<(FileId, model::Version)>,
+        Vec<model::Version>,
+        oneshot::Sender<anyhow::Result<()>>,
+    ),
 }
 
 #[cfg(windows)]
@@ -268,6 +877,22 @@ fn canonical_path_repr(path: &Path) -> Vec<u8> {
     canonical
 }
 
+#[cfg(windows)]
+fn path_from_repr(canonical: Vec<u8>) -> PathBuf {
+    use std::os::windows::ffi::OsStringExt;
+    let wide: Vec<u16> = canonical
+        .chunks_exact(2)
+        .map(|pair| u16::from(pair[0]) | (u16::from(pair[1]) << 8))
+        .collect();
+    std::ffi::OsString::from_wide(&wide).into()
+}
+
+#[cfg(unix)]
+fn path_from_repr(canonical: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    std::ffi::OsString::from_vec(canonical).into()
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::Mutex;
@@ -327,6 +952,57 @@ mod tests {
         db.shutdown().await
     }
 
+    #[tokio::test]
+    async fn migrate_baseline_schema() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("state.db");
+        {
+            let db = Connection::open(&path)?;
+            db.execute_batch(
+                "
+            CREATE TABLE FileId (id BLOB PRIMARY KEY, path BLOB) STRICT, WITHOUT ROWID;
+            CREATE UNIQUE INDEX idx_file_path ON FileId(path);
+            CREATE TABLE File (
+                id      BLOB NOT NULL,
+                version INTEGER NOT NULL,
+                len     INTEGER NOT NULL,
+                PRIMARY KEY (id, version)
+            ) STRICT, WITHOUT ROWID;",
+            )?;
+            db.execute(
+                "INSERT INTO FileId(id, path) VALUES(?1, ?2)",
+                (FileId::default().as_bytes(), b"a/b".as_slice()),
+            )?;
+            db.execute(
+                "INSERT INTO File(id, version, len) VALUES(?1, 0, 100)",
+                (FileId::default().as_bytes(),),
+            )?;
+        }
+
+        let rnd = Arc::new(TestRandom::new(vec![]));
+        let db = Store::new(&path, rnd.clone()).await?;
+        let file = ShallowInfo::new(Path::new("a/b").to_owned(), 100).with_stat(Stat {
+            mtime: 1,
+            ..Default::default()
+        });
+        let stored = Partial {
+            file_id: FileId::default(),
+            version: 0,
+            len: 100,
+        };
+        assert_eq!(
+            db.check_shallow_change(&file).await?,
+            Change::Changed(Some(stored))
+        );
+        assert_eq!(db.insert(&file).await?.version, 1);
+        db.shutdown().await?;
+
+        // Opening the database again applies nothing.
+        let db = Store::new(&path, rnd).await?;
+        assert_eq!(db.insert(&file).await?.version, 2);
+        db.shutdown().await
+    }
+
     #[tokio::test]
     async fn insert_new_version() -> anyhow::Result<()> {
         let rnd = Arc::new(TestRandom::new(vec![0]));
@@ -351,6 +1027,57 @@ mod tests {
         db.shutdown().await
     }
 
+    #[tokio::test]
+    async fn detect_metadata_changes() -> anyhow::Result<()> {
+        let rnd = Arc::new(TestRandom::new(vec![0]));
+        let db = Store::new_for_test(rnd).await.expect("creation failed");
+
+        let stat = Stat {
+            mtime: 1,
+            ctime: 2,
+            inode: u64::MAX,
+            device: 4,
+            mode: 0o100644,
+        };
+        let file = ShallowInfo::new(Path::new("a/b").to_owned(), 100).with_stat(stat.clone());
+        let partial = db.insert(&file).await?;
+        assert_eq!(
+            db.check_shallow_change(&file).await?,
+            Change::Unchanged(partial.clone())
+        );
+
+        // Same length, but any other difference is a change.
+        let edits: [fn(&mut Stat); 5] = [
+            |stat| stat.mtime += 1,
+            |stat| stat.ctime += 1,
+            |stat| stat.inode = 3,
+            |stat| stat.device += 1,
+            |stat| stat.mode = 0o100600,
+        ];
+        for edit in edits {
+            let mut edited = stat.clone();
+            edit(&mut edited);
+            let info = ShallowInfo::new(Path::new("a/b").to_owned(), 100).with_stat(edited);
+            assert_eq!(
+                db.check_shallow_change(&info).await?,
+                Change::Changed(Some(partial.clone()))
+            );
+        }
+
+        // Refreshing the stored metadata makes the file unchanged again.
+        let mut touched = stat.clone();
+        touched.ctime += 1;
+        let info = ShallowInfo::new(Path::new("a/b").to_owned(), 100).with_stat(touched);
+        db.refresh(&partial, &info).await?;
+        assert_eq!(
+            db.check_shallow_change(&info).await?,
+            Change::Unchanged(partial)
+        );
+        assert_eq!(db.file_id(Path::new("a/b")).await?, FileId::default());
+
+        db.shutdown().await
+    }
+
     #[tokio::test]
     async fn file_id_collision() -> anyhow::Result<()> {
         // Pop the same value twice, then a different one. This should cause one retry for the
@@ -382,4 +1109,195 @@ mod tests {
         );
         db.shutdown().await
     }
+
+    #[tokio::test]
+    async fn record_blocks() -> anyhow::Result<()> {
+        let rnd = Arc::new(TestRandom::new(vec![]));
+        let db = Store::new_for_test(rnd).await.expect("creation failed");
+
+        let block = |file: u8, block: u8| -> Result<model::BlockRef> {
+            Ok(model::BlockRef {
+                file_id: FileId::try_from([file; 6].as_slice())?,
+                block_id: model::BlockId::try_from([block; 12].as_slice())?,
+            })
+        };
+        assert_eq!(db.lookup_block(b"digest").await?, None);
+
+        db.record_blocks(vec![(b"digest".to_vec(), block(1, 1)?)])
+            .await?;
+        assert_eq!(db.lookup_block(b"digest").await?, Some(block(1, 1)?));
+
+        // The first block stays in use for the same content.
+        db.record_blocks(vec![
+            (b"digest".to_vec(), block(2, 2)?),
+            (b"other".to_vec(), block(2, 3)?),
+        ])
+        .await?;
+        assert_eq!(db.lookup_block(b"digest").await?, Some(block(1, 1)?));
+        assert_eq!(db.lookup_block(b"other").await?, Some(block(2, 3)?));
+
+        db.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn record_content() -> anyhow::Result<()> {
+        let rnd = Arc::new(TestRandom::new(vec![1]));
+        let db = Store::new_for_test(rnd).await.expect("creation failed");
+
+        let file_id = FileId::try_from([1; 6].as_slice())?;
+        let file = ShallowInfo::new(Path::new("a/b").to_owned(), 100);
+        assert!(db
+            .record_content(&file_id, 0, vec![], vec![])
+            .await
+            .is_err());
+        db.insert(&file).await?;
+        let chunk = |digest: &[u8], block: u8| -> Result<(Vec<u8>, model::BlockRef)> {
+            Ok((
+                digest.to_vec(),
+                model::BlockRef {
+                    file_id: FileId::try_from([block; 6].as_slice())?,
+                    block_id: model::BlockId::try_from([block; 12].as_slice())?,
+                },
+            ))
+        };
+        // The same content may appear several times in a version.
+        let content = vec![chunk(b"b", 2)?, chunk(b"a", 1)?, chunk(b"b", 2)?];
+        db.record_content(&file_id, 0, content.clone(), b"details".to_vec())
+            .await?;
+
+        assert_eq!(
+            db.content(&file_id, 0).await?,
+            (content.clone(), b"details".to_vec())
+        );
+        assert_eq!(db.content(&file_id, 1).await?, (vec![], vec![]));
+        let digests = db.digests().await?;
+        assert_eq!(digests.len(), 2);
+        assert_eq!(
+            digests.get(&(
+                FileId::try_from([2; 6].as_slice())?,
+                model::BlockId::try_from([2; 12].as_slice())?
+            )),
+            Some(&b"b".to_vec())
+        );
+        // Versions are immutable.
+        assert!(db
+            .record_content(&file_id, 0, content, vec![])
+            .await
+            .is_err());
+
+        db.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn find_links() -> anyhow::Result<()> {
+        let rnd = Arc::new(TestRandom::new(vec![1, 2, 3]));
+        let db = Store::new_for_test(rnd).await.expect("creation failed");
+
+        let stat = |inode| Stat {
+            inode,
+            device: 1,
+            ..Default::default()
+        };
+        let file = |path: &str, inode| {
+            ShallowInfo::new(Path::new(path).to_owned(), 100).with_stat(stat(inode))
+        };
+        let b = db.insert(&file("b", 10)).await?;
+        let a = db.insert(&file("a", 10)).await?;
+        db.insert(&file("c", 20)).await?;
+
+        assert_eq!(
+            db.links(&b.file_id, &stat(10)).await?,
+            vec![PathBuf::from("a")]
+        );
+        assert_eq!(
+            db.links(&FileId::default(), &stat(10)).await?,
+            vec![PathBuf::from("a"), "b".into()]
+        );
+        // Only the latest version counts.
+        db.insert(&file("a", 30)).await?;
+        assert!(db.links(&b.file_id, &stat(10)).await?.is_empty());
+        assert_eq!(
+            db.links(&a.file_id, &stat(20)).await?,
+            vec![PathBuf::from("c")]
+        );
+
+        db.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn snapshots() -> anyhow::Result<()> {
+        // The reserved id is never given to a file.
+        let rnd = Arc::new(TestRandom::new(vec![0xff, 1, 2]));
+        let db = Store::new_for_test(rnd).await.expect("creation failed");
+
+        let a = db.insert(&ShallowInfo::new("a".into(), 1)).await?;
+        assert_eq!(a.file_id, FileId::try_from([1; 6].as_slice())?);
+        db.record_content(&a.file_id, 0, vec![], b"a".to_vec())
+            .await?;
+        // The latest version is only reserved, its upload did not complete.
+        db.insert(&ShallowInfo::new("a".into(), 2)).await?;
+        db.insert(&ShallowInfo::new("b".into(), 1)).await?;
+        assert_eq!(
+            db.stored_versions(vec!["a".into(), "b".into(), "c".into()])
+                .await?,
+            vec![(a.file_id, 0)]
+        );
+
+        assert_eq!(db.new_snapshot(100).await?, 0);
+        assert_eq!(db.new_snapshot(200).await?, 1);
+        db.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn forget_versions() -> anyhow::Result<()> {
+        let rnd = Arc::new(TestRandom::new(vec![1]));
+        let db = Store::new_for_test(rnd).await.expect("creation failed");
+
+        let block = |n: u8| -> Result<model::BlockRef> {
+            Ok(model::BlockRef {
+                file_id: FileId::try_from([n; 6].as_slice())?,
+                block_id: model::BlockId::try_from([n; 12].as_slice())?,
+            })
+        };
+        let file = ShallowInfo::new("a".into(), 1).with_stat(Stat {
+            inode: 10,
+            ..Default::default()
+        });
+        let old = db.insert(&file).await?;
+        let content = vec![(b"old".to_vec(), block(1)?), (b"kept".to_vec(), block(2)?)];
+        db.record_content(&old.file_id, 0, content, b"old".to_vec())
+            .await?;
+        let new = db.insert(&file).await?;
+        let content = vec![(b"kept".to_vec(), block(2)?)];
+        db.record_content(&new.file_id, 1, content, b"new".to_vec())
+            .await?;
+        db.record_blocks(vec![
+            (b"old".to_vec(), block(1)?),
+            (b"kept".to_vec(), block(2)?),
+        ])
+        .await?;
+        assert_eq!(db.new_snapshot(100).await?, 0);
+
+        db.forget(vec![(old.file_id.clone(), 0)], vec![0]).await?;
+        assert_eq!(db.content(&old.file_id, 0).await?, (vec![], vec![]));
+        assert_eq!(db.lookup_block(b"old").await?, None);
+        assert_eq!(db.lookup_block(b"kept").await?, Some(block(2)?));
+        assert!(matches!(
+            db.check_shallow_change(&file).await?,
+            Change::Unchanged(_)
+        ));
+
+        // The latest version is cleared, and its number is not reused.
+        db.forget(vec![(new.file_id.clone(), 1)], vec![]).await?;
+        assert_eq!(db.lookup_block(b"kept").await?, None);
+        assert!(db.stored_versions(vec!["a".into()]).await?.is_empty());
+        assert!(matches!(
+            db.check_shallow_change(&file).await?,
+            Change::Changed(Some(_))
+        ));
+        assert_eq!(db.insert(&file).await?.version, 2);
+        // Neither is the number of a snapshot.
+        assert_eq!(db.new_snapshot(200).await?, 1);
+        db.shutdown().await
+    }
 }
@@ -0,0 +1,384 @@
+//! Verification proves the data stored for this Source can be restored: every descriptor is
+//! decrypted, then every block, or a random sample of them, is decrypted and hashed again.
+
+use crate::{
+    restore::{self, Repository},
+    state::Digests,
+};
+use anyhow::{anyhow, Result};
+use crypto::model::{self, BlockId, FileId, Version};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use storage::fingerprint::Fingerprinter;
+
+/// How much is verified.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Fraction of the blocks to decrypt, between 0 and 1. Every block is decrypted if not set.
+    pub sample: Option<f64>,
+}
+
+/// Outcome of a verification, meant to be read by machines as well.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Report {
+    pub files: u64,
+    pub versions: u64,
+    /// Distinct blocks referenced by the versions.
+    pub blocks: u64,
+    /// Blocks decrypted, and the size of their content.
+    pub checked_blocks: u64,
+    pub checked_bytes: u64,
+    /// Blocks decrypted whose digest the local state does not know, so that only their tag was
+    /// checked.
+    pub unhashed_blocks: u64,
+    pub failures: Vec<Failure>,
+}
+
+/// A version or a block which failed verification.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Failure {
+    pub file_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<Version>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_id: Option<String>,
+    pub error: String,
+}
+
+impl Failure {
+    fn version(file_id: &FileId, version: Version, err: anyhow::Error) -> Self {
+        Failure {
+            file_id: hex::encode(file_id.as_bytes()),
+            version: Some(version),
+            block_id: None,
+            error: format!("{:#}", err),
+        }
+    }
+
+    fn block(block: &model::BlockRef, err: anyhow::Error) -> Self {
+        Failure {
+            file_id: hex::encode(block.file_id.as_bytes()),
+            version: None,
+            block_id: Some(hex::encode(block.block_id.as_bytes())),
+            error: format!("{:#}", err),
+        }
+    }
+}
+
+/// Verifies every version of every file in `repository`. Decrypted blocks must hash to the
+/// `digests` recorded when backing them up, if known, and the content of each version must have
+/// the size its descriptor gives once all of its blocks were decrypted.
+///
+/// Failures are reported, only failing to list the repository is an error.
+pub async fn verify<R: Repository + Sync + ?Sized>(
+    repository: &R,
+    keys: &crypto::Keys,
+    rnd: &dyn crypto::RandomApi,
+    digests: &Digests,
+    options: &Options,
+) -> Result<Report> {
+    if let Some(sample) = options.sample {
+        if !(0.0..=1.0).contains(&sample) {
+            return Err(anyhow!("Sample must be between 0 and 1, got {}", sample));
+        }
+    }
+    let mut report = Report::default();
+    let mut versions = vec![];
+    let mut blocks = vec![];
+    let mut seen = HashSet::new();
+    for file_id in repository.files().await? {
+        report.files += 1;
+        for version in repository.versions(&file_id).await? {
+            report.versions += 1;
+            match restore::read_version(repository, keys, &file_id, version).await {
+                Ok((protected, chunks)) => {
+                    for chunk in &chunks {
+                        if seen.insert(key(chunk)) {
+                            blocks.push(chunk.clone());
+                        }
+                    }
+                    versions.push((file_id.clone(), version, protected, chunks));
+                }
+                Err(err) => report
+                    .failures
+                    .push(Failure::version(&file_id, version, err)),
+            }
+        }
+    }
+    report.blocks = blocks.len() as u64;
+
+    let fp = Fingerprinter::new(1)?;
+    let mut sizes = HashMap::new();
+    for block in blocks {
+        if !sampled(rnd, options.sample)? {
+            continue;
+        }
+        report.checked_blocks += 1;
+        match check_block(repository, keys, &fp, digests, &block).await {
+            Ok((size, hashed)) => {
+                report.checked_bytes += size;
+                report.unhashed_blocks += !hashed as u64;
+                sizes.insert(key(&block), size);
+            }
+            Err(err) => report.failures.push(Failure::block(&block, err)),
+        }
+    }
+
+    for (file_id, version, protected, chunks) in versions {
+        if !matches!(
+            protected.kind,
+            model::FileKind::Regular | model::FileKind::Hardlink(_)
+        ) {
+            continue;
+        }
+        let size: Option<u64> = chunks
+            .iter()
+            .map(|chunk| sizes.get(&key(chunk)).copied())
+            .sum();
+        match size {
+            Some(size) if size != protected.size => report.failures.push(Failure::version(
+                &file_id,
+                version,
+                anyhow!("Content is {} bytes, expected {}", size, protected.size),
+            )),
+            _ => {}
+        }
+    }
+    Ok(report)
+}
+
+fn key(block: &model::BlockRef) -> (FileId, BlockId) {
+    (block.file_id.clone(), block.block_id.clone())
+}
+
+/// Whether to check the next block, given the fraction of blocks to check.
+fn sampled(rnd: &dyn crypto::RandomApi, sample: Option<f64>) -> Result<bool> {
+    match sample {
+        None => Ok(true),
+        Some(sample) if sample >= 1.0 => Ok(true),
+        Some(sample) => {
+            // Block ids are uniformly random, and this one is never used for a block.
+            let draw = rnd.generate_block_id()?;
+            let draw = u32::from_le_bytes(draw.as_bytes()[..4].try_into()?);
+            Ok((draw as f64) < sample * (u32::MAX as f64 + 1.0))
+        }
+    }
+}
+
+/// Decrypts a block and hashes its content. Returns the size of the content, and whether its
+/// digest was known.
+async fn check_block<R: Repository + Sync + ?Sized>(
+    repository: &R,
+    keys: &crypto::Keys,
+    fp: &Fingerprinter,
+    digests: &Digests,
+    block: &model::BlockRef,
+) -> Result<(u64, bool)> {
+    let stored = repository.block(&block.file_id, &block.block_id).await?;
+    let (verified, protected) = keys.decrypt_block(&stored)?;
+    if verified.file_id != block.file_id || verified.block_id != block.block_id {
+        return Err(anyhow!("Block stored at the wrong location"));
+    }
+    let size = protected.chunk.len() as u64;
+    let Some(expected) = digests.get(&key(block)) else {
+        return Ok((size, false));
+    };
+    if fp.hash(protected.chunk).await.digest().as_ref() != expected.as_slice() {
+        return Err(anyhow!("Content does not match its digest"));
+    }
+    Ok((size, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::RandomApi;
+    use storage::testing::Fixture;
+
+    /// Stores a block holding `data` for `file_id`, and records its digest.
+    async fn block(
+        fixture: &Fixture,
+        digests: &mut Digests,
+        file_id: &FileId,
+        data: &'static [u8],
+    ) -> Result<model::BlockRef> {
+        let chunk = Fingerprinter::new(1)?
+            .hash(bytes::Bytes::from_static(data))
+            .await;
+        let block = fixture.block(file_id, chunk.bytes())?;
+        digests.insert(
+            (file_id.clone(), block.block_id.clone()),
+            chunk.digest().to_vec(),
+        );
+        Ok(block)
+    }
+
+    /// Describes a version claiming `size` bytes.
+    fn protected(size: u64) -> model::ProtectedDescriptor {
+        model::ProtectedDescriptor {
+            filename: "file".into(),
+            size,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn verify_everything() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let mut fixture = Fixture::new(tmpdir.path())?;
+        fixture.max_refs = 1;
+        let mut digests = Digests::new();
+        let a = fixture.rnd.generate_file_id()?;
+        let b = fixture.rnd.generate_file_id()?;
+        let first = block(&fixture, &mut digests, &a, b"abc").await?;
+        let second = block(&fixture, &mut digests, &a, b"de").await?;
+        fixture.describe(&a, 0, std::slice::from_ref(&first), protected(3))?;
+        fixture.describe(&a, 1, &[first.clone(), second], protected(5))?;
+        // Shared content is only checked once.
+        fixture.describe(&b, 0, &[first], protected(3))?;
+
+        let report = verify(
+            &fixture.root,
+            &fixture.keys,
+            &fixture.rnd,
+            &digests,
+            &Options::default(),
+        )
+        .await?;
+        assert_eq!(
+            report,
+            Report {
+                files: 2,
+                versions: 3,
+                blocks: 2,
+                checked_blocks: 2,
+                checked_bytes: 5,
+                unhashed_blocks: 0,
+                failures: vec![],
+            }
+        );
+
+        // Without the local state, only the tags are checked.
+        digests.clear();
+        let report = verify(
+            &fixture.root,
+            &fixture.keys,
+            &fixture.rnd,
+            &digests,
+            &Options::default(),
+        )
+        .await?;
+        assert_eq!(report.unhashed_blocks, 2);
+        assert!(report.failures.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn report_failures() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let mut fixture = Fixture::new(tmpdir.path())?;
+        fixture.max_refs = 1;
+        let mut digests = Digests::new();
+        let file_id = fixture.rnd.generate_file_id()?;
+        let tampered = block(&fixture, &mut digests, &file_id, b"abc").await?;
+        let other = block(&fixture, &mut digests, &file_id, b"def").await?;
+        let good = block(&fixture, &mut digests, &file_id, b"ghi").await?;
+        fixture.describe(&file_id, 0, std::slice::from_ref(&tampered), protected(3))?;
+        fixture.describe(&file_id, 1, std::slice::from_ref(&other), protected(3))?;
+        fixture.describe(&file_id, 2, &[good], protected(4))?;
+        fixture.describe(&file_id, 3, &[], protected(0))?;
+
+        // Breaks the tag of a block.
+        let file = fixture.root.existing_file(&file_id)?;
+        let mut block = file.read_block(&tampered.block_id)?;
+        let mut protected = block.protected.as_ref().clone();
+        protected[0] ^= 1;
+        block.protected = protected.into();
+        file.write_block(&block, &tampered.block_id)?;
+        // Records another digest for a block.
+        digests.insert((other.file_id.clone(), other.block_id.clone()), vec![0; 32]);
+        // Breaks the tag of a descriptor.
+        let mut descriptor = file.read_descriptor(3, 0)?;
+        descriptor.protected[0] ^= 1;
+        file.write_descriptor(&descriptor, 3, 0)?;
+
+        let report = verify(
+            &fixture.root,
+            &fixture.keys,
+            &fixture.rnd,
+            &digests,
+            &Options::default(),
+        )
+        .await?;
+        assert_eq!(report.versions, 4);
+        assert_eq!(report.checked_blocks, 3);
+        let failures: Vec<(Option<Version>, Option<String>)> = report
+            .failures
+            .into_iter()
+            .map(|failure| (failure.version, failure.block_id))
+            .collect();
+        assert_eq!(
+            failures,
+            vec![
+                (Some(3), None),
+                (None, Some(hex::encode(tampered.block_id.as_bytes()))),
+                (None, Some(hex::encode(other.block_id.as_bytes()))),
+                // Wrong size.
+                (Some(2), None),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sample_blocks() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let mut fixture = Fixture::new(tmpdir.path())?;
+        fixture.max_refs = 1;
+        let mut digests = Digests::new();
+        let file_id = fixture.rnd.generate_file_id()?;
+        let block = block(&fixture, &mut digests, &file_id, b"abc").await?;
+        // Sizes can't be checked without decrypting the blocks.
+        fixture.describe(&file_id, 0, &[block], protected(4))?;
+
+        let options = Options { sample: Some(0.0) };
+        let report = verify(
+            &fixture.root,
+            &fixture.keys,
+            &fixture.rnd,
+            &digests,
+            &options,
+        )
+        .await?;
+        assert_eq!(report.blocks, 1);
+        assert_eq!(report.checked_blocks, 0);
+        assert!(report.failures.is_empty());
+
+        let options = Options { sample: Some(1.0) };
+        assert_eq!(
+            verify(
+                &fixture.root,
+                &fixture.keys,
+                &fixture.rnd,
+                &digests,
+                &options
+            )
+            .await?
+            .failures
+            .len(),
+            1
+        );
+
+        let options = Options { sample: Some(2.0) };
+        assert!(verify(
+            &fixture.root,
+            &fixture.keys,
+            &fixture.rnd,
+            &digests,
+            &options
+        )
+        .await
+        .is_err());
+        Ok(())
+    }
+}
@@ -1,6 +1,9 @@
 use anyhow::Context;
 use settings::{connection, process};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use storage::filesystem::Chunking;
+use storage::filter::Filter;
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 use thiserror::Error;
@@ -22,7 +25,140 @@ pub struct Settings {
 pub struct Backup {
     root: Vec<PathBuf>,
     keyfile: PathBuf,
+    passphrase: Passphrase,
     db: PathBuf,
+    max_refs_per_descriptor: usize,
+    chunking: Chunking,
+    paranoid: bool,
+    filter: Filter,
+    schedule: Schedule,
+    watch: Option<Duration>,
+    retention: Retention,
+}
+
+/// Where the passphrase of a sealed keyfile comes from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Passphrase {
+    /// Asked on the terminal.
+    #[default]
+    Prompt,
+    /// Read from an environment variable.
+    Env(String),
+    /// Read from an open file descriptor, up to the first newline.
+    Fd(i32),
+}
+
+/// When the Source checks its roots for changes.
+#[derive(Debug, Clone, Default)]
+pub enum Schedule {
+    /// A single pass, after which the Source exits.
+    #[default]
+    Once,
+    /// Passes start at a fixed interval from each other, or right away when a pass takes longer.
+    Interval(Duration),
+    /// Passes start at the local times matching a cron expression.
+    Cron(Box<croner::Cron>),
+}
+
+impl Schedule {
+    /// Returns when the pass following one that ran from `started` to `ended` should start, or
+    /// None if there is no further pass.
+    pub fn next(
+        &self,
+        started: SystemTime,
+        ended: SystemTime,
+    ) -> anyhow::Result<Option<SystemTime>> {
+        match self {
+            Schedule::Once => Ok(None),
+            Schedule::Interval(interval) => Ok(Some(std::cmp::max(started + *interval, ended))),
+            Schedule::Cron(cron) => {
+                let ended = chrono::DateTime::<chrono::Local>::from(ended);
+                let next = cron
+                    .find_next_occurrence(&ended, false)
+                    .context("No next occurrence")?;
+                Ok(Some(next.into()))
+            }
+        }
+    }
+}
+
+/// Which snapshots are kept when pruning. A snapshot is kept if any of the rules selects it, and
+/// the latest snapshot is always kept. Without any rule, every snapshot is kept.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Retention {
+    /// Keeps the latest snapshots.
+    pub last: Option<usize>,
+    /// Keeps the latest snapshot of each of the latest days, weeks and months with a snapshot,
+    /// in local time. Weeks start on Monday.
+    pub daily: Option<usize>,
+    pub weekly: Option<usize>,
+    pub monthly: Option<usize>,
+    /// Keeps the snapshots taken within this duration of the latest one.
+    pub within: Option<Duration>,
+}
+
+impl Retention {
+    /// Returns whether no rule is set, in which case every snapshot is kept.
+    pub fn is_empty(&self) -> bool {
+        *self == Retention::default()
+    }
+
+    /// Returns whether each snapshot is kept, given the times they were taken at in increasing
+    /// order.
+    pub fn keep(&self, snapshots: &[SystemTime]) -> Vec<bool> {
+        use chrono::Datelike;
+        if self.is_empty() {
+            return vec![true; snapshots.len()];
+        }
+        let mut keep = vec![false; snapshots.len()];
+        let Some(latest) = snapshots.last() else {
+            return keep;
+        };
+        type Period = fn(&chrono::DateTime<chrono::Local>) -> Option<(i32, u32, u32)>;
+        let rules: [(Option<usize>, Period); 4] = [
+            // Every snapshot is its own period.
+            (self.last, |_| None),
+            (self.daily, |time| {
+                Some((time.year(), time.month(), time.day()))
+            }),
+            (self.weekly, |time| {
+                let week = time.iso_week();
+                Some((week.year(), week.week(), 0))
+            }),
+            (self.monthly, |time| Some((time.year(), time.month(), 0))),
+        ];
+        for (count, period) in rules {
+            let Some(count) = count else {
+                continue;
+            };
+            // From the latest snapshot, keep the latest one of each period.
+            let mut periods = 0;
+            let mut current = None;
+            for (kept, time) in keep.iter_mut().zip(snapshots).rev() {
+                let period = period(&chrono::DateTime::from(*time));
+                if period.is_some() && period == current {
+                    continue;
+                }
+                if periods == count {
+                    break;
+                }
+                current = period;
+                *kept = true;
+                periods += 1;
+            }
+        }
+        if let Some(within) = self.within {
+            for (kept, time) in keep.iter_mut().zip(snapshots) {
+                if *time + within >= *latest {
+                    *kept = true;
+                }
+            }
+        }
+        if let Some(last) = keep.last_mut() {
+            *last = true;
+        }
+        keep
+    }
 }
 
 impl Settings {
@@ -53,9 +189,52 @@ impl Backup {
         &self.keyfile
     }
 
+    /// Returns where the passphrase unlocking the keyfile comes from, if it is sealed.
+    pub fn passphrase(&self) -> &Passphrase {
+        &self.passphrase
+    }
+
     pub fn db(&self) -> &Path {
         &self.db
     }
+
+    /// Returns how many blocks a single descriptor may reference. Larger files are described by
+    /// multiple descriptors.
+    pub fn max_refs_per_descriptor(&self) -> usize {
+        self.max_refs_per_descriptor
+    }
+
+    /// Returns how files are split into chunks.
+    pub fn chunking(&self) -> Chunking {
+        self.chunking
+    }
+
+    /// Returns whether files are re-hashed on every pass, instead of trusting their metadata to
+    /// detect changes.
+    pub fn paranoid(&self) -> bool {
+        self.paranoid
+    }
+
+    /// Returns which files under the roots are backed up.
+    pub fn filter(&self) -> &Filter {
+        &self.filter
+    }
+
+    /// Returns when the roots are checked for changes.
+    pub fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    /// Returns how often the roots are fully checked when they are watched for changes instead of
+    /// following the schedule. None if they are not watched.
+    pub fn watch(&self) -> Option<Duration> {
+        self.watch
+    }
+
+    /// Returns which snapshots are kept when pruning.
+    pub fn retention(&self) -> &Retention {
+        &self.retention
+    }
 }
 
 /// All the customizable options for creating a fresh config.
@@ -112,6 +291,19 @@ impl Builder {
                 root: self.root,
                 db: "db".into(),
                 keyfile: "keyfile".into(),
+                passphrase: wire::Passphrase::default(),
+                max_refs_per_descriptor: wire::default_max_refs_per_descriptor(),
+                chunking: wire::Chunking::default(),
+                paranoid: false,
+                exclude: vec![],
+                include: vec![],
+                ignore_file: wire::default_ignore_file(),
+                exclude_caches: false,
+                one_file_system: false,
+                max_file_size: None,
+                schedule: wire::Schedule::default(),
+                watch: None,
+                retention: wire::Retention::default(),
             },
         };
         settings::save(&settings, NAME, anchor)?;
@@ -137,6 +329,10 @@ enum Fields {
 enum ConfigError {
     #[error("Some fields have not been set")]
     MissingField,
+    #[error("Descriptors must reference at least one block")]
+    InvalidMaxRefs,
+    #[error("Intervals must not be zero")]
+    InvalidInterval,
 }
 
 mod wire {
@@ -157,7 +353,102 @@ mod wire {
     pub struct Backup {
         pub root: Vec<PathBuf>,
         pub keyfile: settings::ConfigPath,
+        #[serde(default)]
+        pub passphrase: Passphrase,
         pub db: settings::ConfigPath,
+        #[serde(default = "default_max_refs_per_descriptor")]
+        pub max_refs_per_descriptor: usize,
+        #[serde(default)]
+        pub chunking: Chunking,
+        #[serde(default)]
+        pub paranoid: bool,
+        /// Gitignore-style patterns of the files not backed up.
+        #[serde(default)]
+        pub exclude: Vec<String>,
+        /// Gitignore-style patterns of the files backed up, all of them if empty.
+        #[serde(default)]
+        pub include: Vec<String>,
+        /// Name of the per-directory files with more patterns to exclude, or empty.
+        #[serde(default = "default_ignore_file")]
+        pub ignore_file: String,
+        /// Skips directories tagged with a CACHEDIR.TAG file.
+        #[serde(default)]
+        pub exclude_caches: bool,
+        /// Stays on the filesystem of each root.
+        #[serde(default)]
+        pub one_file_system: bool,
+        /// Size in bytes above which files are not backed up.
+        #[serde(default)]
+        pub max_file_size: Option<u64>,
+        #[serde(default)]
+        pub schedule: Schedule,
+        #[serde(default)]
+        pub watch: Option<Watch>,
+        #[serde(default)]
+        pub retention: Retention,
+    }
+
+    /// Which snapshots are kept when pruning, by count or by duration like "30d".
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    pub struct Retention {
+        pub keep_last: Option<usize>,
+        pub keep_daily: Option<usize>,
+        pub keep_weekly: Option<usize>,
+        pub keep_monthly: Option<usize>,
+        pub keep_within: Option<String>,
+    }
+
+    /// Watching the roots for changes, on Linux.
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Watch {
+        /// How often the roots are fully checked, to catch changes the notifications missed.
+        pub reconcile: String,
+    }
+
+    pub fn default_max_refs_per_descriptor() -> usize {
+        4096
+    }
+
+    pub fn default_ignore_file() -> String {
+        ".pistonignore".into()
+    }
+
+    /// How files are split into chunks. Sizes are in bytes.
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(tag = "mode", rename_all = "snake_case")]
+    pub enum Chunking {
+        Fixed { size: usize },
+        ContentDefined { min: u32, avg: u32, max: u32 },
+    }
+
+    /// Where the passphrase of a sealed keyfile comes from: "prompt", or a table with either the
+    /// name of an environment variable as `env`, or a file descriptor number as `fd`.
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Passphrase {
+        #[default]
+        Prompt,
+        Env(String),
+        Fd(i32),
+    }
+
+    /// When to check the roots for changes. Intervals are durations like "6h" or "1h 30m", and
+    /// cron expressions have 5 fields, or 6 with seconds.
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Schedule {
+        #[default]
+        Once,
+        Interval(String),
+        Cron(String),
+    }
+
+    impl Default for Chunking {
+        fn default() -> Self {
+            // Blocks are streamed to and from the Sink in frames, so their size is not bound by
+            // gRPC limits.
+            Chunking::Fixed { size: 1 << 24 }
+        }
     }
 }
 
@@ -178,14 +469,76 @@ impl settings::Anchored for Backup {
     type Wire = wire::Backup;
 
     fn anchor(wire: &Self::Wire, anchor: &settings::Anchor) -> anyhow::Result<Self> {
+        if wire.max_refs_per_descriptor == 0 {
+            return Err(ConfigError::InvalidMaxRefs.into());
+        }
+        let chunking = match wire.chunking {
+            wire::Chunking::Fixed { size } => Chunking::Fixed(size),
+            wire::Chunking::ContentDefined { min, avg, max } => {
+                Chunking::ContentDefined { min, avg, max }
+            }
+        };
+        chunking.validate().context("Invalid chunking")?;
+        let filter = Filter::new(&wire.exclude, &wire.include)
+            .context("Invalid patterns")?
+            .ignore_file(Some(wire.ignore_file.clone()).filter(|name| !name.is_empty()))
+            .skip_caches(wire.exclude_caches)
+            .one_filesystem(wire.one_file_system)
+            .max_size(wire.max_file_size);
+        let watch = match &wire.watch {
+            None => None,
+            Some(watch) => Some(parse_interval(&watch.reconcile)?),
+        };
+        let schedule = match &wire.schedule {
+            wire::Schedule::Once => Schedule::Once,
+            wire::Schedule::Interval(interval) => Schedule::Interval(parse_interval(interval)?),
+            wire::Schedule::Cron(expression) => Schedule::Cron(Box::new(
+                croner::Cron::new(expression)
+                    .with_seconds_optional()
+                    .parse()
+                    .context("Invalid cron expression")?,
+            )),
+        };
+        let retention = Retention {
+            last: wire.retention.keep_last,
+            daily: wire.retention.keep_daily,
+            weekly: wire.retention.keep_weekly,
+            monthly: wire.retention.keep_monthly,
+            within: match &wire.retention.keep_within {
+                None => None,
+                Some(within) => {
+                    Some(humantime::parse_duration(within).context("Invalid retention duration")?)
+                }
+            },
+        };
         Ok(Backup {
             root: wire.root.clone(),
             keyfile: wire.keyfile.path(anchor),
+            passphrase: match &wire.passphrase {
+                wire::Passphrase::Prompt => Passphrase::Prompt,
+                wire::Passphrase::Env(name) => Passphrase::Env(name.clone()),
+                wire::Passphrase::Fd(fd) => Passphrase::Fd(*fd),
+            },
             db: wire.db.path(anchor),
+            max_refs_per_descriptor: wire.max_refs_per_descriptor,
+            chunking,
+            paranoid: wire.paranoid,
+            filter,
+            schedule,
+            watch,
+            retention,
         })
     }
 }
 
+fn parse_interval(interval: &str) -> anyhow::Result<Duration> {
+    let interval = humantime::parse_duration(interval).context("Invalid interval")?;
+    if interval.is_zero() {
+        return Err(ConfigError::InvalidInterval.into());
+    }
+    Ok(interval)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -218,7 +571,183 @@ mod test {
         assert_eq!(settings.backup().roots(), &roots);
         assert_eq!(settings.backup().db(), cfg.join("db"));
         assert_eq!(settings.backup().keyfile(), cfg.join("keyfile"));
+        assert_eq!(settings.backup().passphrase(), &Passphrase::Prompt);
+        assert_eq!(settings.backup().max_refs_per_descriptor(), 4096);
+        assert_eq!(settings.backup().chunking(), Chunking::Fixed(1 << 24));
+        assert!(!settings.backup().paranoid());
+        assert!(matches!(settings.backup().schedule(), Schedule::Once));
+        assert_eq!(settings.backup().watch(), None);
         // TODO: validate certificates
         Ok(())
     }
+
+    /// Loads the default settings, once `replace` is replaced by `by` in their saved file.
+    fn load_with(replace: &str, by: &str) -> anyhow::Result<Settings> {
+        let tmpdir = TempDir::new()?;
+        let anchor = settings::get_anchor(Some(tmpdir.path().join("cfg")))?;
+        Builder::default()
+            .root(vec![])
+            .certificate("")
+            .private_key("")
+            .save(&anchor)?;
+        let path = Builder::path(&anchor);
+        let config = std::fs::read_to_string(&path)?;
+        assert!(config.contains(replace), "{}", config);
+        std::fs::write(&path, config.replace(replace, by))?;
+        load_impl(&anchor)
+    }
+
+    #[test]
+    fn content_defined_chunking() -> anyhow::Result<()> {
+        let fixed = "mode = \"fixed\"\nsize = 16777216\n";
+        let cdc = "mode = \"content_defined\"\nmin = 1024\navg = 4096\nmax = 16384\n";
+        assert_eq!(
+            load_with(fixed, cdc)?.backup().chunking(),
+            Chunking::ContentDefined {
+                min: 1024,
+                avg: 4096,
+                max: 16384
+            }
+        );
+
+        let invalid = "mode = \"content_defined\"\nmin = 1\navg = 4096\nmax = 16384\n";
+        assert!(load_with(fixed, invalid).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn schedule() -> anyhow::Result<()> {
+        let once = "schedule = \"once\"\n";
+        let with = |schedule: &str| -> anyhow::Result<Schedule> {
+            Ok(load_with(once, schedule)?.backup().schedule().clone())
+        };
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let interval = with("schedule = { interval = \"1h 30m\" }\n")?;
+        assert_eq!(
+            interval.next(start, start + Duration::from_secs(60))?,
+            Some(start + Duration::from_secs(5400))
+        );
+        // Late passes are followed right away by the next one.
+        let late = start + Duration::from_secs(6000);
+        assert_eq!(interval.next(start, late)?, Some(late));
+
+        let cron = with("schedule = { cron = \"*/5 * * * *\" }\n")?;
+        let next = cron.next(start, start)?.unwrap();
+        let delay = next.duration_since(start)?;
+        assert!(delay > Duration::ZERO && delay <= Duration::from_secs(300));
+        assert_eq!(
+            next.duration_since(SystemTime::UNIX_EPOCH)?.as_secs() % 300,
+            0
+        );
+
+        assert!(with("schedule = { interval = \"0s\" }\n").is_err());
+        assert!(with("schedule = { interval = \"often\" }\n").is_err());
+        assert!(with("schedule = { cron = \"* * *\" }\n").is_err());
+
+        let watch = format!("{once}watch = {{ reconcile = \"1d\" }}\n");
+        assert_eq!(
+            load_with(once, &watch)?.backup().watch(),
+            Some(Duration::from_secs(86400))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn passphrase() -> anyhow::Result<()> {
+        let prompt = "passphrase = \"prompt\"\n";
+        let with = |passphrase: &str| -> anyhow::Result<Passphrase> {
+            Ok(load_with(prompt, passphrase)?.backup().passphrase().clone())
+        };
+        assert_eq!(
+            with("passphrase = { env = \"PISTON_PASSPHRASE\" }\n")?,
+            Passphrase::Env("PISTON_PASSPHRASE".into())
+        );
+        assert_eq!(with("passphrase = { fd = 3 }\n")?, Passphrase::Fd(3));
+        assert!(with("passphrase = { file = \"x\" }\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn retention() -> anyhow::Result<()> {
+        let retention = "[backup.retention]\n";
+        let with = |rules: &str| -> anyhow::Result<Retention> {
+            let by = format!("{retention}{rules}");
+            Ok(load_with(retention, &by)?.backup().retention().clone())
+        };
+        assert!(with("")?.is_empty());
+        assert_eq!(
+            with("keep_last = 3\nkeep_monthly = 12\nkeep_within = \"2d\"\n")?,
+            Retention {
+                last: Some(3),
+                monthly: Some(12),
+                within: Some(Duration::from_secs(2 * 86400)),
+                ..Default::default()
+            }
+        );
+        assert!(with("keep_within = \"forever\"\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn keep_snapshots() {
+        // Snapshots every 6 hours for 3 days, from a Wednesday at noon UTC: they are in the same
+        // week in any timezone.
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(6 * 86400 + 12 * 3600);
+        let snapshots: Vec<SystemTime> = (0..12)
+            .map(|n| start + Duration::from_secs(n * 6 * 3600))
+            .collect();
+        let kept = |retention: Retention| -> Vec<usize> {
+            let keep = retention.keep(&snapshots);
+            (0..snapshots.len()).filter(|n| keep[*n]).collect()
+        };
+
+        assert_eq!(kept(Retention::default()), (0..12).collect::<Vec<_>>());
+        // The latest snapshot is always kept.
+        let none = Retention {
+            last: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(kept(none), vec![11]);
+        let last = Retention {
+            last: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(kept(last), vec![9, 10, 11]);
+        let within = Retention {
+            within: Some(Duration::from_secs(12 * 3600)),
+            ..Default::default()
+        };
+        assert_eq!(kept(within), vec![9, 10, 11]);
+        let weekly = Retention {
+            weekly: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(kept(weekly), vec![11]);
+
+        // Days are in local time, so only count them.
+        let daily = Retention {
+            daily: Some(2),
+            ..Default::default()
+        };
+        let daily = kept(daily);
+        assert_eq!(daily.len(), 2);
+        assert_eq!(daily.last(), Some(&11));
+    }
+
+    #[test]
+    fn filter() -> anyhow::Result<()> {
+        let defaults = concat!(
+            "exclude = []\ninclude = []\nignore_file = \".pistonignore\"\n",
+            "exclude_caches = false\none_file_system = false\n"
+        );
+        load_with(
+            defaults,
+            concat!(
+                "exclude = [\"target/\", \"*.o\"]\ninclude = [\"/home\"]\nignore_file = \"\"\n",
+                "exclude_caches = true\nmax_file_size = 1000000\n"
+            ),
+        )?;
+        assert!(load_with(defaults, "exclude = [\"[z-a]\"]\n").is_err());
+        Ok(())
+    }
 }
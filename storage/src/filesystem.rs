@@ -1,10 +1,13 @@
+use crate::filter::{Filter, Scope};
 use anyhow::{Context, Result};
 use bytes::{Bytes, BytesMut};
 use filetime::FileTime;
 use std::{
+    ffi::OsString,
     fs,
     io::{ErrorKind, Read},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 use tokio::sync::{
     mpsc::{self, Receiver, Sender},
@@ -16,6 +19,11 @@ use tokio::sync::{
 pub enum WalkEvent {
     File(ShallowInfo),
     Error(PathBuf, anyhow::Error),
+    /// A full walk of the roots starts, when watching them: everything reported until `Walked`
+    /// comes from the walk.
+    Walking,
+    /// The full walk of the roots is over.
+    Walked,
 }
 
 /// Shallow information about a file.
@@ -23,6 +31,9 @@ pub enum WalkEvent {
 pub struct ShallowInfo {
     file: PathBuf,
     len: u64,
+    stat: Stat,
+    kind: FileType,
+    links: u64,
 }
 
 impl ShallowInfo {
@@ -39,8 +50,217 @@ impl ShallowInfo {
         self.len == 0
     }
 
+    /// Metadata used to detect changes.
+    pub fn stat(&self) -> &Stat {
+        &self.stat
+    }
+
+    /// Type of the file.
+    pub fn kind(&self) -> &FileType {
+        &self.kind
+    }
+
+    /// Number of hard links to the file.
+    pub fn links(&self) -> u64 {
+        self.links
+    }
+
     pub fn new(file: PathBuf, len: u64) -> Self {
-        ShallowInfo { file, len }
+        ShallowInfo {
+            file,
+            len,
+            stat: Stat::default(),
+            kind: FileType::Regular,
+            links: 1,
+        }
+    }
+
+    pub fn with_stat(mut self, stat: Stat) -> Self {
+        self.stat = stat;
+        self
+    }
+
+    pub fn with_kind(mut self, kind: FileType) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    pub fn with_links(mut self, links: u64) -> Self {
+        self.links = links;
+        self
+    }
+
+    /// Collects the shallow information of a regular file from its metadata.
+    pub fn from_metadata(file: PathBuf, md: &fs::Metadata) -> Self {
+        ShallowInfo::new(file, md.len())
+            .with_stat(Stat::new(md))
+            .with_links(links(md))
+    }
+
+    /// Collects the shallow information of any file which can be backed up, from its metadata
+    /// as returned by `fs::symlink_metadata`. Returns None for directories and sockets.
+    pub fn from_entry(file: PathBuf, md: &fs::Metadata) -> std::io::Result<Option<Self>> {
+        let Some(kind) = FileType::of(&file, md)? else {
+            return Ok(None);
+        };
+        let info = ShallowInfo::from_metadata(file, md);
+        Ok(Some(match kind {
+            FileType::Regular => info,
+            // Only regular files have content.
+            kind => ShallowInfo { len: 0, ..info }.with_kind(kind),
+        }))
+    }
+}
+
+/// Type of a file which can be backed up, with what is needed to recreate it besides its
+/// content.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum FileType {
+    #[default]
+    Regular,
+    /// Symbolic link, which is never followed.
+    Symlink(PathBuf),
+    Fifo,
+    /// Character device, with its device number.
+    CharDevice(u64),
+    /// Block device, with its device number.
+    BlockDevice(u64),
+    /// Directory, with the sorted names of its entries which are backed up.
+    Directory(Vec<OsString>),
+}
+
+impl FileType {
+    /// Returns the type of `path` given its metadata, or None if it can't be backed up.
+    #[cfg(unix)]
+    pub fn of(path: &Path, md: &fs::Metadata) -> std::io::Result<Option<FileType>> {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+        let file_type = md.file_type();
+        Ok(Some(if file_type.is_file() {
+            FileType::Regular
+        } else if file_type.is_symlink() {
+            FileType::Symlink(fs::read_link(path)?)
+        } else if file_type.is_fifo() {
+            FileType::Fifo
+        } else if file_type.is_char_device() {
+            FileType::CharDevice(md.rdev())
+        } else if file_type.is_block_device() {
+            FileType::BlockDevice(md.rdev())
+        } else {
+            return Ok(None);
+        }))
+    }
+
+    /// Returns the type of `path` given its metadata, or None if it can't be backed up.
+    #[cfg(windows)]
+    pub fn of(path: &Path, md: &fs::Metadata) -> std::io::Result<Option<FileType>> {
+        let file_type = md.file_type();
+        Ok(Some(if file_type.is_file() {
+            FileType::Regular
+        } else if file_type.is_symlink() {
+            FileType::Symlink(fs::read_link(path)?)
+        } else {
+            return Ok(None);
+        }))
+    }
+}
+
+#[cfg(unix)]
+fn links(md: &fs::Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::nlink(md)
+}
+
+/// Hard links are not detected.
+#[cfg(windows)]
+fn links(_md: &fs::Metadata) -> u64 {
+    1
+}
+
+/// File metadata which changes whenever a file is modified, even when its length stays the
+/// same. Times are in nanoseconds since the epoch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stat {
+    pub mtime: i64,
+    pub ctime: i64,
+    pub inode: u64,
+    pub device: u64,
+    /// File type and permissions.
+    pub mode: u32,
+}
+
+impl Stat {
+    #[cfg(unix)]
+    pub fn new(md: &fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        Stat {
+            mtime: md.mtime() * 1_000_000_000 + md.mtime_nsec(),
+            ctime: md.ctime() * 1_000_000_000 + md.ctime_nsec(),
+            inode: md.ino(),
+            device: md.dev(),
+            mode: md.mode(),
+        }
+    }
+
+    /// Only the modification time is reliably available.
+    #[cfg(windows)]
+    pub fn new(md: &fs::Metadata) -> Self {
+        let nanos = |time: std::io::Result<std::time::SystemTime>| {
+            time.ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |elapsed| elapsed.as_nanos() as i64)
+        };
+        Stat {
+            mtime: nanos(md.modified()),
+            ..Default::default()
+        }
+    }
+}
+
+/// How files are split into chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chunking {
+    /// Chunks of a fixed size, except for the last chunk of a file.
+    Fixed(usize),
+    /// Content-defined chunks (FastCDC): boundaries depend on the content itself, so that an
+    /// edit only changes the chunks around it instead of shifting all the following ones.
+    ContentDefined { min: u32, avg: u32, max: u32 },
+}
+
+impl Chunking {
+    /// Checks that the chunking parameters are usable.
+    pub fn validate(&self) -> Result<()> {
+        use fastcdc::v2020::{
+            AVERAGE_MAX, AVERAGE_MIN, MAXIMUM_MAX, MAXIMUM_MIN, MINIMUM_MAX, MINIMUM_MIN,
+        };
+        match *self {
+            Chunking::Fixed(size) => {
+                anyhow::ensure!(size > 0, "Chunks can't be empty");
+            }
+            Chunking::ContentDefined { min, avg, max } => {
+                anyhow::ensure!(
+                    (MINIMUM_MIN..=MINIMUM_MAX).contains(&min),
+                    "Minimum chunk size must be within [{}, {}]",
+                    MINIMUM_MIN,
+                    MINIMUM_MAX
+                );
+                anyhow::ensure!(
+                    (AVERAGE_MIN..=AVERAGE_MAX).contains(&avg),
+                    "Average chunk size must be within [{}, {}]",
+                    AVERAGE_MIN,
+                    AVERAGE_MAX
+                );
+                anyhow::ensure!(
+                    (MAXIMUM_MIN..=MAXIMUM_MAX).contains(&max),
+                    "Maximum chunk size must be within [{}, {}]",
+                    MAXIMUM_MIN,
+                    MAXIMUM_MAX
+                );
+                anyhow::ensure!(
+                    min <= avg && avg <= max,
+                    "Chunk sizes must be ordered as min <= avg <= max"
+                );
+            }
+        }
+        Ok(())
     }
 }
 
@@ -65,32 +285,36 @@ impl AsyncFileOps {
     }
 
     /// Streams all the chunks in `file`. Returns an error if not all chunks were returned.
-    /// An empty file will return a single empty chunk. Except in that case, chunks are
-    /// guaranteed to be of size `chunk_size` unless they are the last chunk of the file.
+    /// An empty file will return a single empty chunk. Except in that case, chunks follow
+    /// `chunking`: with fixed-size chunking, they are guaranteed to be of the requested size
+    /// unless they are the last chunk of the file.
     pub async fn read_chunks(
         &self,
         file: &Path,
-        chunk_size: usize,
+        chunking: Chunking,
         chunks: Sender<Bytes>,
     ) -> Result<usize> {
         let (tx, rx) = oneshot::channel();
         self.tx_group
-            .send(Ops::ReadChunks(file.to_owned(), chunk_size, chunks, tx))
+            .send(Ops::ReadChunks(file.to_owned(), chunking, chunks, tx))
             .await
             .context("failed to send command")?;
         rx.await.context("failed to get result")?
     }
 
-    /// Walks all the files in `roots`. Returns an error if not all the files were streamed.
+    /// Walks all the files in `roots` accepted by `filter`. Returns an error if not all the files
+    /// were streamed.
     pub async fn walk(
         &self,
         roots: Vec<PathBuf>,
+        filter: Filter,
         updates: Sender<WalkEvent>,
     ) -> anyhow::Result<()> {
         let (tx, rx) = oneshot::channel();
         self.tx_walk
             .send(WalkOp {
                 roots,
+                filter,
                 updates,
                 done: tx,
             })
@@ -105,7 +329,7 @@ impl AsyncFileOps {
 enum Ops {
     ReadChunks(
         PathBuf,
-        usize,
+        Chunking,
         Sender<Bytes>,
         oneshot::Sender<Result<usize>>,
     ),
@@ -116,6 +340,7 @@ enum Ops {
 #[derive(Debug)]
 struct WalkOp {
     roots: Vec<PathBuf>,
+    filter: Filter,
     updates: Sender<WalkEvent>,
     done: oneshot::Sender<Result<()>>,
 }
@@ -131,11 +356,12 @@ impl OpsRunner {
         let seq_walks = async {
             while let Some(WalkOp {
                 roots,
+                filter,
                 updates,
                 done,
             }) = self.rx_walk.recv().await
             {
-                OpsRunner::walk(roots, updates, done).await;
+                OpsRunner::walk(roots, filter, updates, done).await;
             }
         };
         let seq_ops = async {
@@ -145,8 +371,8 @@ impl OpsRunner {
                         match op {
                             None => break,
 
-                            Some(Ops::ReadChunks(path, chunk_size, chunk_tx, result_tx)) => {
-                                OpsRunner::read_chunks(path, chunk_size, chunk_tx, result_tx).await;
+                            Some(Ops::ReadChunks(path, chunking, chunk_tx, result_tx)) => {
+                                OpsRunner::read_chunks(path, chunking, chunk_tx, result_tx).await;
                             }
                         }
                     },
@@ -160,10 +386,12 @@ impl OpsRunner {
 
     async fn walk(
         roots: Vec<PathBuf>,
+        filter: Filter,
         updates: Sender<WalkEvent>,
         result_tx: oneshot::Sender<Result<()>>,
     ) {
-        let result = match tokio::task::spawn_blocking(move || walk(&roots, updates)).await {
+        let result = match tokio::task::spawn_blocking(move || walk(&roots, &filter, updates)).await
+        {
             Ok(v) => v,
             Err(e) => Err(e.into()),
         };
@@ -173,12 +401,15 @@ impl OpsRunner {
 
     async fn read_chunks(
         path: PathBuf,
-        chunk_size: usize,
+        chunking: Chunking,
         chunk_tx: Sender<Bytes>,
         result_tx: oneshot::Sender<Result<usize>>,
     ) {
-        let result = match tokio::task::spawn_blocking(move || {
-            read_chunks(path.as_path(), chunk_size, chunk_tx)
+        let result = match tokio::task::spawn_blocking(move || match chunking {
+            Chunking::Fixed(chunk_size) => read_chunks(path.as_path(), chunk_size, chunk_tx),
+            Chunking::ContentDefined { min, avg, max } => {
+                read_content_defined_chunks(path.as_path(), min, avg, max, chunk_tx)
+            }
         })
         .await
         {
@@ -191,56 +422,158 @@ impl OpsRunner {
 }
 
 /// Walks `roots` and informs `update` of what is found.
-/// Roots can be individual files or directories.
-/// Symlinks and unknown file types are ignored at the moment.
+/// Roots can be individual files or directories, and are followed if they are symlinks.
+/// Other symlinks are reported as such, along with special files like FIFOs and devices.
+/// Sockets are skipped, as well as files and directories rejected by `filter`.
 ///
 /// Returns an error if any of the updates could not be delivered.
-#[tracing::instrument(skip(update))]
-fn walk(roots: &Vec<PathBuf>, update: Sender<WalkEvent>) -> Result<()> {
-    let mut roots = roots.clone();
-
-    while let Some(current) = roots.pop() {
-        let md = fs::metadata(&current);
-        if let Err(err) = md {
-            update.blocking_send(WalkEvent::Error(current, anyhow::Error::new(err)))?;
-            continue;
-        }
-        let md = md.unwrap();
-        if md.is_symlink() {
-            tracing::debug!("not following symlink {:?}", current);
+#[tracing::instrument(skip(filter, update))]
+fn walk(roots: &[PathBuf], filter: &Filter, update: Sender<WalkEvent>) -> Result<()> {
+    let roots = roots.iter().map(|root| (root.clone(), None)).collect();
+    walk_with(roots, filter, &update, &mut |_, _| {})
+}
+
+/// Same as walk(), also calling `on_dir` for every directory entered before going through its
+/// content, with the scope of its content. Roots found in a known scope come with it.
+pub(crate) fn walk_with(
+    roots: Vec<(PathBuf, Option<Arc<Scope>>)>,
+    filter: &Filter,
+    update: &Sender<WalkEvent>,
+    on_dir: &mut dyn FnMut(&Path, &Arc<Scope>),
+) -> Result<()> {
+    // Directories to walk, already entered, with the scope they were found in.
+    let mut dirs = vec![];
+    for (root, scope) in roots {
+        // Only roots are followed when they are symlinks.
+        let md = match &scope {
+            None => fs::metadata(&root),
+            Some(_) => fs::symlink_metadata(&root),
+        };
+        let md = match md {
+            Ok(md) => md,
+            Err(err) => {
+                update.blocking_send(WalkEvent::Error(root, anyhow::Error::new(err)))?;
+                continue;
+            }
+        };
+        let scope = scope.unwrap_or_else(|| filter.root(&md));
+        if md.is_dir() {
+            if filter.enter(&scope, &root, &md) {
+                dirs.push((root, scope, md));
+            }
             continue;
         }
-        if md.is_file() {
-            update.blocking_send(WalkEvent::File(ShallowInfo::new(current, md.len())))?;
+        if !filter.accepts(&scope, &root, &md) {
             continue;
         }
-        if !md.is_dir() {
-            tracing::warn!("skipping unknown type {:?}: {:?}", current, md);
-            continue;
+        match ShallowInfo::from_entry(root.clone(), &md) {
+            Ok(Some(info)) => update.blocking_send(WalkEvent::File(info))?,
+            Ok(None) => tracing::warn!("skipping unknown type {:?}: {:?}", root, md),
+            Err(err) => update.blocking_send(WalkEvent::Error(root, anyhow::Error::new(err)))?,
         }
-        // Now handle the directory content.
-        match fs::read_dir(&current) {
-            Ok(subdirs) => {
-                for entry in subdirs {
-                    match entry {
-                        Ok(entry) => {
-                            roots.push(entry.path());
-                        }
-                        Err(err) => {
-                            update.blocking_send(WalkEvent::Error(
-                                current.clone(),
-                                anyhow::Error::new(err),
-                            ))?;
-                        }
-                    }
+    }
+
+    while let Some((current, scope, md)) = dirs.pop() {
+        let scope = match filter.scope(&scope, &current) {
+            Ok(scope) => scope,
+            Err((scope, err)) => {
+                update.blocking_send(WalkEvent::Error(current.clone(), err))?;
+                scope
+            }
+        };
+        on_dir(&current, &scope);
+        let mut errors = vec![];
+        match list_dir(filter, &scope, &current, &md, &mut errors) {
+            Ok(listing) => {
+                for info in listing.files {
+                    update.blocking_send(WalkEvent::File(info))?;
                 }
+                update.blocking_send(WalkEvent::File(listing.dir))?;
+                dirs.extend(
+                    listing
+                        .subdirs
+                        .into_iter()
+                        .map(|(path, md)| (path, scope.clone(), md)),
+                );
             }
+            Err(err) => errors.push((current, anyhow::Error::new(err))),
+        }
+        for (path, err) in errors {
+            update.blocking_send(WalkEvent::Error(path, err))?;
+        }
+    }
+    Ok(())
+}
+
+/// Content of a directory, as far as it is backed up.
+pub(crate) struct Listing {
+    /// The directory itself, with the names of its entries.
+    pub dir: ShallowInfo,
+    /// Subdirectories to enter, with their metadata.
+    pub subdirs: Vec<(PathBuf, fs::Metadata)>,
+    /// Other entries.
+    pub files: Vec<ShallowInfo>,
+}
+
+/// Lists the entries of `dir` accepted by the filter, given `scope`, the scope of its content.
+/// Entries which can't be read are skipped and added to `errors`.
+pub(crate) fn list_dir(
+    filter: &Filter,
+    scope: &Scope,
+    dir: &Path,
+    md: &fs::Metadata,
+    errors: &mut Vec<(PathBuf, anyhow::Error)>,
+) -> std::io::Result<Listing> {
+    let mut listing = Listing {
+        dir: ShallowInfo::from_metadata(dir.to_owned(), md),
+        subdirs: vec![],
+        files: vec![],
+    };
+    let mut names = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                errors.push((dir.to_owned(), anyhow::Error::new(err)));
+                continue;
+            }
+        };
+        let path = entry.path();
+        let md = match fs::symlink_metadata(&path) {
+            Ok(md) => md,
             Err(err) => {
-                update.blocking_send(WalkEvent::Error(current, anyhow::Error::new(err)))?;
+                errors.push((path, anyhow::Error::new(err)));
+                continue;
             }
+        };
+        if md.is_dir() {
+            if filter.enter(scope, &path, &md) {
+                names.push(entry.file_name());
+                listing.subdirs.push((path, md));
+            }
+            continue;
+        }
+        if !filter.accepts(scope, &path, &md) {
+            continue;
+        }
+        match ShallowInfo::from_entry(path.clone(), &md) {
+            Ok(Some(info)) => {
+                names.push(entry.file_name());
+                listing.files.push(info);
+            }
+            Ok(None) => tracing::warn!("skipping unknown type {:?}: {:?}", path, md),
+            Err(err) => errors.push((path, anyhow::Error::new(err))),
         }
     }
-    Ok(())
+    names.sort();
+    // Directories can't be linked, their link count only reflects their subdirectories.
+    listing.dir = ShallowInfo {
+        len: 0,
+        ..listing.dir
+    }
+    .with_kind(FileType::Directory(names))
+    .with_links(1);
+    Ok(listing)
 }
 
 /// Errors returned by read_chunks.
@@ -309,6 +642,55 @@ pub fn read_chunks(path: &Path, chunk_size: usize, chunks: Sender<Bytes>) -> Res
     Ok(total_size)
 }
 
+/// Provide the content of a file in content-defined chunks, using FastCDC. Chunks are between
+/// `min` and `max` bytes, except for the last one which may be smaller, and `avg` bytes on
+/// average. Parameters must have been validated with `Chunking::validate`.
+#[tracing::instrument]
+pub fn read_content_defined_chunks(
+    path: &Path,
+    min: u32,
+    avg: u32,
+    max: u32,
+    chunks: Sender<Bytes>,
+) -> Result<usize> {
+    Chunking::ContentDefined { min, avg, max }.validate()?;
+    let file = fs::File::open(path)?;
+    let md = file.metadata()?;
+    // Preserve access time to avoid messing with other tools.
+    let atime = FileTime::from_last_access_time(&md);
+    let mut total_size = 0;
+    for chunk in fastcdc::v2020::StreamCDC::new(Uninterrupted(file), min, avg, max) {
+        let chunk = chunk.map_err(std::io::Error::from)?;
+        total_size += chunk.length;
+        if chunks.blocking_send(chunk.data.into()).is_err() {
+            return Err(ChunkingError::ChannelClosed.into());
+        }
+    }
+    filetime::set_file_atime(path, atime)?;
+
+    // A completely empty file still needs to generate a single empty chunk,
+    // to ensure there is a trace of it for recovery.
+    if total_size == 0 {
+        chunks.blocking_send(Bytes::new())?;
+    }
+    Ok(total_size)
+}
+
+/// Retries reads that were interrupted: random interrupts should not lead to different chunks
+/// being generated.
+struct Uninterrupted<R>(R);
+
+impl<R: Read> Read for Uninterrupted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            match self.0.read(buf) {
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use filetime::FileTime;
@@ -317,10 +699,94 @@ mod tests {
     use super::*;
     use std::path::Path;
 
-    #[test]
+    #[tokio::test]
     #[cfg(target_family = "unix")]
-    fn handle_symlinks() {
-        // TODO: test symlinks on linux by manually setting up the test dir.
+    async fn handle_symlinks() -> anyhow::Result<()> {
+        use std::os::unix::fs::symlink;
+        let tmpdir = tempfile::tempdir()?;
+        let root = tmpdir.path();
+        fs::create_dir(root.join("dir"))?;
+        fs::write(root.join("dir").join("file"), "content")?;
+        fs::hard_link(root.join("dir").join("file"), root.join("hardlink"))?;
+        symlink("dir/file", root.join("link"))?;
+        symlink("dir", root.join("dirlink"))?;
+        symlink("missing", root.join("dangling"))?;
+
+        let walk = |roots: Vec<PathBuf>| async move {
+            let (tx, mut rx) = channel(10);
+            let ops = AsyncFileOps::new().await;
+            ops.walk(roots, Filter::default(), tx).await?;
+            let mut found = vec![];
+            while let Some(WalkEvent::File(info)) = rx.recv().await {
+                found.push((info.file().to_owned(), info.kind().clone(), info.links()));
+            }
+            found.sort_by(|a, b| a.0.cmp(&b.0));
+            anyhow::Ok(found)
+        };
+
+        // Symlinks are reported without being followed.
+        let symlink = |target: &str| FileType::Symlink(target.into());
+        let dir =
+            |entries: &[&str]| FileType::Directory(entries.iter().map(|e| e.into()).collect());
+        assert_eq!(
+            walk(vec![root.to_owned()]).await?,
+            vec![
+                (
+                    root.to_owned(),
+                    dir(&["dangling", "dir", "dirlink", "hardlink", "link"]),
+                    1
+                ),
+                (root.join("dangling"), symlink("missing"), 1),
+                (root.join("dir"), dir(&["file"]), 1),
+                (root.join("dir/file"), FileType::Regular, 2),
+                (root.join("dirlink"), symlink("dir"), 1),
+                (root.join("hardlink"), FileType::Regular, 2),
+                (root.join("link"), symlink("dir/file"), 1),
+            ]
+        );
+
+        // Unless they are roots.
+        assert_eq!(
+            walk(vec![root.join("dirlink")]).await?,
+            vec![
+                (root.join("dirlink"), dir(&["file"]), 1),
+                (root.join("dirlink/file"), FileType::Regular, 2)
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_directories() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let root = tmpdir.path();
+        fs::create_dir_all(root.join("empty"))?;
+        fs::create_dir_all(root.join("cache"))?;
+        fs::write(root.join("cache").join("file"), "")?;
+        fs::write(root.join("file"), "")?;
+        fs::write(root.join("file.tmp"), "")?;
+
+        let (tx, mut rx) = channel(10);
+        let ops = AsyncFileOps::new().await;
+        let filter = Filter::new(&["*.tmp".to_string(), "cache/".to_string()], &[])?;
+        ops.walk(vec![root.to_owned()], filter, tx).await?;
+        let mut dirs = vec![];
+        while let Some(WalkEvent::File(info)) = rx.recv().await {
+            if let FileType::Directory(entries) = info.kind() {
+                dirs.push((info.file().to_owned(), entries.clone()));
+            }
+        }
+        dirs.sort();
+
+        // Only entries which are backed up are listed, and empty directories are kept.
+        assert_eq!(
+            dirs,
+            vec![
+                (root.to_owned(), vec!["empty".into(), "file".into()]),
+                (root.join("empty"), vec![]),
+            ]
+        );
+        Ok(())
     }
 
     #[tokio::test]
@@ -329,8 +795,13 @@ mod tests {
 
         let results = do_walk(vec![root.clone()]).await?;
 
-        let expected: Vec<PathBuf> =
-            vec![root.join("a").join("file"), root.join("b").join("file2")];
+        let expected: Vec<PathBuf> = vec![
+            root.clone(),
+            root.join("a"),
+            root.join("a").join("file"),
+            root.join("b"),
+            root.join("b").join("file2"),
+        ];
         assert_eq!(results, expected);
         Ok(())
     }
@@ -345,6 +816,30 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    #[cfg(target_family = "unix")]
+    fn stat_tracks_changes() -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("file");
+        fs::write(&path, "abc")?;
+        filetime::set_file_mtime(&path, FileTime::from_unix_time(1000, 5))?;
+        let before = ShallowInfo::from_metadata(path.clone(), &fs::metadata(&path)?);
+        assert_eq!(before.stat().mtime, 1000 * 1_000_000_000 + 5);
+
+        // Same length, different content.
+        fs::write(&path, "abd")?;
+        let after = ShallowInfo::from_metadata(path.clone(), &fs::metadata(&path)?);
+        assert_eq!(after.len(), before.len());
+        assert_ne!(after.stat().mtime, before.stat().mtime);
+        assert_eq!(after.stat().inode, before.stat().inode);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        let chmod = ShallowInfo::from_metadata(path.clone(), &fs::metadata(&path)?);
+        assert_eq!(chmod.stat().mode & 0o777, 0o600);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn read_empty_file() -> anyhow::Result<()> {
         let (tx, mut rx) = channel::<Bytes>(1);
@@ -353,7 +848,7 @@ mod tests {
 
         let res = tokio::spawn(async move {
             let ops = AsyncFileOps::new().await;
-            ops.read_chunks(&testdir().join("a").join("file"), 16, tx)
+            ops.read_chunks(&testdir().join("a").join("file"), Chunking::Fixed(16), tx)
                 .await
                 .unwrap();
         });
@@ -372,7 +867,9 @@ mod tests {
         // We need both the fetcher and the chunk generator to run in parallel.
         let res = tokio::spawn(async move {
             let ops = AsyncFileOps::new().await;
-            ops.read_chunks(&file, 2, tx).await.unwrap();
+            ops.read_chunks(&file, Chunking::Fixed(2), tx)
+                .await
+                .unwrap();
         });
 
         assert_eq!(
@@ -405,7 +902,9 @@ mod tests {
 
             tokio::spawn(async move {
                 let ops = AsyncFileOps::new().await;
-                ops.read_chunks(&path, 2, tx).await.unwrap();
+                ops.read_chunks(&path, Chunking::Fixed(2), tx)
+                    .await
+                    .unwrap();
             });
             handler.await;
         }
@@ -416,6 +915,78 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn content_defined_chunks() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let original = tmpdir.path().join("original");
+        let edited = tmpdir.path().join("edited");
+        let chunking = Chunking::ContentDefined {
+            min: 1024,
+            avg: 4096,
+            max: 16384,
+        };
+
+        // Pseudo-random content, so that boundaries can be found.
+        let mut state = 1u64;
+        let data: Vec<u8> = (0..200_000)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (state >> 56) as u8
+            })
+            .collect();
+        std::fs::write(&original, &data)?;
+        std::fs::write(&edited, [b"x".as_slice(), &data].concat())?;
+
+        let original = read_all(&original, chunking).await?;
+        let edited = read_all(&edited, chunking).await?;
+
+        assert_eq!(original.concat(), data);
+        assert!(original.len() > 10);
+        assert!(original[..original.len() - 1]
+            .iter()
+            .all(|chunk| (1024..=16384).contains(&chunk.len())));
+        // Inserting a byte at the start only changes the first chunk.
+        assert_ne!(original[0], edited[0]);
+        assert_eq!(original[1..], edited[1..]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn content_defined_empty_file() -> anyhow::Result<()> {
+        let chunking = Chunking::ContentDefined {
+            min: 64,
+            avg: 256,
+            max: 1024,
+        };
+        let chunks = read_all(&testdir().join("a").join("file"), chunking).await?;
+        assert_eq!(chunks, vec![Bytes::new()]);
+        Ok(())
+    }
+
+    #[test]
+    fn validate_chunking() {
+        assert!(Chunking::Fixed(1).validate().is_ok());
+        assert!(Chunking::Fixed(0).validate().is_err());
+        let cdc = |min, avg, max| Chunking::ContentDefined { min, avg, max }.validate();
+        assert!(cdc(1024, 4096, 16384).is_ok());
+        assert!(cdc(1, 4096, 16384).is_err());
+        assert!(cdc(8192, 4096, 16384).is_err());
+        assert!(cdc(1024, 4096, 1 << 30).is_err());
+    }
+
+    // Helper function that reads all the chunks of `path`.
+    async fn read_all(path: &Path, chunking: Chunking) -> anyhow::Result<Vec<Bytes>> {
+        let (tx, mut rx) = channel::<Bytes>(1);
+        let path = path.to_owned();
+        let reader = tokio::spawn(async move {
+            let ops = AsyncFileOps::new().await;
+            ops.read_chunks(&path, chunking, tx).await
+        });
+        let chunks = get_chunks(&mut rx).await;
+        reader.await??;
+        Ok(chunks)
+    }
+
     // Helper function that collects all the chunks until |rx| closes, and
     // returns them.
     async fn get_chunks(rx: &mut Receiver<Bytes>) -> Vec<Bytes> {
@@ -431,7 +1002,7 @@ mod tests {
 
         let success = tokio::spawn(async move {
             let ops = AsyncFileOps::new().await;
-            ops.walk(roots, tx).await
+            ops.walk(roots, Filter::default(), tx).await
         });
 
         let mut results = vec![];
@@ -444,6 +1015,7 @@ mod tests {
                     // We don't expect errors at this stage.
                     return Err(err);
                 }
+                WalkEvent::Walking | WalkEvent::Walked => {}
             }
         }
         success.await??;
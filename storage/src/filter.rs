@@ -0,0 +1,328 @@
+//! Rules deciding which files under the roots are backed up.
+
+use crate::filesystem::Stat;
+use anyhow::{anyhow, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::{fs, io::Read, path::Path, sync::Arc};
+
+/// Marks directories holding caches, see https://bford.info/cachedir/.
+const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Rules deciding which files are backed up. By default, everything is.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    exclude: Gitignore,
+    include: Option<Gitignore>,
+    ignore_file: Option<String>,
+    skip_caches: bool,
+    one_filesystem: bool,
+    max_size: Option<u64>,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter {
+            exclude: Gitignore::empty(),
+            include: None,
+            ignore_file: None,
+            skip_caches: false,
+            one_filesystem: false,
+            max_size: None,
+        }
+    }
+}
+
+impl Filter {
+    /// Creates a filter from gitignore-style patterns, where a leading "/" anchors a pattern to
+    /// the filesystem root. Files matching `exclude` are not backed up and, unless `include` is
+    /// empty, only files matching `include` are.
+    pub fn new(exclude: &[String], include: &[String]) -> Result<Filter> {
+        let include = match include.is_empty() {
+            true => None,
+            false => Some(patterns(include)?),
+        };
+        Ok(Filter {
+            exclude: patterns(exclude)?,
+            include,
+            ..Default::default()
+        })
+    }
+
+    /// Honors the gitignore-style files with this name found in any directory. They take
+    /// precedence over the patterns of the filter.
+    pub fn ignore_file(mut self, name: Option<String>) -> Self {
+        self.ignore_file = name;
+        self
+    }
+
+    /// Skips directories tagged as caches with a CACHEDIR.TAG file.
+    pub fn skip_caches(mut self, skip: bool) -> Self {
+        self.skip_caches = skip;
+        self
+    }
+
+    /// Does not cross into other filesystems than the one of each root.
+    pub fn one_filesystem(mut self, one: bool) -> Self {
+        self.one_filesystem = one;
+        self
+    }
+
+    /// Skips files larger than `max_size` bytes.
+    pub fn max_size(mut self, max_size: Option<u64>) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Returns the scope in which a root is checked.
+    pub(crate) fn root(&self, md: &fs::Metadata) -> Arc<Scope> {
+        Arc::new(Scope {
+            rules: None,
+            parent: None,
+            device: Stat::new(md).device,
+        })
+    }
+
+    /// Returns whether the content of a directory found in `scope` is backed up.
+    pub(crate) fn enter(&self, scope: &Scope, path: &Path, md: &fs::Metadata) -> bool {
+        if self.excluded(scope, path, true) {
+            tracing::debug!("excluding {:?}", path);
+            return false;
+        }
+        if self.one_filesystem && Stat::new(md).device != scope.device {
+            tracing::debug!("not crossing into the filesystem of {:?}", path);
+            return false;
+        }
+        if self.skip_caches && is_cache(path) {
+            tracing::debug!("skipping cache {:?}", path);
+            return false;
+        }
+        true
+    }
+
+    /// Returns the scope for the content of a directory, once entered. The scope of the parent
+    /// is returned along with the error if the ignore file of the directory is invalid.
+    pub(crate) fn scope(
+        &self,
+        parent: &Arc<Scope>,
+        path: &Path,
+    ) -> std::result::Result<Arc<Scope>, (Arc<Scope>, anyhow::Error)> {
+        let Some(name) = &self.ignore_file else {
+            return Ok(parent.clone());
+        };
+        let file = path.join(name);
+        if !file.is_file() {
+            return Ok(parent.clone());
+        }
+        match Gitignore::new(&file) {
+            (rules, None) => Ok(Arc::new(Scope {
+                rules: Some(rules),
+                parent: Some(parent.clone()),
+                device: parent.device,
+            })),
+            (_, Some(err)) => Err((
+                parent.clone(),
+                anyhow!("invalid ignore file {:?}: {}", file, err),
+            )),
+        }
+    }
+
+    /// Returns whether a file found in `scope` is backed up.
+    pub(crate) fn accepts(&self, scope: &Scope, path: &Path, md: &fs::Metadata) -> bool {
+        if self.excluded(scope, path, false) {
+            tracing::debug!("excluding {:?}", path);
+            return false;
+        }
+        if let Some(include) = &self.include {
+            if !include.matched_path_or_any_parents(path, false).is_ignore() {
+                return false;
+            }
+        }
+        if self.max_size.is_some_and(|max_size| md.len() > max_size) {
+            tracing::debug!("skipping large file {:?}", path);
+            return false;
+        }
+        true
+    }
+
+    fn excluded(&self, scope: &Scope, path: &Path, is_dir: bool) -> bool {
+        scope
+            .excluded(path, is_dir)
+            .unwrap_or_else(|| self.exclude.matched(path, is_dir).is_ignore())
+    }
+}
+
+/// Rules applying to the content of a directory: the ignore files found in it and its parents,
+/// up to the root.
+#[derive(Debug)]
+pub(crate) struct Scope {
+    rules: Option<Gitignore>,
+    parent: Option<Arc<Scope>>,
+    /// Filesystem of the root.
+    device: u64,
+}
+
+impl Scope {
+    /// Returns whether the closest ignore file matching `path` excludes it.
+    fn excluded(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        if let Some(rules) = &self.rules {
+            let matched = rules.matched(path, is_dir);
+            if !matched.is_none() {
+                return Some(matched.is_ignore());
+            }
+        }
+        self.parent.as_ref()?.excluded(path, is_dir)
+    }
+}
+
+fn patterns(lines: &[String]) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new("/");
+    for line in lines {
+        builder.add_line(None, line)?;
+    }
+    Ok(builder.build()?)
+}
+
+fn is_cache(dir: &Path) -> bool {
+    let mut signature = [0; CACHEDIR_SIGNATURE.len()];
+    fs::File::open(dir.join(CACHEDIR_TAG))
+        .and_then(|mut tag| tag.read_exact(&mut signature))
+        .is_ok_and(|_| signature == CACHEDIR_SIGNATURE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::{AsyncFileOps, FileType, WalkEvent};
+    use std::path::PathBuf;
+    use tokio::sync::mpsc;
+
+    async fn walk(root: &Path, filter: Filter) -> Result<Vec<PathBuf>> {
+        let (tx, mut rx) = mpsc::channel(1);
+        let roots = vec![root.to_owned()];
+        let walk = tokio::spawn(async move {
+            let ops = AsyncFileOps::new().await;
+            ops.walk(roots, filter, tx).await
+        });
+        let mut files = vec![];
+        while let Some(update) = rx.recv().await {
+            match update {
+                // Directories are listed with the files they contain.
+                WalkEvent::File(info) if matches!(info.kind(), FileType::Directory(_)) => {}
+                WalkEvent::File(info) => {
+                    files.push(info.file().strip_prefix(root)?.to_owned());
+                }
+                WalkEvent::Error(_, err) => return Err(err),
+                WalkEvent::Walking | WalkEvent::Walked => {}
+            }
+        }
+        walk.await??;
+        files.sort();
+        Ok(files)
+    }
+
+    fn create(root: &Path, files: &[(&str, &str)]) -> Result<()> {
+        for (path, content) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, content)?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exclude_and_include() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let root = tmpdir.path();
+        create(
+            root,
+            &[
+                ("src/main.rs", ""),
+                ("src/main.rs.bak", ""),
+                ("node_modules/lib/index.js", ""),
+                ("docs/node_modules", ""),
+                ("docs/notes.txt", ""),
+            ],
+        )?;
+        let strings =
+            |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+
+        let filter = Filter::new(&strings(&["node_modules/", "*.bak"]), &[])?;
+        assert_eq!(
+            walk(root, filter).await?,
+            vec![
+                PathBuf::from("docs/node_modules"),
+                "docs/notes.txt".into(),
+                "src/main.rs".into()
+            ]
+        );
+
+        // Included directories take all their content.
+        let docs = root.join("docs").to_string_lossy().to_string();
+        let filter = Filter::new(&strings(&["node_modules"]), &[docs, "*.rs".to_string()])?;
+        assert_eq!(
+            walk(root, filter).await?,
+            vec![PathBuf::from("docs/notes.txt"), "src/main.rs".into()]
+        );
+        assert!(Filter::new(&strings(&["a/**/b/[z-a]"]), &[]).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ignore_files() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let root = tmpdir.path();
+        create(
+            root,
+            &[
+                (".pistonignore", "*.log\n"),
+                ("app.log", ""),
+                ("logs/.pistonignore", "!keep.log\n"),
+                ("logs/keep.log", ""),
+                ("logs/drop.log", ""),
+                ("logs/tmp/old.tmp", ""),
+            ],
+        )?;
+
+        let filter = Filter::new(&["*.tmp".to_string()], &[])?;
+        let ignore_file = Some(".pistonignore".to_string());
+        assert_eq!(
+            walk(root, filter.clone()).await?.len(),
+            3 + 2 // Including the ignore files themselves.
+        );
+        assert_eq!(
+            walk(root, filter.ignore_file(ignore_file)).await?,
+            vec![
+                PathBuf::from(".pistonignore"),
+                "logs/.pistonignore".into(),
+                "logs/keep.log".into()
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skip_caches_and_large_files() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let root = tmpdir.path();
+        let tag = "Signature: 8a477f597d28d172789f06886806bc55\n# A cache.\n";
+        create(
+            root,
+            &[
+                ("target/CACHEDIR.TAG", tag),
+                ("target/debug/binary", ""),
+                ("large", "0123456789"),
+                ("fake/CACHEDIR.TAG", "Not a signature"),
+                ("fake/file", "ab"),
+                ("small", "0123"),
+            ],
+        )?;
+
+        let filter = Filter::default().skip_caches(true).max_size(Some(4));
+        assert_eq!(
+            walk(root, filter).await?,
+            vec![PathBuf::from("fake/file"), "small".into()]
+        );
+        Ok(())
+    }
+}
@@ -1,12 +1,15 @@
 //! Canonical layout of the encrypted data on disk.
 //!
-//! This format is used to pass data around in a compatible way
-//! but does not represent how the sink stores it. It has a simple
-//! structure:
+//! This format is used to pass data around in a compatible way,
+//! and is also how the sink stores the data of each source. Writes
+//! are durable once they return. It has a simple structure:
 //!   - root directory for multiple files
 //!     - ${file_id directory for each file
-//!       - ${version}.dsc descriptor file of a specific version
+//!       - v${version}.dsc first descriptor of a specific version
+//!       - v${version}.${index}.dsc other descriptors of the version, if split
 //!       - ${block_id}.blk block file of a block used by one of the descriptors
+//!       - ${entry}.sum checksum of one of the descriptor or block files above, if recorded
+//!       - .${random}.tmp file being written, until renamed to one of the names above
 
 use anyhow::Context;
 use crypto::model;
@@ -14,8 +17,12 @@ use prost::Message;
 use std::{
     io::Write,
     path::{Path, PathBuf},
+    time::{Duration, SystemTime},
 };
 
+/// Suffix of the files being written, before they are renamed.
+const TMP_SUFFIX: &str = ".tmp";
+
 /// The root directory for the encrypted data.
 pub struct Root {
     dir: PathBuf,
@@ -26,6 +33,24 @@ pub struct File {
     dir: PathBuf,
 }
 
+/// Checksum of both parts of a block or descriptor, which detects corruption without the key of
+/// the source. This is SHA-256 over the length of the verified part, then both parts.
+pub fn checksum(verified: &[u8], protected: &[u8]) -> Vec<u8> {
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    context.update(&(verified.len() as u64).to_le_bytes());
+    context.update(verified);
+    context.update(protected);
+    context.finish().as_ref().to_vec()
+}
+
+/// Whether reading or removing an entry failed because it does not exist.
+pub fn is_not_found(err: &anyhow::Error) -> bool {
+    matches!(
+        err.root_cause().downcast_ref::<std::io::Error>(),
+        Some(io) if io.kind() == std::io::ErrorKind::NotFound
+    )
+}
+
 pub fn read_descriptor(path: &Path) -> anyhow::Result<(model::Descriptor, Root)> {
     let data = std::fs::read(path)?;
     let pb = layout_proto::Descriptor::decode(&data[..])?;
@@ -34,11 +59,7 @@ pub fn read_descriptor(path: &Path) -> anyhow::Result<(model::Descriptor, Root)>
         .context("No parent")?
         .parent()
         .context("No parent")?;
-    let desc = model::Descriptor {
-        protected: pb.protected,
-        verified: pb.verified,
-    };
-    Ok((desc, Root::new(dir.to_owned())))
+    Ok((descriptor_from_proto(pb), Root::new(dir.to_owned())))
 }
 
 impl Root {
@@ -53,9 +74,81 @@ impl Root {
         let dir = self.dir.join(hex::encode(file_id.as_bytes()));
         if !dir.exists() {
             std::fs::create_dir(&dir).context(format!("Failed to create output dir {:?}", &dir))?;
+            sync_dir(&self.dir)?;
+        }
+        Ok(File { dir })
+    }
+
+    /// Get a handler for a specific file that must already exist on disk.
+    pub fn existing_file(&self, file_id: &model::FileId) -> anyhow::Result<File> {
+        let dir = self.dir.join(hex::encode(file_id.as_bytes()));
+        if !dir.is_dir() {
+            anyhow::bail!("Unknown file {:?}", &dir);
         }
         Ok(File { dir })
     }
+
+    /// List all the files present on disk. Entries that don't follow the layout are ignored.
+    pub fn files(&self) -> anyhow::Result<Vec<model::FileId>> {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let file_id = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| hex::decode(name).ok())
+                .and_then(|id| model::FileId::try_from(id.as_slice()).ok());
+            if let Some(file_id) = file_id {
+                files.push(file_id);
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// Convert a block to its canonical proto.
+pub fn block_to_proto(block: &model::Block) -> layout_proto::Block {
+    layout_proto::Block {
+        protected: block.protected.as_slice().to_vec(),
+        verified: block.verified.as_slice().to_vec(),
+    }
+}
+
+/// Convert a block from its canonical proto.
+pub fn block_from_proto(pb: layout_proto::Block) -> model::Block {
+    model::Block {
+        protected: pb.protected.into(),
+        verified: pb.verified,
+    }
+}
+
+/// Convert a descriptor to its canonical proto.
+pub fn descriptor_to_proto(descriptor: &model::Descriptor) -> layout_proto::Descriptor {
+    layout_proto::Descriptor {
+        protected: descriptor.protected.as_slice().to_vec(),
+        verified: descriptor.verified.as_slice().to_vec(),
+    }
+}
+
+/// Convert a descriptor from its canonical proto.
+pub fn descriptor_from_proto(pb: layout_proto::Descriptor) -> model::Descriptor {
+    model::Descriptor {
+        protected: pb.protected,
+        verified: pb.verified,
+    }
+}
+
+/// Serialize a block in its canonical format.
+pub fn encode_block(block: &model::Block) -> Vec<u8> {
+    block_to_proto(block).encode_to_vec()
+}
+
+/// Serialize a descriptor in its canonical format.
+pub fn encode_descriptor(descriptor: &model::Descriptor) -> Vec<u8> {
+    descriptor_to_proto(descriptor).encode_to_vec()
 }
 
 impl File {
@@ -65,14 +158,7 @@ impl File {
         block: &model::Block,
         block_id: &model::BlockId,
     ) -> anyhow::Result<()> {
-        let pb = layout_proto::Block {
-            protected: block.protected.as_slice().to_vec(),
-            verified: block.verified.as_slice().to_vec(),
-        };
-        let mut dst = bytes::BytesMut::new();
-        pb.encode(&mut dst)?;
-
-        store(&self.block_path(block_id), &dst)
+        store(&self.block_path(block_id), &encode_block(block))
     }
 
     /// Write a descriptor to disk.
@@ -80,38 +166,404 @@ impl File {
         &self,
         descriptor: &model::Descriptor,
         version: u32,
+        index: u16,
     ) -> anyhow::Result<()> {
-        let pb = layout_proto::Descriptor {
-            protected: descriptor.protected.as_slice().to_vec(),
-            verified: descriptor.verified.as_slice().to_vec(),
-        };
-        let mut dst = bytes::BytesMut::new();
-        pb.encode(&mut dst)?;
+        store(
+            &self.descriptor_path(version, index),
+            &encode_descriptor(descriptor),
+        )
+    }
+
+    /// List all the versions for which a descriptor is present on disk, in increasing order.
+    pub fn versions(&self) -> anyhow::Result<Vec<model::Version>> {
+        let mut versions = vec![];
+        for entry in std::fs::read_dir(&self.dir)? {
+            let version = entry?
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix('v'))
+                .and_then(|name| name.strip_suffix(".dsc"))
+                .and_then(|version| version.parse::<model::Version>().ok());
+            if let Some(version) = version {
+                versions.push(version);
+            }
+        }
+        versions.sort();
+        Ok(versions)
+    }
+
+    /// Remove all the descriptors of a version. The first descriptor goes first, so that the
+    /// version is no longer listed even if the removal is interrupted. Returns how many
+    /// descriptors were removed.
+    pub fn remove_version(&self, version: model::Version) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        let mut index = 0;
+        loop {
+            match std::fs::remove_file(self.descriptor_path(version, index)) {
+                Ok(()) => {
+                    remove_if_exists(&sum_path(self.descriptor_path(version, index)))?;
+                    removed += 1;
+                }
+                // Later descriptors may remain from an interrupted removal.
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound && index == 0 => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => break,
+                Err(err) => return Err(err.into()),
+            }
+            index = index.checked_add(1).context("Too many descriptors")?;
+        }
+        sync_dir(&self.dir)?;
+        Ok(removed)
+    }
 
-        store(&self.descriptor_path(version), &dst)
+    pub fn read_descriptor(
+        &self,
+        version: model::Version,
+        index: u16,
+    ) -> anyhow::Result<model::Descriptor> {
+        let data = std::fs::read(self.descriptor_path(version, index))?;
+        let pb = layout_proto::Descriptor::decode(&data[..])?;
+        Ok(descriptor_from_proto(pb))
     }
 
     pub fn read_block(&self, block_id: &model::BlockId) -> anyhow::Result<model::Block> {
         let data = std::fs::read(self.block_path(block_id))?;
         let pb = layout_proto::Block::decode(&data[..])?;
-        Ok(model::Block {
-            protected: pb.protected.into(),
-            verified: pb.verified,
+        Ok(block_from_proto(pb))
+    }
+
+    /// Whether a descriptor is already present on disk.
+    pub fn has_descriptor(&self, version: model::Version, index: u16) -> bool {
+        self.descriptor_path(version, index).exists()
+    }
+
+    /// Whether a block is already present on disk.
+    pub fn has_block(&self, block_id: &model::BlockId) -> bool {
+        self.block_path(block_id).exists()
+    }
+
+    /// List all the blocks present on disk.
+    pub fn blocks(&self) -> anyhow::Result<Vec<model::BlockId>> {
+        let mut blocks = vec![];
+        for entry in std::fs::read_dir(&self.dir)? {
+            let block_id = entry?
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(".blk"))
+                .and_then(|name| hex::decode(name).ok())
+                .and_then(|id| model::BlockId::try_from(id.as_slice()).ok());
+            if let Some(block_id) = block_id {
+                blocks.push(block_id);
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// Filesystem metadata of a block, for its size and when it was written.
+    pub fn block_metadata(&self, block_id: &model::BlockId) -> anyhow::Result<std::fs::Metadata> {
+        Ok(std::fs::metadata(self.block_path(block_id))?)
+    }
+
+    /// Remove a block from disk.
+    pub fn remove_block(&self, block_id: &model::BlockId) -> anyhow::Result<()> {
+        std::fs::remove_file(self.block_path(block_id))?;
+        remove_if_exists(&sum_path(self.block_path(block_id)))?;
+        sync_dir(&self.dir)
+    }
+
+    /// Write the checksum of a block, see `checksum`.
+    pub fn write_block_checksum(
+        &self,
+        block_id: &model::BlockId,
+        checksum: &[u8],
+    ) -> anyhow::Result<()> {
+        store(&sum_path(self.block_path(block_id)), checksum)
+    }
+
+    /// Read the checksum of a block, if one was written.
+    pub fn read_block_checksum(
+        &self,
+        block_id: &model::BlockId,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        read_if_exists(&sum_path(self.block_path(block_id)))
+    }
+
+    /// Write the checksum of a descriptor, see `checksum`.
+    pub fn write_descriptor_checksum(
+        &self,
+        version: model::Version,
+        index: u16,
+        checksum: &[u8],
+    ) -> anyhow::Result<()> {
+        store(&sum_path(self.descriptor_path(version, index)), checksum)
+    }
+
+    /// Read the checksum of a descriptor, if one was written.
+    pub fn read_descriptor_checksum(
+        &self,
+        version: model::Version,
+        index: u16,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        read_if_exists(&sum_path(self.descriptor_path(version, index)))
+    }
+
+    /// Remove the checksum of a descriptor, before the descriptor is replaced.
+    pub fn remove_descriptor_checksum(
+        &self,
+        version: model::Version,
+        index: u16,
+    ) -> anyhow::Result<()> {
+        remove_if_exists(&sum_path(self.descriptor_path(version, index)))?;
+        sync_dir(&self.dir)
+    }
+
+    /// Remove the temporary files left by interrupted writes, unless they were modified less than
+    /// `grace` ago as they may still be written. Returns how many were removed.
+    pub fn remove_temporaries(&self, grace: Duration) -> anyhow::Result<u64> {
+        self.remove_stale(grace, |path| path.to_string_lossy().ends_with(TMP_SUFFIX))
+    }
+
+    /// Remove the checksums left without their entry by an interrupted write or removal, unless
+    /// they were modified less than `grace` ago as their entry may still be written. Returns how
+    /// many were removed.
+    pub fn remove_orphaned_checksums(&self, grace: Duration) -> anyhow::Result<u64> {
+        self.remove_stale(grace, |path| {
+            path.extension().is_some_and(|ext| ext == "sum") && !path.with_extension("").exists()
         })
     }
 
+    fn remove_stale(&self, grace: Duration, stale: impl Fn(&Path) -> bool) -> anyhow::Result<u64> {
+        let now = SystemTime::now();
+        let mut removed = 0;
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if !stale(&entry.path()) {
+                continue;
+            }
+            let age = now
+                .duration_since(entry.metadata()?.modified()?)
+                .unwrap_or(Duration::ZERO);
+            if age >= grace {
+                remove_if_exists(&entry.path())?;
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            sync_dir(&self.dir)?;
+        }
+        Ok(removed)
+    }
+
+    /// Remove the directory of the file if it holds nothing anymore. Returns whether it was
+    /// removed.
+    pub fn remove_if_empty(self) -> anyhow::Result<bool> {
+        if std::fs::read_dir(&self.dir)?.next().is_some() {
+            return Ok(false);
+        }
+        std::fs::remove_dir(&self.dir)?;
+        sync_dir(self.dir.parent().context("No parent")?)?;
+        Ok(true)
+    }
+
     fn block_path(&self, block: &model::BlockId) -> PathBuf {
         self.dir
             .join(format!("{}.blk", hex::encode(block.as_bytes())))
     }
 
-    fn descriptor_path(&self, version: u32) -> PathBuf {
-        self.dir.join(format!("v{}.dsc", version))
+    fn descriptor_path(&self, version: u32, index: u16) -> PathBuf {
+        match index {
+            0 => self.dir.join(format!("v{}.dsc", version)),
+            _ => self.dir.join(format!("v{}.{}.dsc", version, index)),
+        }
+    }
+}
+
+fn sum_path(path: PathBuf) -> PathBuf {
+    let mut path = path.into_os_string();
+    path.push(".sum");
+    path.into()
+}
+
+fn read_if_exists(path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn remove_if_exists(path: &Path) -> anyhow::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
     }
 }
 
+/// Writes `data` to `path` atomically and durably: the file is first written and synced under
+/// a unique temporary name, then renamed, and the directory entry is synced as well.
 fn store(path: &Path, data: &[u8]) -> anyhow::Result<()> {
-    let mut out = std::fs::File::create(path)?;
-    out.write_all(data)?;
+    let dir = path.parent().context("No parent")?;
+    let mut tmp = tempfile::Builder::new()
+        .prefix(".")
+        .suffix(TMP_SUFFIX)
+        .tempfile_in(dir)?;
+    tmp.write_all(data)?;
+    tmp.as_file().sync_all()?;
+    tmp.persist(path)?;
+    sync_dir(dir)
+}
+
+/// Makes the entries of `dir` durable, once files were created, renamed or removed in it.
+#[cfg(unix)]
+pub fn sync_dir(dir: &Path) -> anyhow::Result<()> {
+    std::fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn sync_dir(_dir: &Path) -> anyhow::Result<()> {
+    // Directories can't be opened as files on Windows, and NTFS journals
+    // the metadata updates anyway.
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_files_and_versions() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let root = Root::new(tmpdir.path().to_owned());
+        let file_id = model::FileId::try_from([1u8; model::FILE_ID_LEN].as_slice())?;
+        let descriptor = model::Descriptor {
+            verified: vec![1],
+            protected: vec![2],
+        };
+
+        let file = root.file(&file_id)?;
+        file.write_descriptor(&descriptor, 10, 0)?;
+        file.write_descriptor(&descriptor, 10, 1)?;
+        file.write_descriptor(&descriptor, 2, 0)?;
+        // Unrelated entries are ignored.
+        std::fs::create_dir(tmpdir.path().join("other"))?;
+        std::fs::write(tmpdir.path().join("020202020202"), "")?;
+
+        assert_eq!(root.files()?, vec![file_id.clone()]);
+        let file = root.existing_file(&file_id)?;
+        assert_eq!(file.versions()?, vec![2, 10]);
+        assert_eq!(file.read_descriptor(10, 0)?, descriptor);
+        assert_eq!(file.read_descriptor(10, 1)?, descriptor);
+        assert!(file.read_descriptor(10, 2).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn remove_stale_entries() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let root = Root::new(tmpdir.path().to_owned());
+        let file_id = model::FileId::try_from([1u8; model::FILE_ID_LEN].as_slice())?;
+        let descriptor = model::Descriptor {
+            verified: vec![1],
+            protected: vec![2],
+        };
+
+        let file = root.file(&file_id)?;
+        file.write_descriptor(&descriptor, 0, 0)?;
+        let dir = tmpdir.path().join(hex::encode(file_id.as_bytes()));
+        std::fs::write(dir.join(".v1.dsc.tmp"), b"")?;
+        // Recent ones may still be written.
+        assert_eq!(file.remove_temporaries(Duration::from_secs(3600))?, 0);
+        assert_eq!(file.remove_temporaries(Duration::ZERO)?, 1);
+        // Checksums of entries whose write or removal was interrupted.
+        file.write_descriptor_checksum(0, 0, &[1])?;
+        file.write_descriptor_checksum(1, 0, &[1])?;
+        assert_eq!(
+            file.remove_orphaned_checksums(Duration::from_secs(3600))?,
+            0
+        );
+        assert_eq!(file.remove_orphaned_checksums(Duration::ZERO)?, 1);
+        let mut names: Vec<_> = std::fs::read_dir(&dir)?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<anyhow::Result<_>>()?;
+        names.sort();
+        assert_eq!(names, vec!["v0.dsc", "v0.dsc.sum"]);
+        Ok(())
+    }
+
+    #[test]
+    fn remove_versions() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let root = Root::new(tmpdir.path().to_owned());
+        let file_id = model::FileId::try_from([1u8; model::FILE_ID_LEN].as_slice())?;
+        let descriptor = model::Descriptor {
+            verified: vec![1],
+            protected: vec![2],
+        };
+
+        let file = root.file(&file_id)?;
+        for index in 0..3 {
+            file.write_descriptor(&descriptor, 1, index)?;
+        }
+        file.write_descriptor(&descriptor, 2, 0)?;
+        assert_eq!(file.remove_version(1)?, 3);
+        assert_eq!(file.versions()?, vec![2]);
+        assert_eq!(file.remove_version(1)?, 0);
+        assert_eq!(file.read_descriptor(2, 0)?, descriptor);
+        Ok(())
+    }
+
+    #[test]
+    fn list_and_remove_blocks() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let root = Root::new(tmpdir.path().to_owned());
+        let file_id = model::FileId::try_from([1u8; model::FILE_ID_LEN].as_slice())?;
+        let block_id = model::BlockId::try_from([2u8; model::BLOCK_ID_LEN].as_slice())?;
+        let block = model::Block {
+            verified: vec![1],
+            protected: vec![2, 3].into(),
+        };
+
+        let file = root.file(&file_id)?;
+        file.write_block(&block, &block_id)?;
+        let descriptor = model::Descriptor {
+            verified: vec![1],
+            protected: vec![2],
+        };
+        file.write_descriptor(&descriptor, 0, 0)?;
+        assert_eq!(file.blocks()?, vec![block_id.clone()]);
+        assert_eq!(
+            file.block_metadata(&block_id)?.len(),
+            encode_block(&block).len() as u64
+        );
+
+        assert_eq!(file.read_block_checksum(&block_id)?, None);
+        let checksum = checksum(&block.verified, &block.protected);
+        file.write_block_checksum(&block_id, &checksum)?;
+        file.write_descriptor_checksum(0, 0, &checksum)?;
+        assert_eq!(file.read_block_checksum(&block_id)?, Some(checksum.clone()));
+        assert_eq!(file.read_descriptor_checksum(0, 0)?, Some(checksum));
+        // Checksums are not listed as blocks or versions.
+        assert_eq!(file.blocks()?.len(), 1);
+        assert_eq!(file.versions()?, vec![0]);
+
+        // They are removed along with what they check.
+        file.remove_block(&block_id)?;
+        assert!(file.blocks()?.is_empty());
+        assert_eq!(file.read_block_checksum(&block_id)?, None);
+        assert!(!root.existing_file(&file_id)?.remove_if_empty()?);
+        file.remove_version(0)?;
+        assert!(root.existing_file(&file_id)?.remove_if_empty()?);
+        assert!(root.files()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn missing_file() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let root = Root::new(tmpdir.path().to_owned());
+        let file_id = model::FileId::try_from([1u8; model::FILE_ID_LEN].as_slice())?;
+
+        assert!(root.existing_file(&file_id).is_err());
+        Ok(())
+    }
+}
@@ -1,3 +1,9 @@
 pub mod filesystem;
+pub mod filter;
 pub mod fingerprint;
 pub mod layout;
+pub mod metadata;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(target_os = "linux")]
+pub mod watch;
@@ -0,0 +1,163 @@
+//! POSIX metadata of files, kept with their content so that restored trees match the originals.
+
+use crypto::model::Metadata;
+use filetime::FileTime;
+use std::{fs, io, path::Path};
+
+/// Reads the metadata of `path`, following it only if `follow` when it is a symlink.
+#[cfg(unix)]
+pub fn read(path: &Path, follow: bool) -> io::Result<Metadata> {
+    use std::os::unix::{ffi::OsStrExt, fs::MetadataExt};
+    let md = match follow {
+        true => fs::metadata(path)?,
+        false => fs::symlink_metadata(path)?,
+    };
+    let names = match follow {
+        true => xattr::list_deref(path),
+        false => xattr::list(path),
+    };
+    let mut xattrs = vec![];
+    match names {
+        Ok(names) => {
+            for name in names {
+                let value = match follow {
+                    true => xattr::get_deref(path, &name)?,
+                    false => xattr::get(path, &name)?,
+                };
+                // Attributes may be removed while they are listed.
+                if let Some(value) = value {
+                    xattrs.push((name.as_bytes().to_vec(), value));
+                }
+            }
+        }
+        Err(err) if err.raw_os_error() == Some(libc::ENOTSUP) => {}
+        Err(err) => return Err(err),
+    }
+    xattrs.sort();
+    Ok(Metadata {
+        mode: md.mode() & 0o7777,
+        uid: md.uid(),
+        gid: md.gid(),
+        mtime: md.mtime() * 1_000_000_000 + md.mtime_nsec(),
+        atime: md.atime() * 1_000_000_000 + md.atime_nsec(),
+        xattrs,
+    })
+}
+
+/// Only the times are available.
+#[cfg(windows)]
+pub fn read(path: &Path, follow: bool) -> io::Result<Metadata> {
+    let md = match follow {
+        true => fs::metadata(path)?,
+        false => fs::symlink_metadata(path)?,
+    };
+    let nanos = |time: FileTime| time.unix_seconds() * 1_000_000_000 + time.nanoseconds() as i64;
+    Ok(Metadata {
+        mtime: nanos(FileTime::from_last_modification_time(&md)),
+        atime: nanos(FileTime::from_last_access_time(&md)),
+        ..Default::default()
+    })
+}
+
+/// Applies `metadata` to `path`, which is never followed when it is a symlink. Ownership is only
+/// restored if `ownership`, as it usually requires running as root.
+///
+/// Extended attributes which can't be set, because of the filesystem or of missing privileges,
+/// are skipped with a warning.
+#[cfg(unix)]
+pub fn apply(metadata: &Metadata, path: &Path, ownership: bool) -> io::Result<()> {
+    use std::os::unix::{ffi::OsStrExt, fs::PermissionsExt};
+    let symlink = fs::symlink_metadata(path)?.is_symlink();
+    for (name, value) in &metadata.xattrs {
+        let name = std::ffi::OsStr::from_bytes(name);
+        if let Err(err) = xattr::set(path, name, value) {
+            tracing::warn!("failed to set {:?} on {:?}: {}", name, path, err);
+        }
+    }
+    // Changing the owner clears the setuid and setgid bits, so it comes first.
+    if ownership {
+        std::os::unix::fs::lchown(path, Some(metadata.uid), Some(metadata.gid))?;
+    }
+    // Symlinks have no permissions of their own.
+    if !symlink {
+        fs::set_permissions(path, fs::Permissions::from_mode(metadata.mode))?;
+    }
+    set_times(metadata, path)
+}
+
+/// Only the times are applied.
+#[cfg(windows)]
+pub fn apply(metadata: &Metadata, path: &Path, _ownership: bool) -> io::Result<()> {
+    set_times(metadata, path)
+}
+
+fn set_times(metadata: &Metadata, path: &Path) -> io::Result<()> {
+    let time = |nanos: i64| {
+        FileTime::from_unix_time(
+            nanos.div_euclid(1_000_000_000),
+            nanos.rem_euclid(1_000_000_000) as u32,
+        )
+    };
+    filetime::set_symlink_file_times(path, time(metadata.atime), time(metadata.mtime))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let original = tmpdir.path().join("original");
+        fs::write(&original, "content")?;
+        fs::set_permissions(
+            &original,
+            std::os::unix::fs::PermissionsExt::from_mode(0o4751),
+        )?;
+        filetime::set_file_times(
+            &original,
+            FileTime::from_unix_time(1000, 5),
+            FileTime::from_unix_time(-1000, 7),
+        )?;
+        // Not every filesystem supports user attributes.
+        let xattrs = xattr::set(&original, "user.piston", b"value").is_ok();
+
+        let metadata = read(&original, false)?;
+        assert_eq!(metadata.mode, 0o4751);
+        assert_eq!(metadata.atime, 1000 * 1_000_000_000 + 5);
+        assert_eq!(metadata.mtime, -1000 * 1_000_000_000 + 7);
+        if xattrs {
+            assert_eq!(
+                metadata.xattrs,
+                vec![(b"user.piston".to_vec(), b"value".to_vec())]
+            );
+        }
+
+        let restored = tmpdir.path().join("restored");
+        fs::write(&restored, "content")?;
+        apply(&metadata, &restored, false)?;
+        assert_eq!(read(&restored, false)?, metadata);
+        Ok(())
+    }
+
+    #[test]
+    fn symlinks_are_not_followed() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let target = tmpdir.path().join("target");
+        let link = tmpdir.path().join("link");
+        fs::write(&target, "content")?;
+        fs::set_permissions(&target, std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        std::os::unix::fs::symlink("target", &link)?;
+
+        let mut metadata = read(&link, false)?;
+        assert_eq!(metadata.mode, 0o777);
+        assert_eq!(read(&link, true)?.mode, 0o600);
+
+        metadata.mtime = 0;
+        apply(&metadata, &link, false)?;
+        assert_eq!(read(&link, false)?.mtime, 0);
+        assert_eq!(read(&target, false)?.mode, 0o600);
+        assert_ne!(read(&target, false)?.mtime, 0);
+        Ok(())
+    }
+}
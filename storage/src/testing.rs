@@ -0,0 +1,172 @@
+//! Helpers for tests that need encrypted files stored in a layout.
+
+use crate::layout;
+use bytes::Bytes;
+use crypto::{model, RandomApi};
+use std::path::Path;
+
+/// Encrypts blocks and descriptors, and stores them with their checksums as the Sink would.
+pub struct Fixture {
+    pub root: layout::Root,
+    pub keys: crypto::Keys,
+    pub rnd: crypto::Random,
+    /// Most blocks referenced by each descriptor described.
+    pub max_refs: usize,
+}
+
+impl Fixture {
+    /// Returns a fixture storing into `dir`, with a new root key.
+    pub fn new(dir: &Path) -> anyhow::Result<Self> {
+        let rnd = crypto::Random::new();
+        Ok(Fixture {
+            root: layout::Root::new(dir.to_owned()),
+            keys: crypto::Keys::new(rnd.generate_root_key()?),
+            rnd,
+            max_refs: 10,
+        })
+    }
+
+    /// Stores a new file made of `chunks` under `filename`, as its version `version`.
+    pub fn add(
+        &self,
+        filename: &str,
+        version: model::Version,
+        chunks: &[&[u8]],
+    ) -> anyhow::Result<model::FileId> {
+        let file_id = self.rnd.generate_file_id()?;
+        self.add_version(&file_id, filename, version, chunks)?;
+        Ok(file_id)
+    }
+
+    /// Stores a version of `file_id` made of `chunks` under `filename`. Returns its blocks.
+    pub fn add_version(
+        &self,
+        file_id: &model::FileId,
+        filename: &str,
+        version: model::Version,
+        chunks: &[&[u8]],
+    ) -> anyhow::Result<Vec<model::BlockRef>> {
+        let blocks = chunks
+            .iter()
+            .map(|chunk| self.block(file_id, Bytes::copy_from_slice(chunk)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let protected = model::ProtectedDescriptor {
+            filename: filename.to_string(),
+            size: chunks.iter().map(|chunk| chunk.len() as u64).sum(),
+            ..Default::default()
+        };
+        self.describe(file_id, version, &blocks, protected)?;
+        Ok(blocks)
+    }
+
+    /// Stores a new block of `file_id` holding `chunk`.
+    pub fn block(&self, file_id: &model::FileId, chunk: Bytes) -> anyhow::Result<model::BlockRef> {
+        let block_id = self.rnd.generate_block_id()?;
+        self.block_as(file_id, &block_id, &block_id, chunk)?;
+        Ok(model::BlockRef {
+            file_id: file_id.clone(),
+            block_id,
+        })
+    }
+
+    /// Stores under `stored_as` a block of `file_id` holding `chunk`, named after `block_id` in
+    /// its verified part.
+    pub fn block_as(
+        &self,
+        file_id: &model::FileId,
+        block_id: &model::BlockId,
+        stored_as: &model::BlockId,
+        chunk: Bytes,
+    ) -> anyhow::Result<()> {
+        let block = self.keys.encrypt_block(
+            model::VerifiedBlock {
+                file_id: file_id.clone(),
+                block_id: block_id.clone(),
+            },
+            model::ProtectedBlock {
+                chunk,
+                padding: vec![],
+            },
+        )?;
+        self.store_block(file_id, stored_as, &block)
+    }
+
+    /// Stores the descriptors of a version of `file_id` made of existing blocks.
+    pub fn describe(
+        &self,
+        file_id: &model::FileId,
+        version: model::Version,
+        blocks: &[model::BlockRef],
+        protected: model::ProtectedDescriptor,
+    ) -> anyhow::Result<()> {
+        let descriptors =
+            self.keys
+                .encrypt_descriptors(file_id, version, blocks, protected, self.max_refs)?;
+        self.store_descriptors(file_id, version, &descriptors)
+    }
+
+    /// Stores snapshot `version`, made of the given file versions.
+    pub fn snapshot(
+        &self,
+        version: model::Version,
+        files: Vec<(model::FileId, model::Version)>,
+    ) -> anyhow::Result<()> {
+        let manifest = model::Manifest {
+            files,
+            ..Default::default()
+        };
+        self.manifest(version, &manifest)
+    }
+
+    /// Stores snapshot `version` with its whole manifest.
+    pub fn manifest(
+        &self,
+        version: model::Version,
+        manifest: &model::Manifest,
+    ) -> anyhow::Result<()> {
+        let (blocks, descriptors) =
+            self.keys
+                .encrypt_manifest(&self.rnd, version, manifest, self.max_refs)?;
+        for block in &blocks {
+            let block_id = crypto::peek_block(block)?.block_id;
+            self.store_block(&model::SNAPSHOTS, &block_id, block)?;
+        }
+        self.store_descriptors(&model::SNAPSHOTS, version, &descriptors)
+    }
+
+    /// Stores an encrypted block of `file_id` under `block_id`.
+    pub fn store_block(
+        &self,
+        file_id: &model::FileId,
+        block_id: &model::BlockId,
+        block: &model::Block,
+    ) -> anyhow::Result<()> {
+        let file = self.root.file(file_id)?;
+        file.write_block(block, block_id)?;
+        file.write_block_checksum(
+            block_id,
+            &layout::checksum(&block.verified, &block.protected),
+        )
+    }
+
+    /// Stores the encrypted descriptors of a version of `file_id`, at the index given by their
+    /// verified part.
+    pub fn store_descriptors(
+        &self,
+        file_id: &model::FileId,
+        version: model::Version,
+        descriptors: &[model::Descriptor],
+    ) -> anyhow::Result<()> {
+        let file = self.root.file(file_id)?;
+        for descriptor in descriptors {
+            let index = crypto::peek_descriptor(descriptor)?.index;
+            file.write_descriptor(descriptor, version, index)?;
+            file.write_descriptor_checksum(
+                version,
+                index,
+                &layout::checksum(&descriptor.verified, &descriptor.protected),
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,365 @@
+//! Change detection based on filesystem notifications, to avoid walking whole trees to find the
+//! few files which changed.
+
+use crate::{
+    filesystem::{list_dir, walk_with, ShallowInfo, WalkEvent},
+    filter::{Filter, Scope},
+};
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use inotify::{EventMask, Inotify, WatchMask, Watches};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::mpsc::Sender, time::Instant};
+
+/// Events which may indicate a change in the content or metadata of a file, or in the entries of
+/// a directory.
+const MASK: WatchMask = WatchMask::CLOSE_WRITE
+    .union(WatchMask::MOVED_TO)
+    .union(WatchMask::CREATE)
+    .union(WatchMask::ATTRIB)
+    .union(WatchMask::MOVED_FROM)
+    .union(WatchMask::DELETE)
+    .union(WatchMask::DONT_FOLLOW);
+
+/// Events changing the entries of a directory.
+const ENTRIES: EventMask = EventMask::CREATE
+    .union(EventMask::MOVED_TO)
+    .union(EventMask::MOVED_FROM)
+    .union(EventMask::DELETE);
+
+/// Watches roots for changes, and reports the files which may have changed.
+#[derive(Debug)]
+pub struct Watcher {
+    roots: Vec<PathBuf>,
+    filter: Filter,
+    reconcile: Duration,
+}
+
+/// Watched directories, with the scope of their content.
+type Dirs = HashMap<i32, (PathBuf, Arc<Scope>)>;
+
+/// Directories which could not be watched, with why.
+type Failures = Vec<(PathBuf, anyhow::Error)>;
+
+impl Watcher {
+    /// Creates a watcher on the files in `roots` accepted by `filter`. The roots are fully walked
+    /// again every `reconcile` to catch any change the notifications missed.
+    pub fn new(roots: Vec<PathBuf>, filter: Filter, reconcile: Duration) -> Self {
+        Watcher {
+            roots,
+            filter,
+            reconcile,
+        }
+    }
+
+    /// Reports every file under the roots to `updates`, then only the files which may have
+    /// changed, until `updates` is closed. Falls back to a full walk when notifications were
+    /// lost.
+    pub async fn run(&self, updates: Sender<WalkEvent>) -> Result<()> {
+        match self.follow(&updates).await {
+            // Files being reported when `updates` is closed are not an error.
+            Err(_) if updates.is_closed() => Ok(()),
+            result => result,
+        }
+    }
+
+    async fn follow(&self, updates: &Sender<WalkEvent>) -> Result<()> {
+        loop {
+            // Watches are set up before walking, so that no change is missed in between.
+            let inotify = Inotify::init().context("failed to initialize inotify")?;
+            let roots = self.roots.iter().map(|root| (root.clone(), None)).collect();
+            updates.send(WalkEvent::Walking).await?;
+            let (mut dirs, failures) = self.walk(inotify.watches(), roots, updates).await?;
+            updates.send(WalkEvent::Walked).await?;
+            report_failures(failures, updates).await?;
+            let mut events = inotify.into_event_stream([0; 4096])?;
+            let deadline = Instant::now() + self.reconcile;
+
+            loop {
+                let event = tokio::select! {
+                    event = events.next() => event.context("inotify stopped")??,
+                    _ = tokio::time::sleep_until(deadline) => {
+                        tracing::info!("reconciling watched roots");
+                        break;
+                    },
+                    _ = updates.closed() => return Ok(()),
+                };
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    tracing::warn!("lost filesystem notifications, walking the roots again");
+                    break;
+                }
+                let wd = event.wd.get_watch_descriptor_id();
+                let Some((watched, scope)) = dirs.get(&wd).cloned() else {
+                    continue;
+                };
+                if event.mask.contains(EventMask::IGNORED) {
+                    dirs.remove(&wd);
+                    continue;
+                }
+                let Some(name) = event.name else {
+                    // The watched directory itself changed, or a file given as root.
+                    self.report(watched, &scope, true, updates).await?;
+                    continue;
+                };
+                let path = watched.join(name);
+                if event.mask.contains(EventMask::ISDIR) {
+                    // Directories created or moved in were not walked yet.
+                    if event
+                        .mask
+                        .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+                    {
+                        let (added, failures) = self
+                            .walk(events.watches(), vec![(path, Some(scope.clone()))], updates)
+                            .await?;
+                        dirs.extend(added);
+                        report_failures(failures, updates).await?;
+                    }
+                } else if !event
+                    .mask
+                    .intersects(EventMask::MOVED_FROM | EventMask::DELETE)
+                {
+                    self.report(path, &scope, false, updates).await?;
+                }
+                if event.mask.intersects(ENTRIES) {
+                    self.report(watched, &scope, true, updates).await?;
+                }
+            }
+        }
+    }
+
+    /// Walks `roots`, adding a watch on every directory found. Returns the watched directories,
+    /// and the ones which could not be watched.
+    async fn walk(
+        &self,
+        mut watches: Watches,
+        roots: Vec<(PathBuf, Option<Arc<Scope>>)>,
+        updates: &Sender<WalkEvent>,
+    ) -> Result<(Dirs, Failures)> {
+        let updates = updates.clone();
+        let filter = self.filter.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut dirs = HashMap::new();
+            let mut failures = vec![];
+            // Files given as roots are watched directly.
+            for (root, _) in &roots {
+                if let Ok(md) = fs::metadata(root) {
+                    if md.is_file() {
+                        let scope = filter.root(&md);
+                        watch(&mut watches, root, &scope, &mut dirs, &mut failures);
+                    }
+                }
+            }
+            walk_with(roots, &filter, &updates, &mut |dir, scope| {
+                watch(&mut watches, dir, scope, &mut dirs, &mut failures)
+            })?;
+            Ok((dirs, failures))
+        })
+        .await?
+    }
+
+    /// Reports `path` if it still exists and is accepted by the filter. Watched directories are
+    /// given with the scope of their content, and only followed ones can be symlinks: the roots.
+    async fn report(
+        &self,
+        path: PathBuf,
+        scope: &Scope,
+        follow: bool,
+        updates: &Sender<WalkEvent>,
+    ) -> Result<()> {
+        let md = match follow {
+            true => fs::metadata(&path),
+            false => fs::symlink_metadata(&path),
+        };
+        let info = md.and_then(|md| {
+            if md.is_dir() {
+                // Entries which can't be read are not listed, and were reported when walking.
+                let listing = list_dir(&self.filter, scope, &path, &md, &mut vec![])?;
+                return Ok(Some(listing.dir));
+            }
+            match self.filter.accepts(scope, &path, &md) {
+                true => ShallowInfo::from_entry(path.clone(), &md),
+                false => Ok(None),
+            }
+        });
+        match info {
+            Ok(Some(info)) => updates.send(WalkEvent::File(info)).await?,
+            Ok(None) => {}
+            // The file may be gone already.
+            Err(err) => tracing::debug!("ignoring {:?}: {:?}", path, err),
+        }
+        Ok(())
+    }
+}
+
+/// Reports the directories which could not be watched. They were still walked, so they are not
+/// part of a walk.
+async fn report_failures(failures: Failures, updates: &Sender<WalkEvent>) -> Result<()> {
+    for (path, err) in failures {
+        updates.send(WalkEvent::Error(path, err)).await?;
+    }
+    Ok(())
+}
+
+/// Adds a watch on `path`. Failures, like reaching the limit of watches, are collected: the
+/// content is still checked when reconciling.
+fn watch(
+    watches: &mut Watches,
+    path: &Path,
+    scope: &Arc<Scope>,
+    dirs: &mut Dirs,
+    failures: &mut Failures,
+) {
+    match watches.add(path, MASK) {
+        Ok(wd) => {
+            dirs.insert(
+                wd.get_watch_descriptor_id(),
+                (path.to_owned(), scope.clone()),
+            );
+        }
+        Err(err) => failures.push((
+            path.to_owned(),
+            anyhow::Error::new(err).context("failed to watch"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::FileType;
+    use tokio::sync::mpsc::{channel, Receiver};
+
+    /// Returns the next event reported.
+    async fn next_event(rx: &mut Receiver<WalkEvent>) -> WalkEvent {
+        tokio::time::timeout(Duration::from_secs(10), rx.recv())
+            .await
+            .expect("no event")
+            .expect("watcher stopped")
+    }
+
+    /// Returns the next file reported, skipping other events.
+    async fn next(rx: &mut Receiver<WalkEvent>) -> ShallowInfo {
+        loop {
+            if let WalkEvent::File(info) = next_event(rx).await {
+                return info;
+            }
+        }
+    }
+
+    /// Returns the next file reported, skipping errors and directories.
+    async fn next_file(rx: &mut Receiver<WalkEvent>) -> PathBuf {
+        loop {
+            let info = next(rx).await;
+            if !matches!(info.kind(), FileType::Directory(_)) {
+                return info.file().to_owned();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn report_changed_files() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let root = tmpdir.path().to_owned();
+        fs::write(root.join("existing"), "a")?;
+
+        let (tx, mut rx) = channel(1);
+        let watcher = Watcher::new(
+            vec![root.clone()],
+            Filter::new(&["*.tmp".to_string()], &[])?,
+            Duration::from_secs(3600),
+        );
+        let handle = tokio::spawn(async move { watcher.run(tx).await });
+
+        // Existing files are reported first.
+        assert_eq!(next_file(&mut rx).await, root.join("existing"));
+
+        // Excluded files are not reported.
+        fs::write(root.join("excluded.tmp"), "b")?;
+        fs::write(root.join("existing"), "b")?;
+        assert_eq!(next_file(&mut rx).await, root.join("existing"));
+
+        // New directories are walked and watched too.
+        fs::create_dir(root.join("dir"))?;
+        fs::write(root.join("dir").join("new"), "c")?;
+        assert_eq!(next_file(&mut rx).await, root.join("dir").join("new"));
+
+        drop(rx);
+        handle.await?
+    }
+
+    #[tokio::test]
+    async fn report_changed_directories() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let root = tmpdir.path().to_owned();
+        fs::create_dir(root.join("dir"))?;
+        fs::write(root.join("dir").join("a"), "a")?;
+
+        let (tx, mut rx) = channel(1);
+        let watcher = Watcher::new(
+            vec![root.clone()],
+            Filter::default(),
+            Duration::from_secs(3600),
+        );
+        let handle = tokio::spawn(async move { watcher.run(tx).await });
+        let mut next_dir = async || loop {
+            let info = next(&mut rx).await;
+            if let FileType::Directory(entries) = info.kind() {
+                break (info.file().to_owned(), entries.clone());
+            }
+        };
+
+        // Directories are reported with the names of their entries.
+        assert_eq!(next_dir().await, (root.clone(), vec!["dir".into()]));
+        assert_eq!(next_dir().await, (root.join("dir"), vec!["a".into()]));
+
+        // Adding or removing an entry changes the listing.
+        fs::write(root.join("dir").join("b"), "b")?;
+        assert_eq!(
+            next_dir().await,
+            (root.join("dir"), vec!["a".into(), "b".into()])
+        );
+        fs::remove_file(root.join("dir").join("a"))?;
+        assert_eq!(next_dir().await, (root.join("dir"), vec!["b".into()]));
+
+        drop(rx);
+        handle.await?
+    }
+
+    #[tokio::test]
+    async fn reconcile_periodically() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let root = tmpdir.path().to_owned();
+        fs::write(root.join("file"), "a")?;
+
+        let (tx, mut rx) = channel(1);
+        let watcher = Watcher::new(
+            vec![root.clone()],
+            Filter::default(),
+            Duration::from_millis(50),
+        );
+        let handle = tokio::spawn(async move { watcher.run(tx).await });
+
+        // Every full walk reports the file again, without any change.
+        for _ in 0..3 {
+            assert!(matches!(next_event(&mut rx).await, WalkEvent::Walking));
+            let mut files = vec![];
+            loop {
+                match next_event(&mut rx).await {
+                    WalkEvent::File(info) => files.push(info.file().to_owned()),
+                    WalkEvent::Walked => break,
+                    event => panic!("unexpected {:?}", event),
+                }
+            }
+            assert_eq!(files, vec![root.join("file"), root.clone()]);
+        }
+
+        drop(rx);
+        handle.await?
+    }
+}